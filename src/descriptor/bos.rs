@@ -0,0 +1,497 @@
+use alloc::vec::Vec;
+
+use super::*;
+use crate::Speed;
+
+pub const CAP_USB2_EXTENSION: u8 = 0x02;
+pub const CAP_SUPERSPEED: u8 = 0x03;
+pub const CAP_CONTAINER_ID: u8 = 0x04;
+pub const CAP_PLATFORM: u8 = 0x05;
+pub const CAP_SUPERSPEED_PLUS: u8 = 0x0A;
+
+/// BESL 编码对应的恢复时间（us），见 USB 2.0 LPM ECN
+const BESL_US: [u16; 16] = [
+    125, 150, 200, 300, 400, 500, 1000, 2000, 3000, 4000, 5000, 6000, 7000, 8000, 9000, 10000,
+];
+
+pub fn besl_to_us(besl: u8) -> u16 {
+    BESL_US[(besl & 0xF) as usize]
+}
+
+descriptor!(
+    /// BOS 描述符头部，不含其后的设备能力描述符
+    BosDescriptor,
+    TYPE_BOS,
+    5
+);
+
+impl BosDescriptor<'_> {
+    /// wTotalLength，包含其后所有设备能力描述符
+    pub fn total_length(&self) -> u16 {
+        u16_at(self.0, 2)
+    }
+
+    pub fn num_device_caps(&self) -> u8 {
+        self.0[4]
+    }
+}
+
+/// 设备能力描述符，除类型与最小长度外还检查 bDevCapabilityType
+macro_rules! capability {
+    ($(#[$meta:meta])* $name:ident, $cap:expr, $len:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name<'a>(&'a [u8]);
+
+        impl<'a> $name<'a> {
+            pub const LENGTH: usize = $len;
+
+            pub fn parse(raw: &'a [u8]) -> Result<Self> {
+                let raw = check(raw, TYPE_DEVICE_CAPABILITY, $len)?;
+                if raw[2] != $cap {
+                    return Err(USBError::InvalidDescriptor);
+                }
+                Ok(Self(raw))
+            }
+
+            pub fn bytes(&self) -> &'a [u8] {
+                self.0
+            }
+        }
+
+        impl<'a> TryFrom<RawDescriptor<'a>> for $name<'a> {
+            type Error = USBError;
+
+            fn try_from(value: RawDescriptor<'a>) -> Result<Self> {
+                Self::parse(value.0)
+            }
+        }
+    };
+}
+
+capability!(
+    /// USB 2.0 Extension，声明 LPM 支持及 BESL 参数
+    Usb2Extension,
+    CAP_USB2_EXTENSION,
+    7
+);
+
+impl Usb2Extension<'_> {
+    pub fn attributes(&self) -> u32 {
+        u32_at(self.0, 3)
+    }
+
+    /// 支持 L1 链路电源管理
+    pub fn lpm(&self) -> bool {
+        self.attributes() & 1 << 1 != 0
+    }
+
+    /// 以 BESL 而非 HIRD 表示恢复时间
+    pub fn besl(&self) -> bool {
+        self.attributes() & 1 << 2 != 0
+    }
+
+    /// 建议的 BESL 基准值
+    pub fn baseline_besl(&self) -> Option<u8> {
+        (self.attributes() & 1 << 3 != 0).then_some((self.attributes() >> 8 & 0xF) as u8)
+    }
+
+    /// 建议的深度睡眠 BESL 值
+    pub fn deep_besl(&self) -> Option<u8> {
+        (self.attributes() & 1 << 4 != 0).then_some((self.attributes() >> 12 & 0xF) as u8)
+    }
+}
+
+capability!(
+    /// SuperSpeed USB 设备能力
+    SuperSpeedCapability,
+    CAP_SUPERSPEED,
+    10
+);
+
+impl SuperSpeedCapability<'_> {
+    pub fn attributes(&self) -> u8 {
+        self.0[3]
+    }
+
+    /// 支持 Latency Tolerance Messages
+    pub fn ltm(&self) -> bool {
+        self.attributes() & 1 << 1 != 0
+    }
+
+    /// wSpeedsSupported：bit0 低速，bit1 全速，bit2 高速，bit3 5Gb/s
+    pub fn speeds_supported(&self) -> u16 {
+        u16_at(self.0, 4)
+    }
+
+    /// 功能完整可用的最低速度，编码同`speeds_supported`的位号
+    pub fn functionality_support(&self) -> u8 {
+        self.0[6]
+    }
+
+    /// bU1DevExitLat（us）
+    pub fn u1_exit_latency(&self) -> u8 {
+        self.0[7]
+    }
+
+    /// wU2DevExitLat（us）
+    pub fn u2_exit_latency(&self) -> u16 {
+        u16_at(self.0, 8)
+    }
+}
+
+capability!(
+    /// SuperSpeedPlus USB 设备能力，列出各子链路速度
+    SuperSpeedPlusCapability,
+    CAP_SUPERSPEED_PLUS,
+    12
+);
+
+impl SuperSpeedPlusCapability<'_> {
+    pub fn attributes(&self) -> u32 {
+        u32_at(self.0, 4)
+    }
+
+    /// 子链路速度属性的个数（SSAC + 1）
+    pub fn sublink_speed_attr_count(&self) -> usize {
+        (self.attributes() & 0x1F) as usize + 1
+    }
+
+    /// 速度 ID 的个数（SSIC + 1）
+    pub fn sublink_speed_id_count(&self) -> usize {
+        (self.attributes() >> 5 & 0xF) as usize + 1
+    }
+
+    pub fn functionality_support(&self) -> u16 {
+        u16_at(self.0, 8)
+    }
+
+    /// 功能完整可用的最低速度 ID
+    pub fn min_functional_speed_id(&self) -> u8 {
+        (self.functionality_support() & 0xF) as u8
+    }
+
+    /// 描述符长度不足时只返回实际包含的部分
+    pub fn sublink_speeds(&self) -> impl Iterator<Item = SublinkSpeed> + '_ {
+        self.0[12..]
+            .as_chunks::<4>()
+            .0
+            .iter()
+            .take(self.sublink_speed_attr_count())
+            .map(|c| SublinkSpeed(u32::from_le_bytes(*c)))
+    }
+}
+
+/// bmSublinkSpeedAttr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SublinkSpeed(pub u32);
+
+impl SublinkSpeed {
+    /// 速度 ID（SSID），对称链路的收发两个属性共用
+    pub fn id(&self) -> u8 {
+        (self.0 & 0xF) as u8
+    }
+
+    pub fn asymmetric(&self) -> bool {
+        self.0 & 1 << 6 != 0
+    }
+
+    /// 非对称链路中该属性描述发送方向
+    pub fn transmit(&self) -> bool {
+        self.0 & 1 << 7 != 0
+    }
+
+    /// 链路协议：0 为 SuperSpeed，1 为 SuperSpeedPlus
+    pub fn protocol(&self) -> u8 {
+        (self.0 >> 14 & 0x3) as u8
+    }
+
+    /// 子链路速率（b/s），由 LSE 指数与 LSM 尾数算出
+    pub fn bits_per_second(&self) -> u64 {
+        let mantissa = (self.0 >> 16) as u64;
+        mantissa * 1000u64.pow(self.0 >> 4 & 0x3)
+    }
+}
+
+capability!(
+    /// Container ID，同一物理设备的各功能共用的 UUID
+    ContainerId,
+    CAP_CONTAINER_ID,
+    20
+);
+
+impl<'a> ContainerId<'a> {
+    pub fn uuid(&self) -> &'a [u8; 16] {
+        self.0[4..20].try_into().unwrap()
+    }
+}
+
+capability!(
+    /// 平台能力，内容由 UUID 标识的平台定义（如 WebUSB、Microsoft OS 2.0）
+    PlatformCapability,
+    CAP_PLATFORM,
+    20
+);
+
+impl<'a> PlatformCapability<'a> {
+    pub fn uuid(&self) -> &'a [u8; 16] {
+        self.0[4..20].try_into().unwrap()
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        &self.0[20..]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceCapability<'a> {
+    Usb2Extension(Usb2Extension<'a>),
+    SuperSpeed(SuperSpeedCapability<'a>),
+    SuperSpeedPlus(SuperSpeedPlusCapability<'a>),
+    ContainerId(ContainerId<'a>),
+    Platform(PlatformCapability<'a>),
+    /// 未解析的其他能力
+    Other(RawDescriptor<'a>),
+}
+
+impl<'a> TryFrom<RawDescriptor<'a>> for DeviceCapability<'a> {
+    type Error = USBError;
+
+    fn try_from(desc: RawDescriptor<'a>) -> Result<Self> {
+        if desc.descriptor_type() != TYPE_DEVICE_CAPABILITY || desc.0.len() < 3 {
+            return Err(USBError::InvalidDescriptor);
+        }
+        Ok(match desc.0[2] {
+            CAP_USB2_EXTENSION => Self::Usb2Extension(desc.try_into()?),
+            CAP_SUPERSPEED => Self::SuperSpeed(desc.try_into()?),
+            CAP_SUPERSPEED_PLUS => Self::SuperSpeedPlus(desc.try_into()?),
+            CAP_CONTAINER_ID => Self::ContainerId(desc.try_into()?),
+            CAP_PLATFORM => Self::Platform(desc.try_into()?),
+            _ => Self::Other(desc),
+        })
+    }
+}
+
+/// 完整 BOS 解析结果
+#[derive(Debug, Clone)]
+pub struct Bos<'a> {
+    pub descriptor: BosDescriptor<'a>,
+    pub capabilities: Vec<DeviceCapability<'a>>,
+}
+
+impl<'a> Bos<'a> {
+    /// 解析 GET_DESCRIPTOR(BOS) 读到的完整数据，超出 wTotalLength 的部分忽略
+    pub fn parse(raw: &'a [u8]) -> Result<Self> {
+        let descriptor = BosDescriptor::parse(raw)?;
+        let total = descriptor.total_length() as usize;
+        if raw.len() < total {
+            return Err(USBError::InvalidDescriptor);
+        }
+        let mut capabilities = Vec::new();
+        for desc in Descriptors::new(&raw[..total]).skip(1) {
+            let desc = desc?;
+            // BOS 中只应有设备能力描述符，其余忽略
+            if desc.descriptor_type() == TYPE_DEVICE_CAPABILITY {
+                capabilities.push(desc.try_into()?);
+            }
+        }
+        Ok(Self {
+            descriptor,
+            capabilities,
+        })
+    }
+
+    pub fn usb2_extension(&self) -> Option<Usb2Extension<'a>> {
+        self.capabilities.iter().find_map(|c| match c {
+            DeviceCapability::Usb2Extension(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    pub fn superspeed(&self) -> Option<SuperSpeedCapability<'a>> {
+        self.capabilities.iter().find_map(|c| match c {
+            DeviceCapability::SuperSpeed(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    pub fn superspeed_plus(&self) -> Option<SuperSpeedPlusCapability<'a>> {
+        self.capabilities.iter().find_map(|c| match c {
+            DeviceCapability::SuperSpeedPlus(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    pub fn container_id(&self) -> Option<ContainerId<'a>> {
+        self.capabilities.iter().find_map(|c| match c {
+            DeviceCapability::ContainerId(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    pub fn platforms(&self) -> impl Iterator<Item = PlatformCapability<'a>> + '_ {
+        self.capabilities.iter().filter_map(|c| match c {
+            DeviceCapability::Platform(c) => Some(*c),
+            _ => None,
+        })
+    }
+
+    /// 以`speed`连接时链路电源管理的最大退出延迟（us），用作
+    /// `ContextUpdate::max_exit_latency`；设备不支持 LPM 时为`None`
+    pub fn lpm_exit_latency(&self, speed: Speed) -> Option<u16> {
+        match speed {
+            Speed::Super | Speed::SuperPlus => {
+                let ss = self.superspeed()?;
+                let mel = (ss.u1_exit_latency() as u16).max(ss.u2_exit_latency());
+                (mel != 0).then_some(mel)
+            }
+            Speed::High => {
+                let ext = self.usb2_extension().filter(|e| e.lpm() && e.besl())?;
+                let besl = ext.deep_besl().or(ext.baseline_besl()).unwrap_or(0);
+                Some(besl_to_us(besl))
+            }
+            Speed::Low | Speed::Full => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::super::tests::desc;
+    use super::*;
+
+    /// SSID 1、Gb/s、SuperSpeedPlus、10Gb/s 的对称子链路
+    const SUBLINK_10G: u32 = 0x000A_4031;
+
+    fn cap(ty: u8, body: &[u8]) -> Vec<u8> {
+        desc(TYPE_DEVICE_CAPABILITY, &[&[ty], body].concat())
+    }
+
+    fn bos_bytes(caps: &[Vec<u8>]) -> Vec<u8> {
+        let body = caps.concat();
+        let total = (BosDescriptor::LENGTH + body.len()) as u16;
+        let [lo, hi] = total.to_le_bytes();
+        [desc(TYPE_BOS, &[lo, hi, caps.len() as u8]), body].concat()
+    }
+
+    fn ssp_cap(attr_count: u32, sublinks: &[u32]) -> Vec<u8> {
+        let attrs = (attr_count - 1) | (attr_count / 2 - 1) << 5;
+        let mut body = vec![0];
+        body.extend_from_slice(&attrs.to_le_bytes());
+        body.extend_from_slice(&[0x01, 0x00, 0, 0]);
+        for s in sublinks {
+            body.extend_from_slice(&s.to_le_bytes());
+        }
+        cap(CAP_SUPERSPEED_PLUS, &body)
+    }
+
+    fn all_caps() -> Vec<Vec<u8>> {
+        let mut platform = vec![0];
+        platform.extend(0x10..0x20);
+        platform.extend_from_slice(&[1, 2, 3, 4]);
+        vec![
+            // LPM + BESL，基准 BESL 2，深度 BESL 6
+            cap(CAP_USB2_EXTENSION, &0x621Eu32.to_le_bytes()),
+            cap(CAP_SUPERSPEED, &[0, 0x0E, 0, 1, 10, 0x00, 0x02]),
+            ssp_cap(2, &[SUBLINK_10G, SUBLINK_10G | 1 << 7]),
+            cap(CAP_CONTAINER_ID, &[[0].as_slice(), &[0xAB; 16]].concat()),
+            cap(CAP_PLATFORM, &platform),
+            cap(0x0B, &[0, 0]),
+        ]
+    }
+
+    #[test]
+    fn capabilities() {
+        let raw = bos_bytes(&all_caps());
+        let bos = Bos::parse(&raw).unwrap();
+        assert_eq!(bos.descriptor.num_device_caps(), 6);
+        assert_eq!(bos.capabilities.len(), 6);
+
+        let ext = bos.usb2_extension().unwrap();
+        assert!(ext.lpm() && ext.besl());
+        assert_eq!(ext.baseline_besl(), Some(2));
+        assert_eq!(ext.deep_besl(), Some(6));
+
+        let ss = bos.superspeed().unwrap();
+        assert_eq!(ss.speeds_supported(), 0x0E);
+        assert_eq!(ss.u1_exit_latency(), 10);
+        assert_eq!(ss.u2_exit_latency(), 0x200);
+
+        let ssp = bos.superspeed_plus().unwrap();
+        assert_eq!(ssp.sublink_speed_attr_count(), 2);
+        assert_eq!(ssp.sublink_speed_id_count(), 1);
+        assert_eq!(ssp.min_functional_speed_id(), 1);
+        let speeds: Vec<_> = ssp.sublink_speeds().collect();
+        assert_eq!(speeds.len(), 2);
+        assert_eq!(speeds[0].id(), 1);
+        assert_eq!(speeds[0].protocol(), 1);
+        assert_eq!(speeds[0].bits_per_second(), 10_000_000_000);
+        assert!(!speeds[0].transmit() && speeds[1].transmit());
+
+        assert_eq!(bos.container_id().unwrap().uuid(), &[0xAB; 16]);
+        let platform = bos.platforms().next().unwrap();
+        assert_eq!(platform.uuid()[0], 0x10);
+        assert_eq!(platform.data(), &[1, 2, 3, 4]);
+        assert!(matches!(
+            bos.capabilities[5],
+            DeviceCapability::Other(d) if d.bytes()[2] == 0x0B
+        ));
+    }
+
+    #[test]
+    fn lpm_exit_latency() {
+        let raw = bos_bytes(&all_caps());
+        let bos = Bos::parse(&raw).unwrap();
+        assert_eq!(bos.lpm_exit_latency(Speed::Super), Some(0x200));
+        assert_eq!(bos.lpm_exit_latency(Speed::High), Some(1000));
+        assert_eq!(bos.lpm_exit_latency(Speed::Full), None);
+
+        // 不支持 BESL 时高速不启用 LPM
+        let raw = bos_bytes(&[cap(CAP_USB2_EXTENSION, &0x2u32.to_le_bytes())]);
+        assert_eq!(
+            Bos::parse(&raw).unwrap().lpm_exit_latency(Speed::High),
+            None
+        );
+    }
+
+    #[test]
+    fn sublink_speeds_limited_to_descriptor() {
+        // SSAC 声明 4 个属性，描述符中只有 2 个
+        let raw = bos_bytes(&[ssp_cap(4, &[SUBLINK_10G, SUBLINK_10G])]);
+        let bos = Bos::parse(&raw).unwrap();
+        assert_eq!(bos.superspeed_plus().unwrap().sublink_speeds().count(), 2);
+    }
+
+    #[test]
+    fn malformed_capabilities() {
+        // 短于该能力的最小长度
+        let raw = bos_bytes(&[cap(CAP_SUPERSPEED, &[0, 0x0E, 0])]);
+        assert_eq!(Bos::parse(&raw).unwrap_err(), USBError::InvalidDescriptor);
+
+        // 缺少 bDevCapabilityType
+        let raw = bos_bytes(&[desc(TYPE_DEVICE_CAPABILITY, &[])]);
+        assert_eq!(Bos::parse(&raw).unwrap_err(), USBError::InvalidDescriptor);
+
+        // 能力类型不符
+        let ss = cap(CAP_SUPERSPEED, &[0, 0x0E, 0, 1, 10, 0x00, 0x02]);
+        assert_eq!(
+            Usb2Extension::parse(&ss).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+
+        // wTotalLength 超出读到的数据
+        let raw = bos_bytes(&all_caps());
+        assert_eq!(
+            Bos::parse(&raw[..raw.len() - 1]).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+    }
+
+    #[test]
+    fn non_capability_descriptors_ignored() {
+        let raw = bos_bytes(&[desc(TYPE_STRING, &[0x09, 0x04])]);
+        assert!(Bos::parse(&raw).unwrap().capabilities.is_empty());
+    }
+}
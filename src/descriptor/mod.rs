@@ -0,0 +1,717 @@
+use alloc::vec::Vec;
+
+use crate::{EndpointConfig, TransferKind, err::*};
+
+pub const TYPE_DEVICE: u8 = 0x01;
+pub const TYPE_CONFIGURATION: u8 = 0x02;
+pub const TYPE_STRING: u8 = 0x03;
+pub const TYPE_INTERFACE: u8 = 0x04;
+pub const TYPE_ENDPOINT: u8 = 0x05;
+pub const TYPE_INTERFACE_ASSOCIATION: u8 = 0x0B;
+pub const TYPE_BOS: u8 = 0x0F;
+pub const TYPE_DEVICE_CAPABILITY: u8 = 0x10;
+pub const TYPE_SS_ENDPOINT_COMPANION: u8 = 0x30;
+pub const TYPE_SSP_ISOC_ENDPOINT_COMPANION: u8 = 0x31;
+
+fn u16_at(raw: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([raw[offset], raw[offset + 1]])
+}
+
+fn u32_at(raw: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        raw[offset],
+        raw[offset + 1],
+        raw[offset + 2],
+        raw[offset + 3],
+    ])
+}
+
+/// 未解析的单个描述符，包含 bLength 与 bDescriptorType
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawDescriptor<'a>(&'a [u8]);
+
+impl<'a> RawDescriptor<'a> {
+    pub fn descriptor_type(&self) -> u8 {
+        self.0[1]
+    }
+
+    /// 完整的原始字节
+    pub fn bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// 去掉 bLength 与 bDescriptorType 后的内容
+    pub fn payload(&self) -> &'a [u8] {
+        &self.0[2..]
+    }
+}
+
+/// 按 bLength 依次取出连续排列的描述符，遇到格式错误时返回`InvalidDescriptor`并结束
+pub struct Descriptors<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Descriptors<'a> {
+    pub fn new(raw: &'a [u8]) -> Self {
+        Self { rest: raw }
+    }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Result<RawDescriptor<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let len = self.rest[0] as usize;
+        if len < 2 || len > self.rest.len() {
+            self.rest = &[];
+            return Some(Err(USBError::InvalidDescriptor));
+        }
+        let (desc, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Some(Ok(RawDescriptor(desc)))
+    }
+}
+
+/// 检查类型与最小长度，超出最小长度的部分保留（新版本规范可能追加字段）
+fn check(raw: &[u8], ty: u8, min_len: usize) -> Result<&[u8]> {
+    let len = *raw.first().ok_or(USBError::InvalidDescriptor)? as usize;
+    if len < min_len || raw.len() < len || raw[1] != ty {
+        return Err(USBError::InvalidDescriptor);
+    }
+    Ok(&raw[..len])
+}
+
+macro_rules! descriptor {
+    ($(#[$meta:meta])* $name:ident, $ty:expr, $len:expr) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name<'a>(&'a [u8]);
+
+        impl<'a> $name<'a> {
+            pub const LENGTH: usize = $len;
+
+            pub fn parse(raw: &'a [u8]) -> Result<Self> {
+                check(raw, $ty, $len).map(Self)
+            }
+
+            pub fn bytes(&self) -> &'a [u8] {
+                self.0
+            }
+        }
+
+        impl<'a> TryFrom<RawDescriptor<'a>> for $name<'a> {
+            type Error = USBError;
+
+            fn try_from(value: RawDescriptor<'a>) -> Result<Self> {
+                Self::parse(value.0)
+            }
+        }
+    };
+}
+
+// 宏须先于子模块定义
+mod bos;
+
+pub use bos::*;
+
+descriptor!(
+    /// 设备描述符
+    DeviceDescriptor,
+    TYPE_DEVICE,
+    18
+);
+
+impl DeviceDescriptor<'_> {
+    /// bcdUSB
+    pub fn usb_version(&self) -> u16 {
+        u16_at(self.0, 2)
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn subclass(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.0[6]
+    }
+
+    /// bMaxPacketSize0，SuperSpeed 设备为 2 的指数
+    pub fn max_packet_size0(&self) -> u8 {
+        self.0[7]
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        u16_at(self.0, 8)
+    }
+
+    pub fn product_id(&self) -> u16 {
+        u16_at(self.0, 10)
+    }
+
+    /// bcdDevice
+    pub fn device_version(&self) -> u16 {
+        u16_at(self.0, 12)
+    }
+
+    pub fn manufacturer_index(&self) -> u8 {
+        self.0[14]
+    }
+
+    pub fn product_index(&self) -> u8 {
+        self.0[15]
+    }
+
+    pub fn serial_index(&self) -> u8 {
+        self.0[16]
+    }
+
+    pub fn num_configurations(&self) -> u8 {
+        self.0[17]
+    }
+}
+
+descriptor!(
+    /// 配置描述符头部，不含其后的接口等描述符
+    ConfigurationDescriptor,
+    TYPE_CONFIGURATION,
+    9
+);
+
+impl ConfigurationDescriptor<'_> {
+    /// wTotalLength，包含其后所有描述符
+    pub fn total_length(&self) -> u16 {
+        u16_at(self.0, 2)
+    }
+
+    pub fn num_interfaces(&self) -> u8 {
+        self.0[4]
+    }
+
+    /// SET_CONFIGURATION 使用的值
+    pub fn configuration_value(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn configuration_index(&self) -> u8 {
+        self.0[6]
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[7]
+    }
+
+    pub fn self_powered(&self) -> bool {
+        self.attributes() & 0x40 != 0
+    }
+
+    pub fn remote_wakeup(&self) -> bool {
+        self.attributes() & 0x20 != 0
+    }
+
+    /// bMaxPower，高速及以下单位为 2mA，SuperSpeed 为 8mA
+    pub fn max_power(&self) -> u8 {
+        self.0[8]
+    }
+}
+
+descriptor!(
+    /// 接口描述符，每个备用设置一个
+    InterfaceDescriptor,
+    TYPE_INTERFACE,
+    9
+);
+
+impl InterfaceDescriptor<'_> {
+    pub fn interface_number(&self) -> u8 {
+        self.0[2]
+    }
+
+    pub fn alternate_setting(&self) -> u8 {
+        self.0[3]
+    }
+
+    pub fn num_endpoints(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn subclass(&self) -> u8 {
+        self.0[6]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.0[7]
+    }
+
+    pub fn interface_index(&self) -> u8 {
+        self.0[8]
+    }
+}
+
+descriptor!(
+    /// 端点描述符，音频类的 9 字节版本同样适用
+    EndpointDescriptor,
+    TYPE_ENDPOINT,
+    7
+);
+
+impl EndpointDescriptor<'_> {
+    /// bEndpointAddress
+    pub fn address(&self) -> u8 {
+        self.0[2]
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address() & 0x80 != 0
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[3]
+    }
+
+    pub fn transfer_kind(&self) -> TransferKind {
+        match self.attributes() & 0x03 {
+            0 => TransferKind::Control,
+            1 => TransferKind::Isochronous,
+            2 => TransferKind::Bulk,
+            _ => TransferKind::Interrupt,
+        }
+    }
+
+    /// wMaxPacketSize，高速周期端点的 bit 11..=12 为每微帧额外事务数
+    pub fn max_packet_size(&self) -> u16 {
+        u16_at(self.0, 4)
+    }
+
+    pub fn interval(&self) -> u8 {
+        self.0[6]
+    }
+}
+
+descriptor!(
+    /// 接口关联描述符（IAD），把连续的几个接口归为一个功能
+    InterfaceAssociationDescriptor,
+    TYPE_INTERFACE_ASSOCIATION,
+    8
+);
+
+impl InterfaceAssociationDescriptor<'_> {
+    pub fn first_interface(&self) -> u8 {
+        self.0[2]
+    }
+
+    pub fn interface_count(&self) -> u8 {
+        self.0[3]
+    }
+
+    /// 该功能是否包含`interface`号接口
+    pub fn contains(&self, interface: u8) -> bool {
+        interface
+            .checked_sub(self.first_interface())
+            .is_some_and(|i| i < self.interface_count())
+    }
+
+    pub fn function_class(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn function_subclass(&self) -> u8 {
+        self.0[5]
+    }
+
+    pub fn function_protocol(&self) -> u8 {
+        self.0[6]
+    }
+
+    pub fn function_index(&self) -> u8 {
+        self.0[7]
+    }
+}
+
+descriptor!(
+    /// SuperSpeed 端点伴随描述符，紧跟在 SuperSpeed 设备的每个端点描述符之后
+    SsEndpointCompanion,
+    TYPE_SS_ENDPOINT_COMPANION,
+    6
+);
+
+impl SsEndpointCompanion<'_> {
+    /// bMaxBurst，每次突发的包数减一
+    pub fn max_burst(&self) -> u8 {
+        self.0[2]
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.0[3]
+    }
+
+    /// 批量端点支持的流数为 2^n，0 表示不支持流
+    pub fn max_streams(&self) -> u8 {
+        self.attributes() & 0x1F
+    }
+
+    /// 等时端点每个服务间隔的突发数减一；有 SuperSpeedPlus 等时伴随描述符时无效
+    pub fn mult(&self) -> u8 {
+        self.attributes() & 0x03
+    }
+
+    /// 其后是否跟有 SuperSpeedPlus 等时端点伴随描述符
+    pub fn ssp_isoc_companion(&self) -> bool {
+        self.attributes() & 0x80 != 0
+    }
+
+    /// wBytesPerInterval，周期端点每个服务间隔的最大字节数
+    pub fn bytes_per_interval(&self) -> u16 {
+        u16_at(self.0, 4)
+    }
+}
+
+descriptor!(
+    /// SuperSpeedPlus 等时端点伴随描述符，每个服务间隔超过 48KiB 的等时端点使用
+    SspIsocEndpointCompanion,
+    TYPE_SSP_ISOC_ENDPOINT_COMPANION,
+    8
+);
+
+impl SspIsocEndpointCompanion<'_> {
+    /// dwBytesPerInterval，取代 SuperSpeed 伴随描述符中的 wBytesPerInterval
+    pub fn bytes_per_interval(&self) -> u32 {
+        u32_at(self.0, 4)
+    }
+}
+
+descriptor!(
+    /// 字符串描述符，0 号为设备支持的语言 ID 列表，其余为 UTF-16LE 文本
+    StringDescriptor,
+    TYPE_STRING,
+    2
+);
+
+impl StringDescriptor<'_> {
+    /// UTF-16 码元，多出的奇数字节忽略
+    pub fn utf16(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0[2..]
+            .as_chunks::<2>()
+            .0
+            .iter()
+            .map(|c| u16::from_le_bytes(*c))
+    }
+
+    /// 0 号字符串描述符中的语言 ID
+    pub fn lang_ids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.utf16()
+    }
+
+    /// 解码为字符，无效的代理对替换为 U+FFFD
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.utf16()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+/// 完整配置描述符解析出的树
+#[derive(Debug, Clone)]
+pub struct Configuration<'a> {
+    pub descriptor: ConfigurationDescriptor<'a>,
+    /// 按接口号首次出现的顺序排列
+    pub interfaces: Vec<Interface<'a>>,
+    pub associations: Vec<InterfaceAssociationDescriptor<'a>>,
+    /// 配置描述符之后、第一个接口之前的其他描述符
+    pub extra: Vec<RawDescriptor<'a>>,
+}
+
+/// 同一接口号的所有备用设置
+#[derive(Debug, Clone)]
+pub struct Interface<'a> {
+    pub number: u8,
+    pub alt_settings: Vec<AltSetting<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AltSetting<'a> {
+    pub descriptor: InterfaceDescriptor<'a>,
+    pub endpoints: Vec<Endpoint<'a>>,
+    /// 接口描述符之后、第一个端点之前的类特定描述符（如 HID、CDC 功能描述符）
+    pub extra: Vec<RawDescriptor<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Endpoint<'a> {
+    pub descriptor: EndpointDescriptor<'a>,
+    /// 紧随端点描述符的其他描述符（如 SuperSpeed 伴随描述符、类特定端点描述符）
+    pub extra: Vec<RawDescriptor<'a>>,
+}
+
+impl<'a> Configuration<'a> {
+    /// 解析 GET_DESCRIPTOR(CONFIGURATION) 读到的完整数据，超出 wTotalLength 的部分忽略
+    pub fn parse(raw: &'a [u8]) -> Result<Self> {
+        let descriptor = ConfigurationDescriptor::parse(raw)?;
+        let total = descriptor.total_length() as usize;
+        if raw.len() < total {
+            return Err(USBError::InvalidDescriptor);
+        }
+
+        let mut config = Self {
+            descriptor,
+            interfaces: Vec::new(),
+            associations: Vec::new(),
+            extra: Vec::new(),
+        };
+        // 当前描述符所属的 (接口下标, 备用设置下标)
+        let mut current: Option<(usize, usize)> = None;
+
+        for desc in Descriptors::new(&raw[..total]).skip(1) {
+            let desc = desc?;
+            match desc.descriptor_type() {
+                TYPE_INTERFACE => {
+                    let descriptor = InterfaceDescriptor::try_from(desc)?;
+                    let alt = AltSetting {
+                        descriptor,
+                        endpoints: Vec::new(),
+                        extra: Vec::new(),
+                    };
+                    let number = descriptor.interface_number();
+                    let i = match config.interfaces.iter().position(|i| i.number == number) {
+                        Some(i) => i,
+                        None => {
+                            config.interfaces.push(Interface {
+                                number,
+                                alt_settings: Vec::new(),
+                            });
+                            config.interfaces.len() - 1
+                        }
+                    };
+                    config.interfaces[i].alt_settings.push(alt);
+                    current = Some((i, config.interfaces[i].alt_settings.len() - 1));
+                }
+                TYPE_ENDPOINT => {
+                    let (i, a) = current.ok_or(USBError::InvalidDescriptor)?;
+                    config.interfaces[i].alt_settings[a]
+                        .endpoints
+                        .push(Endpoint {
+                            descriptor: EndpointDescriptor::try_from(desc)?,
+                            extra: Vec::new(),
+                        });
+                }
+                TYPE_INTERFACE_ASSOCIATION => {
+                    config
+                        .associations
+                        .push(InterfaceAssociationDescriptor::try_from(desc)?);
+                }
+                _ => match current {
+                    Some((i, a)) => {
+                        let alt = &mut config.interfaces[i].alt_settings[a];
+                        match alt.endpoints.last_mut() {
+                            Some(ep) => ep.extra.push(desc),
+                            None => alt.extra.push(desc),
+                        }
+                    }
+                    None => config.extra.push(desc),
+                },
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn interface(&self, number: u8) -> Option<&Interface<'a>> {
+        self.interfaces.iter().find(|i| i.number == number)
+    }
+
+    /// 包含`interface`号接口的 IAD
+    pub fn association(&self, interface: u8) -> Option<&InterfaceAssociationDescriptor<'a>> {
+        self.associations.iter().find(|a| a.contains(interface))
+    }
+}
+
+impl<'a> Interface<'a> {
+    pub fn alt_setting(&self, setting: u8) -> Option<&AltSetting<'a>> {
+        self.alt_settings
+            .iter()
+            .find(|a| a.descriptor.alternate_setting() == setting)
+    }
+}
+
+impl<'a> Endpoint<'a> {
+    pub fn ss_companion(&self) -> Option<SsEndpointCompanion<'a>> {
+        self.extra.iter().find_map(|d| (*d).try_into().ok())
+    }
+
+    pub fn ssp_isoc_companion(&self) -> Option<SspIsocEndpointCompanion<'a>> {
+        self.extra.iter().find_map(|d| (*d).try_into().ok())
+    }
+
+    /// 由端点描述符及伴随描述符得到`configure_endpoints`所需的配置
+    pub fn config(&self) -> EndpointConfig {
+        let desc = &self.descriptor;
+        let kind = desc.transfer_kind();
+        let periodic = matches!(kind, TransferKind::Isochronous | TransferKind::Interrupt);
+        let ss = self.ss_companion();
+        let ssp = self
+            .ssp_isoc_companion()
+            .filter(|_| kind == TransferKind::Isochronous);
+
+        EndpointConfig {
+            address: desc.address(),
+            kind,
+            max_packet_size: desc.max_packet_size(),
+            interval: desc.interval(),
+            max_burst: ss.map_or(0, |c| c.max_burst()),
+            mult: match (ss, ssp) {
+                (Some(c), None) if kind == TransferKind::Isochronous => c.mult(),
+                _ => 0,
+            },
+            max_esit_payload: match (ss, ssp) {
+                (_, Some(p)) => p.bytes_per_interval(),
+                (Some(c), None) if periodic => c.bytes_per_interval() as u32,
+                _ => 0,
+            },
+        }
+    }
+}
+
+impl AltSetting<'_> {
+    /// 该备用设置下所有端点的配置
+    pub fn endpoint_configs(&self) -> Vec<EndpointConfig> {
+        self.endpoints.iter().map(Endpoint::config).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 按类型与内容拼出一个描述符，bLength 由内容长度得到
+    pub(super) fn desc(ty: u8, body: &[u8]) -> Vec<u8> {
+        [&[body.len() as u8 + 2, ty], body].concat()
+    }
+
+    /// IAD + 接口 0（两个备用设置，含 HID 描述符与中断 IN 端点）+ 接口 1（批量 OUT 端点）
+    fn config_bytes() -> Vec<u8> {
+        let mut raw = [
+            desc(TYPE_CONFIGURATION, &[0, 0, 2, 1, 0, 0xA0, 50]),
+            desc(TYPE_INTERFACE_ASSOCIATION, &[0, 2, 0x0E, 0x03, 0, 0]),
+            desc(TYPE_INTERFACE, &[0, 0, 1, 0x03, 0x01, 0x01, 0]),
+            desc(0x21, &[0x11, 0x01, 0, 1, 0x22, 0x3F, 0]),
+            desc(TYPE_ENDPOINT, &[0x81, 0x03, 8, 0, 10]),
+            desc(TYPE_SS_ENDPOINT_COMPANION, &[0, 0, 8, 0]),
+            desc(TYPE_INTERFACE, &[0, 1, 0, 0x03, 0x01, 0x01, 0]),
+            desc(TYPE_INTERFACE, &[1, 0, 1, 0x08, 0x06, 0x50, 0]),
+            desc(TYPE_ENDPOINT, &[0x02, 0x02, 0x00, 0x04, 0]),
+        ]
+        .concat();
+        let total = raw.len() as u16;
+        raw[2..4].copy_from_slice(&total.to_le_bytes());
+        raw
+    }
+
+    #[test]
+    fn configuration_tree() {
+        let raw = config_bytes();
+        let config = Configuration::parse(&raw).unwrap();
+        assert_eq!(config.descriptor.configuration_value(), 1);
+        assert!(config.descriptor.remote_wakeup());
+        assert!(config.extra.is_empty());
+
+        assert_eq!(config.interfaces.len(), 2);
+        let hid = config.interface(0).unwrap();
+        assert_eq!(hid.alt_settings.len(), 2);
+        let alt0 = hid.alt_setting(0).unwrap();
+        assert_eq!(alt0.extra.len(), 1);
+        assert_eq!(alt0.extra[0].descriptor_type(), 0x21);
+        assert_eq!(alt0.endpoints.len(), 1);
+        let ep = &alt0.endpoints[0];
+        assert!(ep.descriptor.is_in());
+        assert_eq!(ep.descriptor.transfer_kind(), TransferKind::Interrupt);
+        assert_eq!(ep.ss_companion().unwrap().bytes_per_interval(), 8);
+        assert_eq!(ep.config().max_esit_payload, 8);
+        assert!(hid.alt_setting(1).unwrap().endpoints.is_empty());
+
+        let msc = config.interface(1).unwrap();
+        let ep = &msc.alt_settings[0].endpoints[0];
+        assert_eq!(ep.descriptor.transfer_kind(), TransferKind::Bulk);
+        assert_eq!(ep.descriptor.max_packet_size(), 1024);
+        assert_eq!(ep.config().max_esit_payload, 0);
+
+        assert_eq!(config.association(1).unwrap().first_interface(), 0);
+        assert!(config.association(2).is_none());
+    }
+
+    #[test]
+    fn bytes_past_total_length_ignored() {
+        let mut raw = config_bytes();
+        raw.extend_from_slice(&[0xFF, 0xFF, 0xFF]);
+        assert_eq!(Configuration::parse(&raw).unwrap().interfaces.len(), 2);
+    }
+
+    #[test]
+    fn truncated_configuration() {
+        let raw = config_bytes();
+        assert_eq!(
+            Configuration::parse(&raw[..raw.len() - 1]).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+        assert_eq!(
+            ConfigurationDescriptor::parse(&raw[..8]).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+    }
+
+    #[test]
+    fn oversized_blength() {
+        // 端点描述符的 bLength 越过 wTotalLength
+        let mut raw = config_bytes();
+        let last = raw.len() - 7;
+        raw[last] = 8;
+        assert_eq!(
+            Configuration::parse(&raw).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+
+        let mut it = Descriptors::new(&[4, TYPE_STRING, 0x09, 0x04, 9, TYPE_ENDPOINT, 0]);
+        assert!(it.next().unwrap().is_ok());
+        assert_eq!(it.next().unwrap(), Err(USBError::InvalidDescriptor));
+        assert!(it.next().is_none());
+    }
+
+    #[test]
+    fn undersized_blength() {
+        // bLength 为 0 时不能原地循环
+        let mut it = Descriptors::new(&[0, TYPE_STRING, 4, TYPE_STRING, 0x09, 0x04]);
+        assert_eq!(it.next().unwrap(), Err(USBError::InvalidDescriptor));
+        assert!(it.next().is_none());
+
+        // 短于该类型的最小长度
+        let short = [6, TYPE_ENDPOINT, 0x81, 0x03, 8, 0];
+        assert_eq!(
+            EndpointDescriptor::parse(&short).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+        let mut raw = config_bytes();
+        let last = raw.len() - 7;
+        raw[last] = 6;
+        assert!(Configuration::parse(&raw).is_err());
+    }
+
+    #[test]
+    fn longer_descriptor_keeps_extra_fields() {
+        // 音频类 9 字节端点描述符
+        let raw = [9, TYPE_ENDPOINT, 0x01, 0x01, 0xC0, 0x00, 1, 0, 0, 0xAA];
+        let ep = EndpointDescriptor::parse(&raw).unwrap();
+        assert_eq!(ep.bytes().len(), 9);
+        assert_eq!(ep.transfer_kind(), TransferKind::Isochronous);
+        assert_eq!(ep.max_packet_size(), 192);
+        assert_eq!(
+            DeviceDescriptor::parse(&raw).unwrap_err(),
+            USBError::InvalidDescriptor
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum USBError {
     #[error("unknown usb error")]
     Unknown,
@@ -10,6 +10,30 @@ pub enum USBError {
     NoMemory,
     #[error("slot limit reached")]
     SlotLimitReached,
+    #[error("not supported")]
+    NotSupported,
+    #[error("timeout")]
+    Timeout,
+    #[error("command failed: {0:?}")]
+    CommandFailed(xhci::ring::trb::event::CompletionCode),
+    #[error("host system error")]
+    HostSystemError,
+    #[error("host controller error")]
+    HostControllerError,
+    #[error("controller not responding")]
+    ControllerNotResponding,
+    #[error("device disconnected")]
+    Disconnected,
+    #[error("device reset")]
+    DeviceReset,
+    #[error("insufficient bandwidth")]
+    Bandwidth,
+    #[error("controller out of resources")]
+    NoResources,
+    #[error("ring full")]
+    RingFull,
+    #[error("invalid descriptor")]
+    InvalidDescriptor,
     #[error("transfer event error: {0:?}")]
     TransferEventError(xhci::ring::trb::event::CompletionCode),
 }
@@ -0,0 +1,281 @@
+use core::{
+    any::Any,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use log::{info, warn};
+
+use super::{Controller, DeviceHandle, HostEvent, USBHost};
+use crate::err::*;
+
+/// 带总线号的控制器事件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusEvent {
+    pub bus: u8,
+    pub event: HostEvent,
+}
+
+/// 装箱的`USBHost<C>`，保留具体类型，可按类型取回
+trait AnyHost: Any + Send + Sync {
+    fn controller(&self) -> &dyn Controller;
+}
+
+impl<C: Controller + 'static> AnyHost for USBHost<C> {
+    fn controller(&self) -> &dyn Controller {
+        &self.ctrl
+    }
+}
+
+struct Bus {
+    id: u8,
+    /// 控制器的中断号，轮询模式为`None`
+    irq: Option<usize>,
+    host: Box<dyn AnyHost>,
+}
+
+/// 管理多个控制器：分配总线号、汇总事件并把中断分发给对应的控制器。
+/// 各总线的控制器类型可以不同，按类型用`get`取回后使用其专有接口。增删控制器需要`&mut self`，其余方法可在中断与多个 CPU 间共享
+pub struct HostManager {
+    /// 按总线号排序
+    buses: Vec<Bus>,
+    /// `poll_event`下次开始查询的位置，避免某个控制器的事件饿死其他控制器
+    next_poll: AtomicUsize,
+}
+
+impl Default for HostManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostManager {
+    pub const fn new() -> Self {
+        Self {
+            buses: Vec::new(),
+            next_poll: AtomicUsize::new(0),
+        }
+    }
+
+    /// 登记控制器，返回分配的总线号：从 1 开始取最小的空闲号
+    pub fn add<C: Controller + 'static>(
+        &mut self,
+        host: USBHost<C>,
+        irq: Option<usize>,
+    ) -> Result<u8> {
+        let pos = self
+            .buses
+            .iter()
+            .enumerate()
+            .position(|(i, bus)| bus.id as usize != i + 1)
+            .unwrap_or(self.buses.len());
+        let id = u8::try_from(pos + 1).map_err(|_| USBError::NotSupported)?;
+        self.buses.insert(
+            pos,
+            Bus {
+                id,
+                irq,
+                host: Box::new(host),
+            },
+        );
+        info!("USB bus {} registered, irq {:?}", id, irq);
+        Ok(id)
+    }
+
+    /// 移除控制器，调用方应先`shutdown`。总线号可被之后登记的控制器复用；
+    /// 控制器不是`C`类型时不移除，返回`None`
+    pub fn remove<C: Controller + 'static>(&mut self, bus: u8) -> Option<USBHost<C>> {
+        let pos = self.buses.iter().position(|b| b.id == bus)?;
+        if !(&*self.buses[pos].host as &dyn Any).is::<USBHost<C>>() {
+            return None;
+        }
+        let host: Box<dyn Any> = self.buses.remove(pos).host;
+        host.downcast().ok().map(|h| *h)
+    }
+
+    /// 取回`bus`上的控制器，类型不是`C`时返回`None`
+    pub fn get<C: Controller + 'static>(&self, bus: u8) -> Option<&USBHost<C>> {
+        let host = &*self.buses.iter().find(|b| b.id == bus)?.host;
+        (host as &dyn Any).downcast_ref()
+    }
+
+    pub fn controller(&self, bus: u8) -> Option<&dyn Controller> {
+        self.buses
+            .iter()
+            .find(|b| b.id == bus)
+            .map(|b| b.host.controller())
+    }
+
+    /// 按总线号顺序遍历
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &dyn Controller)> {
+        self.buses.iter().map(|b| (b.id, b.host.controller()))
+    }
+
+    /// 按总线号顺序遍历`C`类型的控制器
+    pub fn iter_as<C: Controller + 'static>(&self) -> impl Iterator<Item = (u8, &USBHost<C>)> {
+        self.buses.iter().filter_map(|b| {
+            (&*b.host as &dyn Any)
+                .downcast_ref()
+                .map(|host| (b.id, host))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.buses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buses.is_empty()
+    }
+
+    /// 依次初始化所有控制器，返回初始化失败的总线及错误，失败的控制器不影响其他控制器
+    pub async fn init(&self) -> Vec<(u8, USBError)> {
+        let mut failed = Vec::new();
+        for bus in &self.buses {
+            if let Err(e) = bus.host.controller().init().await {
+                warn!("USB bus {} init failed: {}", bus.id, e);
+                failed.push((bus.id, e));
+            }
+        }
+        failed
+    }
+
+    /// 在所有控制器上`probe`，返回 (总线号, 设备)；某条总线出错时跳过该总线
+    pub async fn probe(&self) -> Vec<(u8, DeviceHandle)> {
+        let mut devices = Vec::new();
+        for bus in &self.buses {
+            match bus.host.controller().probe().await {
+                Ok(list) => devices.extend(list.into_iter().map(|dev| (bus.id, dev))),
+                Err(e) => warn!("USB bus {} probe failed: {}", bus.id, e),
+            }
+        }
+        devices
+    }
+
+    /// 轮流从各控制器取事件
+    pub fn poll_event(&self) -> Option<BusEvent> {
+        let n = self.buses.len();
+        if n == 0 {
+            return None;
+        }
+        let start = self.next_poll.fetch_add(1, Ordering::Relaxed) % n;
+        (0..n)
+            .map(|i| &self.buses[(start + i) % n])
+            .find_map(|bus| {
+                bus.host
+                    .controller()
+                    .poll_event()
+                    .map(|event| BusEvent { bus: bus.id, event })
+            })
+    }
+
+    /// 中断处理程序调用，共享同一中断号的控制器都会处理，返回是否有控制器使用该中断
+    pub fn handle_irq(&self, irq: usize) -> bool {
+        let mut handled = false;
+        for bus in self.buses.iter().filter(|b| b.irq == Some(irq)) {
+            bus.host.controller().handle_irq();
+            handled = true;
+        }
+        handled
+    }
+
+    /// 停止所有控制器，返回第一个错误，其余控制器仍会被停止
+    pub async fn shutdown(&self, release_to_bios: bool) -> Result {
+        let mut res = Ok(());
+        for bus in &self.buses {
+            if let Err(e) = bus.host.controller().shutdown(release_to_bios).await {
+                warn!("USB bus {} shutdown failed: {}", bus.id, e);
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, future::LocalBoxFuture};
+
+    use super::*;
+
+    struct Idle;
+    impl Controller for Idle {
+        fn init(&self) -> LocalBoxFuture<'_, Result> {
+            async { Ok(()) }.boxed_local()
+        }
+    }
+
+    struct Resetting;
+    impl Controller for Resetting {
+        fn init(&self) -> LocalBoxFuture<'_, Result> {
+            async { Ok(()) }.boxed_local()
+        }
+
+        fn poll_event(&self) -> Option<HostEvent> {
+            Some(HostEvent::Reset)
+        }
+    }
+
+    #[test]
+    fn mixed_controllers() {
+        let mut m = HostManager::new();
+        assert_eq!(m.add(USBHost::from(Idle), None), Ok(1));
+        assert_eq!(m.add(USBHost::from(Resetting), Some(5)), Ok(2));
+        assert_eq!(m.len(), 2);
+        assert!(m.handle_irq(5));
+        assert!(!m.handle_irq(6));
+        assert_eq!(
+            m.poll_event(),
+            Some(BusEvent {
+                bus: 2,
+                event: HostEvent::Reset
+            })
+        );
+
+        assert!(m.remove::<Idle>(1).is_some());
+        assert_eq!(m.add(USBHost::from(Resetting), None), Ok(1));
+    }
+
+    struct Counter(AtomicUsize);
+    impl Controller for Counter {
+        fn init(&self) -> LocalBoxFuture<'_, Result> {
+            async { Ok(()) }.boxed_local()
+        }
+
+        fn handle_irq(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 只有具体类型才有的接口
+    impl USBHost<Counter> {
+        fn irqs(&self) -> usize {
+            self.ctrl.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn typed_lookup() {
+        let mut m = HostManager::new();
+        m.add(USBHost::from(Idle), None).unwrap();
+        let bus = m
+            .add(USBHost::from(Counter(AtomicUsize::new(0))), Some(7))
+            .unwrap();
+        assert!(m.handle_irq(7));
+
+        assert_eq!(m.get::<Counter>(bus).unwrap().irqs(), 1);
+        assert!(m.get::<Counter>(1).is_none());
+        assert!(m.get::<crate::Xhci>(bus).is_none());
+        assert!(m.controller(bus).is_some());
+        let ids: Vec<u8> = m.iter_as::<Counter>().map(|(id, _)| id).collect();
+        assert_eq!(ids, [bus]);
+
+        // 类型不符时不移除
+        assert!(m.remove::<Idle>(bus).is_none());
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.remove::<Counter>(bus).unwrap().irqs(), 1);
+        assert_eq!(m.len(), 1);
+    }
+}
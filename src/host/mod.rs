@@ -1,11 +1,17 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
 use core::ptr::NonNull;
 
 use futures::{FutureExt, future::LocalBoxFuture};
 
+mod manager;
 pub mod xhci;
 
-use crate::err::*;
-pub use xhci::Xhci;
+use crate::{SetupPacket, err::*};
+pub use manager::{BusEvent, HostManager};
+pub use xhci::{
+    ContextUpdate, Dbc, DbcConfig, DbcState, DeviceState, DeviceStrings, DmaStats, EndpointConfig,
+    HubInfo, InBuf, OutBuf, PciConfig, PciId, Quirks, TransferKind, Xhci,
+};
 
 pub struct USBHost<C>
 where
@@ -23,34 +29,323 @@ where
     }
 }
 
-impl USBHost<Xhci> {
-    pub fn new(reg_base: NonNull<u8>) -> Self {
-        Self::from(Xhci::new(reg_base))
-    }
-
-    pub async fn init(&mut self) -> Result {
+impl<C> USBHost<C>
+where
+    C: Controller,
+{
+    pub async fn init(&self) -> Result {
         self.ctrl.init().await
     }
 
-    pub async fn test_cmd(&mut self) -> Result {
+    pub async fn test_cmd(&self) -> Result {
         // for _ in 0..300 {
-            self.ctrl.test_cmd().await?;
+        self.ctrl.test_cmd().await?;
         // }
 
         Ok(())
     }
 
-    pub unsafe fn handle_irq(&mut self) {
+    /// 为根端口上已连接的设备分配 slot 并完成寻址
+    pub async fn probe(&self) -> Result<Vec<DeviceHandle>> {
+        self.ctrl.probe().await
+    }
+
+    /// 从致命错误中恢复：复位控制器并重新枚举设备
+    pub async fn recover(&self) -> Result {
+        self.ctrl.recover().await
+    }
+
+    pub fn poll_event(&self) -> Option<HostEvent> {
+        self.ctrl.poll_event()
+    }
+
+    /// 停止控制器并释放 DMA 内存，之后可重新`init`。
+    /// `release_to_bios`为 true 时将控制器所有权交还 BIOS
+    pub async fn shutdown(&self, release_to_bios: bool) -> Result {
+        self.ctrl.shutdown(release_to_bios).await
+    }
+
+    /// 中断处理程序调用，可与其他 CPU 上的请求并发执行
+    pub fn handle_irq(&self) {
         self.ctrl.handle_irq();
     }
 }
 
-pub trait Controller {
-    fn init(&mut self) -> LocalBoxFuture<'_, Result>;
+impl USBHost<Xhci> {
+    /// `mode`为`Polling`时无需调用`handle_irq`
+    pub fn new(reg_base: NonNull<u8>, mode: EventMode) -> Self {
+        Self::from(Xhci::new(reg_base, mode))
+    }
+
+    /// PCI 控制器，`pci`用于读取设备标识及执行需要配置空间的规避措施
+    pub fn new_pci(reg_base: NonNull<u8>, pci: impl PciConfig + 'static, mode: EventMode) -> Self {
+        Self::from(Xhci::new_pci(reg_base, Box::new(pci), mode))
+    }
+
+    /// 生效的规避措施，内核据此决定是否使用 MSI、唤醒后是否重新`init`
+    pub fn quirks(&self) -> Quirks {
+        self.ctrl.quirks()
+    }
+
+    pub fn add_quirks(&mut self, quirks: Quirks) {
+        self.ctrl.add_quirks(quirks);
+    }
+
+    /// 非 PCI 控制器启用 Local Memory 前需设置寄存器空间的总线地址
+    pub fn set_mmio_bus_addr(&mut self, addr: u64) {
+        self.ctrl.set_mmio_bus_addr(addr);
+    }
+
+    pub fn debug_capability(&self, config: &DbcConfig) -> Result<Dbc> {
+        self.ctrl.debug_capability(config)
+    }
+
+    /// 非 SuperSpeed 设备先以 BSR=1 寻址读取 EP0 最大包长，再复位端口正式寻址，
+    /// 兼容对 SET_ADDRESS 时序敏感的设备
+    pub fn set_bsr_first(&self, enable: bool) {
+        self.ctrl.set_bsr_first(enable);
+    }
+
+    pub fn device_state(&self, dev: DeviceHandle) -> Result<DeviceState> {
+        self.ctrl.device_state(dev)
+    }
+
+    /// 枚举时读取的设备描述符
+    pub fn device_descriptor(&self, dev: DeviceHandle) -> Result<Vec<u8>> {
+        self.ctrl.device_descriptor(dev)
+    }
+
+    /// 枚举时读取的第`index`个完整配置描述符
+    pub fn config_descriptor(&self, dev: DeviceHandle, index: u8) -> Result<Vec<u8>> {
+        self.ctrl.config_descriptor(dev, index)
+    }
+
+    /// 枚举时选择或之后经 SET_CONFIGURATION 设置的配置值
+    pub fn active_configuration(&self, dev: DeviceHandle) -> Result<Option<u8>> {
+        self.ctrl.active_configuration(dev)
+    }
+
+    /// EP0 IN 控制传输，数据阶段长度为`setup.length`，返回实际收到的字节数
+    pub async fn control_in(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        self.ctrl.control_in(dev, setup, buf).await
+    }
+
+    /// EP0 OUT 控制传输，发送`data`的前`setup.length`字节，长度为 0 时没有数据阶段
+    pub async fn control_out(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> Result<usize> {
+        self.ctrl.control_out(dev, setup, data).await
+    }
+
+    /// 批量/中断 IN 传输，`endpoint`为 bEndpointAddress，返回实际收到的字节数。
+    /// `buf`可为切片、`DVec`或`DBox`，控制器直接写入，不做拷贝；
+    /// 不满足控制器限制的缓冲区自动经驱动缓冲区中转，见`dma_stats`
+    pub async fn transfer_in(
+        &self,
+        dev: DeviceHandle,
+        endpoint: u8,
+        buf: impl Into<InBuf<'_>>,
+    ) -> Result<usize> {
+        self.ctrl.transfer_in(dev, endpoint, buf).await
+    }
+
+    /// 批量/中断 OUT 传输，`data`同样不做拷贝
+    pub async fn transfer_out(
+        &self,
+        dev: DeviceHandle,
+        endpoint: u8,
+        data: impl Into<OutBuf<'_>>,
+    ) -> Result<usize> {
+        self.ctrl.transfer_out(dev, endpoint, data).await
+    }
+
+    /// 设备支持多种语言时优先选用的 LANGID，默认为英语（美国）
+    pub fn set_string_language(&self, lang_id: u16) {
+        self.ctrl.set_string_language(lang_id);
+    }
+
+    /// 读取`index`号字符串描述符并解码，结果按设备缓存
+    pub async fn string(&self, dev: DeviceHandle, index: u8) -> Result<String> {
+        self.ctrl.string(dev, index).await
+    }
+
+    /// 读取厂商、产品及序列号字符串，个别字符串读取失败时对应字段为`None`
+    pub async fn device_strings(&self, dev: DeviceHandle) -> Result<DeviceStrings> {
+        self.ctrl.device_strings(dev).await
+    }
+
+    pub fn dma_stats(&self) -> DmaStats {
+        self.ctrl.dma_stats()
+    }
+
+    pub async fn evaluate_context(&self, dev: DeviceHandle, update: ContextUpdate) -> Result {
+        self.ctrl.evaluate_context(dev, update).await
+    }
+
+    /// hub 驱动复位下游端口后调用，为其上的设备分配 slot 并完成寻址
+    pub async fn attach_hub_port(
+        &self,
+        hub: DeviceHandle,
+        port: u8,
+        speed: Speed,
+    ) -> Result<DeviceHandle> {
+        self.ctrl.attach_hub_port(hub, port, speed).await
+    }
+
+    /// 复位设备并重新寻址，句柄保持有效，之前选择的配置会重新下发。
+    /// hub 下的设备需由 hub 驱动先复位其所在端口
+    pub async fn reset_device(&self, dev: DeviceHandle) -> Result {
+        self.ctrl.reset_device(dev).await
+    }
+
+    /// hub 驱动发现下游设备断开时调用，挂在其下的设备一并移除
+    pub async fn detach(&self, dev: DeviceHandle) -> Result {
+        self.ctrl.detach(dev).await
+    }
+
+    /// 添加`add`中的端点并移除地址在`drop`中的端点，带宽不足时返回`Bandwidth`
+    pub async fn configure_endpoints(
+        &self,
+        dev: DeviceHandle,
+        add: &[EndpointConfig],
+        drop: &[u8],
+    ) -> Result {
+        self.ctrl.configure_endpoints(dev, add, drop).await
+    }
+
+    /// 根端口（`hub`为`None`）或 hub 各下游端口的可用带宽百分比
+    pub async fn port_bandwidth(&self, hub: Option<DeviceHandle>, speed: Speed) -> Result<Vec<u8>> {
+        self.ctrl.port_bandwidth(hub, speed).await
+    }
+
+    pub async fn negotiate_bandwidth(&self, dev: DeviceHandle) -> Result {
+        self.ctrl.negotiate_bandwidth(dev).await
+    }
+
+    /// 将已配置的设备标记为 hub，之后才能在其端口上`attach_hub_port`
+    pub async fn set_hub(&self, dev: DeviceHandle, info: HubInfo) -> Result {
+        self.ctrl.set_hub(dev, info).await
+    }
+}
+
+/// 已寻址设备的句柄，控制器复位后失效
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceHandle {
+    slot_id: u8,
+    port_id: u8,
+}
+
+impl DeviceHandle {
+    pub(crate) fn new(slot_id: u8, port_id: u8) -> Self {
+        Self { slot_id, port_id }
+    }
+
+    pub fn slot_id(&self) -> u8 {
+        self.slot_id
+    }
+
+    /// 所在根端口，从 1 开始
+    pub fn port_id(&self) -> u8 {
+        self.port_id
+    }
+}
+
+/// 事件的获取方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventMode {
+    /// 由中断处理程序调用`handle_irq`
+    Interrupt,
+    /// 不使用中断，等待命令/传输完成时及`poll_event`中直接读取事件环
+    Polling,
+}
 
-    fn test_cmd(&mut self) -> LocalBoxFuture<'_, Result> {
+/// 设备速度，取值与 xHCI 默认 Protocol Speed ID 一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Speed {
+    Full = 1,
+    Low = 2,
+    High = 3,
+    Super = 4,
+    SuperPlus = 5,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostEvent {
+    /// 控制器出现致命错误，等待中的请求均已失败，需调用`recover`
+    Error(USBError),
+    /// 控制器已复位，此前的`DeviceHandle`全部失效
+    Reset,
+    /// 设备完成寻址
+    Attached(DeviceHandle),
+    /// 设备已断开，其传输均以`Disconnected`结束，下次`probe`时释放 slot
+    Detached(DeviceHandle),
+    /// 控制器建议重新协商该设备的周期端点带宽
+    BandwidthRequest(DeviceHandle),
+}
+
+/// 方法均取`&self`，实现内部自行同步，可在中断与多个 CPU 间共享
+pub trait Controller: Send + Sync {
+    fn init(&self) -> LocalBoxFuture<'_, Result>;
+
+    fn test_cmd(&self) -> LocalBoxFuture<'_, Result> {
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, Result<Vec<DeviceHandle>>> {
+        async { Ok(Vec::new()) }.boxed_local()
+    }
+
+    fn recover(&self) -> LocalBoxFuture<'_, Result> {
         async { Ok(()) }.boxed_local()
     }
 
-    fn handle_irq(&mut self) {}
+    fn poll_event(&self) -> Option<HostEvent> {
+        None
+    }
+
+    fn shutdown(&self, _release: bool) -> LocalBoxFuture<'_, Result> {
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn handle_irq(&self) {}
+}
+
+/// 使`USBHost<Box<dyn Controller>>`可以持有不同类型的控制器
+impl<C: Controller + ?Sized> Controller for Box<C> {
+    fn init(&self) -> LocalBoxFuture<'_, Result> {
+        (**self).init()
+    }
+
+    fn test_cmd(&self) -> LocalBoxFuture<'_, Result> {
+        (**self).test_cmd()
+    }
+
+    fn probe(&self) -> LocalBoxFuture<'_, Result<Vec<DeviceHandle>>> {
+        (**self).probe()
+    }
+
+    fn recover(&self) -> LocalBoxFuture<'_, Result> {
+        (**self).recover()
+    }
+
+    fn poll_event(&self) -> Option<HostEvent> {
+        (**self).poll_event()
+    }
+
+    fn shutdown(&self, release: bool) -> LocalBoxFuture<'_, Result> {
+        (**self).shutdown(release)
+    }
+
+    fn handle_irq(&self) {
+        (**self).handle_irq()
+    }
 }
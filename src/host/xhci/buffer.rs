@@ -0,0 +1,494 @@
+use core::{
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{vec, vec::Vec};
+use dma_api::{DBox, DSlice, DSliceMut, DVec, Direction};
+use log::{trace, warn};
+
+use super::ring::{TRB_MAX_BUFFER, split_boundary};
+use crate::{dma_capable, dma_coherent, err::*, page_size};
+
+/// 碎片化的缓冲区拆出的 TRB 数超过此值，且多于同样长度的连续缓冲区所需时改为中转
+const SG_MAX_TRBS: usize = 32;
+
+/// 批量/中断 IN 传输的接收缓冲区，控制器直接写入，不经过驱动的中间缓冲区。
+/// 普通切片在传输期间按页映射，`DVec`/`DBox`本身已是 DMA 内存，直接使用；
+/// 不满足控制器限制时自动经驱动缓冲区中转
+pub struct InBuf<'a>(InSource<'a>);
+
+enum InSource<'a> {
+    Slice(&'a mut [u8]),
+    Vec(&'a mut DVec<u8>),
+    /// `DBox`无法按字节访问，不能中转
+    Box(u64, usize, PhantomData<&'a mut [u8]>),
+}
+
+impl<'a> From<&'a mut [u8]> for InBuf<'a> {
+    fn from(value: &'a mut [u8]) -> Self {
+        Self(InSource::Slice(value))
+    }
+}
+
+impl<'a, const N: usize> From<&'a mut [u8; N]> for InBuf<'a> {
+    fn from(value: &'a mut [u8; N]) -> Self {
+        Self(InSource::Slice(value))
+    }
+}
+
+impl<'a> From<&'a mut DVec<u8>> for InBuf<'a> {
+    fn from(value: &'a mut DVec<u8>) -> Self {
+        Self(InSource::Vec(value))
+    }
+}
+
+impl<'a, T> From<&'a mut DBox<T>> for InBuf<'a> {
+    fn from(value: &'a mut DBox<T>) -> Self {
+        Self(InSource::Box(value.bus_addr(), size_of::<T>(), PhantomData))
+    }
+}
+
+impl InBuf<'_> {
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            InSource::Slice(s) => s.len(),
+            InSource::Vec(v) => v.len(),
+            InSource::Box(_, len, _) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 批量/中断 OUT 传输的数据，同`InBuf`
+pub struct OutBuf<'a>(OutSource<'a>);
+
+enum OutSource<'a> {
+    Slice(&'a [u8]),
+    Vec(&'a DVec<u8>),
+    Box(u64, usize, PhantomData<&'a [u8]>),
+}
+
+impl<'a> From<&'a [u8]> for OutBuf<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Self(OutSource::Slice(value))
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for OutBuf<'a> {
+    fn from(value: &'a [u8; N]) -> Self {
+        Self(OutSource::Slice(value))
+    }
+}
+
+impl<'a> From<&'a DVec<u8>> for OutBuf<'a> {
+    fn from(value: &'a DVec<u8>) -> Self {
+        Self(OutSource::Vec(value))
+    }
+}
+
+impl<'a, T> From<&'a DBox<T>> for OutBuf<'a> {
+    fn from(value: &'a DBox<T>) -> Self {
+        Self(OutSource::Box(
+            value.bus_addr(),
+            size_of::<T>(),
+            PhantomData,
+        ))
+    }
+}
+
+impl<'a> OutBuf<'a> {
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            OutSource::Slice(s) => s.len(),
+            OutSource::Vec(v) => v.len(),
+            OutSource::Box(_, len, _) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 切片形式的数据，用于不超过 8 字节时直接写入 TRB
+    pub(crate) fn as_slice(&self) -> Option<&'a [u8]> {
+        match self.0 {
+            OutSource::Slice(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// 调用方缓冲区映射后的分散/聚集表，析构时解除映射
+pub struct SgList<'a> {
+    /// 各段 (总线地址, 长度)，总线地址连续的相邻页已合并
+    segs: Vec<(u64, usize)>,
+    maps: Vec<Mapping<'a>>,
+    /// `map_in`映射的调用方缓冲区，改为中转时由`into_slice`交还
+    buf: Option<&'a mut [u8]>,
+}
+
+enum Mapping<'a> {
+    In(DSliceMut<'a, u8>),
+    /// 只需在析构时解除映射
+    Out {
+        _map: DSlice<'a, u8>,
+    },
+}
+
+impl<'a> SgList<'a> {
+    /// 按虚拟页边界切分，各页分别映射，物理上不必连续
+    fn map_in(s: &'a mut [u8]) -> Self {
+        let mut list = Self::empty();
+        let base = s.as_mut_ptr();
+        let mut offset = 0;
+        while offset < s.len() {
+            let ptr = base.wrapping_add(offset);
+            let len = page_remaining(ptr, s.len() - offset);
+            // SAFETY: 各页互不重叠且都在`s`内。映射只用于 DMA 与缓存维护，不经由它读写内存；
+            // `s`保存在本结构中，`into_slice`先解除全部映射才交还
+            let page = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+            let map = DSliceMut::from(page, Direction::FromDevice);
+            list.push(map.bus_addr(), map.len());
+            list.maps.push(Mapping::In(map));
+            offset += len;
+        }
+        list.buf = Some(s);
+        list
+    }
+
+    /// 解除映射，交还`map_in`的缓冲区，其他方式构造的返回空切片
+    fn into_slice(self) -> &'a mut [u8] {
+        let Self { maps, buf, .. } = self;
+        drop(maps);
+        buf.unwrap_or_default()
+    }
+
+    fn map_out(s: &'a [u8]) -> Self {
+        let mut list = Self::empty();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (page, tail) = rest.split_at(page_remaining(rest.as_ptr(), rest.len()));
+            let map = DSlice::from(page);
+            list.push(map.bus_addr(), map.len());
+            list.maps.push(Mapping::Out { _map: map });
+            rest = tail;
+        }
+        list
+    }
+
+    fn empty() -> Self {
+        Self {
+            segs: Vec::new(),
+            maps: Vec::new(),
+            buf: None,
+        }
+    }
+
+    fn contiguous(bus: u64, len: usize) -> Self {
+        let mut list = Self::empty();
+        list.push(bus, len);
+        list
+    }
+
+    fn push(&mut self, bus: u64, len: usize) {
+        if len == 0 {
+            return;
+        }
+        if let Some((last, n)) = self.segs.last_mut()
+            && *last + *n as u64 == bus
+        {
+            *n += len;
+            return;
+        }
+        self.segs.push((bus, len));
+    }
+
+    pub fn segments(&self) -> &[(u64, usize)] {
+        &self.segs
+    }
+
+    pub fn len(&self) -> usize {
+        self.segs.iter().map(|&(_, n)| n).sum()
+    }
+
+    /// IN 传输结束后使 CPU 缓存失效，之后调用方才能看到控制器写入的数据
+    pub fn complete(&self) {
+        if dma_coherent() {
+            return;
+        }
+        for map in &self.maps {
+            if let Mapping::In(map) = map {
+                map.preper_read_all();
+            }
+        }
+    }
+}
+
+/// 从`ptr`起到所在页末尾的字节数，不超过`len`
+fn page_remaining(ptr: *const u8, len: usize) -> usize {
+    let page = page_size();
+    (page - (ptr as usize & (page - 1))).min(len)
+}
+
+/// IN 中转结束后数据的去处
+pub enum BounceTarget<'a> {
+    Slice(&'a mut [u8]),
+    Vec(&'a mut DVec<u8>),
+}
+
+impl BounceTarget<'_> {
+    /// 由`read`把中转缓冲区的前`n`字节写入调用方缓冲区
+    pub fn fill(&mut self, n: usize, read: impl FnOnce(&mut [u8])) {
+        match self {
+            Self::Slice(s) => read(&mut s[..n]),
+            Self::Vec(v) => {
+                // `DVec`没有可变切片，只经临时缓冲区写回收到的前`n`字节，短包时其余字节不变；
+                // `DVec::copy_from_slice`实际要求长度相同，仅整块时使用
+                let mut tmp = vec![0; n];
+                read(&mut tmp);
+                if n == v.len() {
+                    v.copy_from_slice(&tmp);
+                } else {
+                    for (i, &b) in tmp.iter().enumerate() {
+                        v.set(i, b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub enum InPlan<'a> {
+    Direct(SgList<'a>),
+    Bounce(BounceTarget<'a>),
+}
+
+pub enum OutPlan<'a> {
+    Direct(SgList<'a>),
+    /// 复制到驱动缓冲区的数据
+    Bounce(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BounceReason {
+    AboveLimit,
+    Fragmented,
+    NotDmaCapable,
+}
+
+/// 零拷贝传输的统计，计数自控制器创建起累计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DmaStats {
+    /// 直接使用调用方缓冲区的传输数
+    pub direct: u64,
+    /// 经驱动缓冲区中转的传输数
+    pub bounced: u64,
+    /// 中转的总字节数
+    pub bounced_bytes: u64,
+    /// 因地址超出控制器寻址范围（AC64=0 时为 4GiB）而中转
+    pub above_limit: u64,
+    /// 因碎片过多而中转
+    pub fragmented: u64,
+    /// 因`Kernel::dma_capable`判定不可映射而中转
+    pub not_dma_capable: u64,
+}
+
+/// 决定调用方缓冲区直接映射还是中转，并记录统计
+pub struct DmaPolicy {
+    /// 控制器可访问的最高总线地址
+    addr_limit: AtomicU64,
+    direct: AtomicU64,
+    bounced: AtomicU64,
+    bounced_bytes: AtomicU64,
+    above_limit: AtomicU64,
+    fragmented: AtomicU64,
+    not_dma_capable: AtomicU64,
+}
+
+impl DmaPolicy {
+    pub const fn new() -> Self {
+        Self {
+            addr_limit: AtomicU64::new(u64::MAX),
+            direct: AtomicU64::new(0),
+            bounced: AtomicU64::new(0),
+            bounced_bytes: AtomicU64::new(0),
+            above_limit: AtomicU64::new(0),
+            fragmented: AtomicU64::new(0),
+            not_dma_capable: AtomicU64::new(0),
+        }
+    }
+
+    /// 按 HCCPARAMS1 的 AC64 位设置寻址范围
+    pub fn set_ac64(&self, ac64: bool) {
+        let limit = if ac64 { u64::MAX } else { u32::MAX as u64 };
+        self.addr_limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> DmaStats {
+        DmaStats {
+            direct: self.direct.load(Ordering::Relaxed),
+            bounced: self.bounced.load(Ordering::Relaxed),
+            bounced_bytes: self.bounced_bytes.load(Ordering::Relaxed),
+            above_limit: self.above_limit.load(Ordering::Relaxed),
+            fragmented: self.fragmented.load(Ordering::Relaxed),
+            not_dma_capable: self.not_dma_capable.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn place_in<'a>(&self, buf: InBuf<'a>) -> Result<InPlan<'a>> {
+        let len = buf.len();
+        let (reason, target) = match buf.0 {
+            InSource::Box(bus, len, _) => {
+                return self.direct_box(bus, len).map(InPlan::Direct);
+            }
+            InSource::Vec(v) => match self.reachable(v.bus_addr(), v.len()) {
+                true => {
+                    self.count_direct();
+                    return Ok(InPlan::Direct(SgList::contiguous(v.bus_addr(), v.len())));
+                }
+                false => (BounceReason::AboveLimit, BounceTarget::Vec(v)),
+            },
+            InSource::Slice(s) if !capable(s) => {
+                (BounceReason::NotDmaCapable, BounceTarget::Slice(s))
+            }
+            InSource::Slice(s) => {
+                let sg = SgList::map_in(s);
+                match self.check(&sg) {
+                    None => {
+                        self.count_direct();
+                        return Ok(InPlan::Direct(sg));
+                    }
+                    Some(reason) => (reason, BounceTarget::Slice(sg.into_slice())),
+                }
+            }
+        };
+        self.count_bounce(reason, len);
+        Ok(InPlan::Bounce(target))
+    }
+
+    pub fn place_out<'a>(&self, buf: OutBuf<'a>) -> Result<OutPlan<'a>> {
+        let len = buf.len();
+        let (reason, data) = match buf.0 {
+            OutSource::Box(bus, len, _) => {
+                return self.direct_box(bus, len).map(OutPlan::Direct);
+            }
+            OutSource::Vec(v) => match self.reachable(v.bus_addr(), v.len()) {
+                true => {
+                    self.count_direct();
+                    return Ok(OutPlan::Direct(SgList::contiguous(v.bus_addr(), v.len())));
+                }
+                false => (BounceReason::AboveLimit, &**v),
+            },
+            OutSource::Slice(s) if !capable(s) => (BounceReason::NotDmaCapable, s),
+            OutSource::Slice(s) => {
+                let sg = SgList::map_out(s);
+                match self.check(&sg) {
+                    None => {
+                        self.count_direct();
+                        return Ok(OutPlan::Direct(sg));
+                    }
+                    Some(reason) => (reason, s),
+                }
+            }
+        };
+        self.count_bounce(reason, len);
+        Ok(OutPlan::Bounce(data))
+    }
+
+    fn direct_box<'a>(&self, bus: u64, len: usize) -> Result<SgList<'a>> {
+        if !self.reachable(bus, len) {
+            warn!("DBox @{:#x} is beyond the controller's address limit", bus);
+            return Err(USBError::NotSupported);
+        }
+        self.count_direct();
+        Ok(SgList::contiguous(bus, len))
+    }
+
+    fn reachable(&self, bus: u64, len: usize) -> bool {
+        let limit = self.addr_limit.load(Ordering::Relaxed);
+        len == 0 || bus.saturating_add(len as u64 - 1) <= limit
+    }
+
+    /// 映射后的缓冲区不满足控制器限制时返回中转原因
+    fn check(&self, sg: &SgList) -> Option<BounceReason> {
+        if !sg.segs.iter().all(|&(bus, len)| self.reachable(bus, len)) {
+            return Some(BounceReason::AboveLimit);
+        }
+        let trbs = split_boundary(&sg.segs).len();
+        if trbs > SG_MAX_TRBS && trbs > sg.len().div_ceil(TRB_MAX_BUFFER) + 1 {
+            return Some(BounceReason::Fragmented);
+        }
+        None
+    }
+
+    fn count_direct(&self) {
+        self.direct.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_bounce(&self, reason: BounceReason, len: usize) {
+        trace!("bounce {} bytes: {:?}", len, reason);
+        self.bounced.fetch_add(1, Ordering::Relaxed);
+        self.bounced_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        let counter = match reason {
+            BounceReason::AboveLimit => &self.above_limit,
+            BounceReason::Fragmented => &self.fragmented,
+            BounceReason::NotDmaCapable => &self.not_dma_capable,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn capable(s: &[u8]) -> bool {
+    NonNull::new(s.as_ptr() as *mut u8).is_some_and(|p| dma_capable(p, s.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dvec(fill: u8, len: usize) -> DVec<u8> {
+        let mut v = DVec::zeros(len, 64, Direction::FromDevice).unwrap();
+        for i in 0..len {
+            v.set(i, fill);
+        }
+        v
+    }
+
+    #[test]
+    fn short_in_packet_keeps_tail() {
+        let mut v = dvec(0xAA, 8);
+        BounceTarget::Vec(&mut v).fill(3, |buf| buf.copy_from_slice(&[1, 2, 3]));
+        assert_eq!(&*v, [1, 2, 3, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]);
+
+        BounceTarget::Vec(&mut v).fill(8, |buf| buf.fill(5));
+        assert_eq!(&*v, [5; 8]);
+    }
+
+    #[test]
+    fn zero_length_in_packet() {
+        let mut v = dvec(7, 4);
+        BounceTarget::Vec(&mut v).fill(0, |buf| assert!(buf.is_empty()));
+        assert_eq!(&*v, [7; 4]);
+    }
+
+    #[test]
+    fn bounced_slice_is_returned() {
+        // 主机上以虚拟地址作为总线地址，栈位于 4GiB 以上
+        let dma = DmaPolicy::new();
+        dma.set_ac64(false);
+        let mut buf = [0u8; 16];
+        let ptr = buf.as_ptr();
+        let Ok(InPlan::Bounce(BounceTarget::Slice(s))) = dma.place_in(InBuf::from(&mut buf)) else {
+            panic!("expected bounce");
+        };
+        assert_eq!((s.as_ptr(), s.len()), (ptr, 16));
+        s[0] = 1;
+        assert_eq!(buf[0], 1);
+        assert_eq!(dma.stats().above_limit, 1);
+    }
+}
@@ -0,0 +1,280 @@
+use core::{mem, task::Waker};
+
+use alloc::vec::Vec;
+use log::{debug, warn};
+use xhci::{
+    context::EndpointState,
+    ring::trb::{
+        command,
+        event::{Allowed, CompletionCode},
+    },
+};
+
+use super::{
+    Data, Xhci,
+    completion::RingKey,
+    mem::MemVec,
+    ring::{Ring, TRB_LEN, TrbBuffer},
+};
+use crate::{DeviceHandle, err::*};
+
+/// 已敲门铃、尚未完成的 TD。等待它的 future 被丢弃时登记一个取消请求后立即返回，
+/// 由事件处理路径停止端点并只把该 TD 改为 No Op，同一端点上的其他 TD 照常完成。
+/// 驱动分配的缓冲区随请求移交，退役后才释放；直接映射的调用方缓冲区在返回时即解除映射，
+/// 端点停止前控制器仍可能访问
+pub(super) struct TdGuard<'g, 'a> {
+    pub xhci: &'g Xhci,
+    pub dev: DeviceHandle,
+    pub dci: u8,
+    /// TD 的全部 TRB，不含 Link
+    pub trbs: Vec<u64>,
+    /// 尚未取走结果的等待地址
+    pub waits: Vec<u64>,
+    pub data: &'g mut TrbBuffer<'a>,
+}
+
+impl TdGuard<'_, '_> {
+    /// TD 已结束，丢弃时不再取消
+    pub fn done(mut self) {
+        self.trbs.clear();
+    }
+}
+
+impl Drop for TdGuard<'_, '_> {
+    fn drop(&mut self) {
+        if self.trbs.is_empty() {
+            return;
+        }
+        let buf = self.data.take_owned();
+        self.xhci
+            .cancel_td(self.dev, self.dci, &self.trbs, &self.waits, buf);
+    }
+}
+
+/// 取消请求当前等待的命令
+#[derive(Clone, Copy)]
+enum Step {
+    Stop(u64),
+    /// 端点因该 TD 出错而 Halted
+    Reset(u64),
+    Dequeue(u64),
+}
+
+pub(super) struct TdCancel {
+    dev: DeviceHandle,
+    dci: u8,
+    /// 传输环首段的地址，slot 被重新分配后据此识别旧环
+    ring: u64,
+    td: Vec<(u64, [u32; TRB_LEN])>,
+    step: Step,
+    /// 退役前不能释放的驱动缓冲区
+    buf: Option<MemVec<u8>>,
+}
+
+impl Xhci {
+    /// 放弃已敲门铃的 TD，不等待任何命令
+    fn cancel_td(
+        &self,
+        dev: DeviceHandle,
+        dci: u8,
+        trbs: &[u64],
+        waits: &[u64],
+        mut buf: Option<MemVec<u8>>,
+    ) {
+        let key = RingKey::transfer(dev.slot_id(), dci);
+        let mut done = Vec::new();
+        let mut pending = false;
+        for &addr in waits {
+            match self.completions.disarm(key, addr) {
+                None => pending = true,
+                Some(Ok(Allowed::TransferEvent(ev))) => match ev.completion_code() {
+                    Ok(CompletionCode::Success | CompletionCode::ShortPacket) => done.push(addr),
+                    // 出错后端点 Halted，原本由等待者复位
+                    _ => pending = true,
+                },
+                // 环已解绑或被复位，其中的 TD 已一并丢弃
+                Some(_) => {}
+            }
+        }
+
+        let res = self.with_data(|data| {
+            let Data {
+                dev_list,
+                cmd,
+                cancels,
+                ..
+            } = data;
+            let Some(ring) = dev_list
+                .by_slot_mut(dev.slot_id())
+                .filter(|ctx| ctx.port_id == dev.port_id())
+                .and_then(|ctx| ctx.transfer_rings.get_mut(&dci))
+            else {
+                // slot 已禁用，端点不再访问内存
+                return Ok(());
+            };
+            for &addr in &done {
+                ring.set_dequeue(addr);
+            }
+            if !pending {
+                return Ok(());
+            }
+
+            debug!(
+                "Slot {} DCI {} transfer dropped, stopping endpoint",
+                dev.slot_id(),
+                dci
+            );
+            let mut trb = command::StopEndpoint::new();
+            trb.set_slot_id(dev.slot_id()).set_endpoint_id(dci);
+            let addr = self.queue_cmd(cmd, command::Allowed::StopEndpoint(trb))?;
+            ring.stopping += 1;
+            cancels.push(TdCancel {
+                dev,
+                dci,
+                ring: ring_id(ring),
+                td: ring.snapshot(trbs),
+                step: Step::Stop(addr),
+                buf: buf.take(),
+            });
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            warn!("Slot {} DCI {} cancel failed: {}", dev.slot_id(), dci, e);
+            // 控制器可能仍在访问，宁可泄漏也不能释放
+            mem::forget(buf);
+        }
+    }
+
+    /// 推进各取消请求，`waker`为`None`时不登记唤醒
+    pub(super) fn advance_cancels(&self, data: &mut Data, waker: Option<&Waker>) {
+        let mut cancels = mem::take(&mut data.cancels);
+        cancels.retain_mut(|c| {
+            let res = self.advance_cancel(data, c, waker);
+            if let Ok(true) = res {
+                return true;
+            }
+            if let Err(e) = res {
+                warn!(
+                    "Slot {} DCI {} cancel failed: {}",
+                    c.dev.slot_id(),
+                    c.dci,
+                    e
+                );
+                mem::forget(c.buf.take());
+            }
+            if let Some(ring) = cancel_ring(data, c) {
+                ring.stopping -= 1;
+                if ring.stopping == 0 {
+                    self.ring_doorbell(c.dev.slot_id(), c.dci);
+                }
+            }
+            false
+        });
+        cancels.append(&mut data.cancels);
+        data.cancels = cancels;
+    }
+
+    /// 返回请求是否仍在进行
+    fn advance_cancel(
+        &self,
+        data: &mut Data,
+        c: &mut TdCancel,
+        waker: Option<&Waker>,
+    ) -> Result<bool> {
+        let (Step::Stop(addr) | Step::Reset(addr) | Step::Dequeue(addr)) = c.step;
+        let res = match waker {
+            Some(waker) => self.completions.poll_result(RingKey::COMMAND, addr, waker),
+            None => self.completions.take(RingKey::COMMAND, addr),
+        };
+        let Some(res) = res else {
+            return Ok(true);
+        };
+        data.cmd.set_dequeue(addr);
+        let Allowed::CommandCompletion(ev) = res? else {
+            return Err(USBError::Unknown);
+        };
+        let code = ev.completion_code().map_err(|_| USBError::Unknown)?;
+
+        let Some(ctx) = data
+            .dev_list
+            .by_slot_mut(c.dev.slot_id())
+            .filter(|ctx| ctx.port_id == c.dev.port_id())
+        else {
+            return Ok(false);
+        };
+        let (state, hw_deq) = ctx.out.endpoint(c.dci);
+        let Some(ring) = ctx
+            .transfer_rings
+            .get_mut(&c.dci)
+            .filter(|r| ring_id(r) == c.ring)
+        else {
+            return Ok(false);
+        };
+        let in_td = c.td.iter().any(|&(a, _)| a == hw_deq);
+
+        let retire = match (c.step, code) {
+            (Step::Stop(_), CompletionCode::Success) => true,
+            // Halted 或已停止时 Stop Endpoint 返回 Context State Error；
+            // 停在本 TD 上说明是它出错，其他 TD 出错时由其等待者复位
+            (Step::Stop(_), CompletionCode::ContextStateError) => match state {
+                EndpointState::Halted if in_td => false,
+                EndpointState::Halted | EndpointState::Stopped => true,
+                _ => return Err(USBError::CommandFailed(code)),
+            },
+            (Step::Reset(_), CompletionCode::Success | CompletionCode::ContextStateError) => true,
+            (Step::Dequeue(_), CompletionCode::Success) => {
+                if let Some(&(last, _)) = c.td.last() {
+                    ring.set_dequeue(last);
+                }
+                return Ok(false);
+            }
+            (_, code) => return Err(USBError::CommandFailed(code)),
+        };
+
+        let trb = if retire {
+            let Some((deq, cycle)) = ring.retire_td(&c.td, hw_deq) else {
+                return Ok(false);
+            };
+            set_tr_dequeue(c, deq, cycle)
+        } else {
+            let mut trb = command::ResetEndpoint::new();
+            trb.set_slot_id(c.dev.slot_id()).set_endpoint_id(c.dci);
+            command::Allowed::ResetEndpoint(trb)
+        };
+        let addr = self.queue_cmd(&mut data.cmd, trb)?;
+        c.step = if retire {
+            Step::Dequeue(addr)
+        } else {
+            Step::Reset(addr)
+        };
+        Ok(true)
+    }
+}
+
+fn ring_id(ring: &Ring) -> u64 {
+    ring.segment_addrs().next().unwrap_or(0)
+}
+
+fn cancel_ring<'d>(data: &'d mut Data, c: &TdCancel) -> Option<&'d mut Ring> {
+    data.dev_list
+        .by_slot_mut(c.dev.slot_id())
+        .filter(|ctx| ctx.port_id == c.dev.port_id())?
+        .transfer_rings
+        .get_mut(&c.dci)
+        .filter(|r| ring_id(r) == c.ring)
+}
+
+/// 把 dequeue 指针移过被退役的 TD
+fn set_tr_dequeue(c: &TdCancel, deq: u64, cycle: bool) -> command::Allowed {
+    let mut trb = command::SetTrDequeuePointer::new();
+    trb.set_slot_id(c.dev.slot_id())
+        .set_endpoint_id(c.dci)
+        .set_new_tr_dequeue_pointer(deq);
+    if cycle {
+        trb.set_dequeue_cycle_state();
+    } else {
+        trb.clear_dequeue_cycle_state();
+    }
+    command::Allowed::SetTrDequeuePointer(trb)
+}
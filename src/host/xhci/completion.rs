@@ -0,0 +1,425 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+use core::task::Waker;
+
+use alloc::boxed::Box;
+use crossbeam::queue::SegQueue;
+use futures::task::AtomicWaker;
+use log::{debug, trace};
+use spin::Once;
+use xhci::ring::trb::event::{Allowed, CompletionCode};
+
+use super::ring::{Ring, TRB_SIZE};
+use crate::err::*;
+
+/// 每个 slot 占用的表数，下标即 Device Context Index；slot 0 的 0 号为命令环
+const RINGS_PER_SLOT: usize = 32;
+
+/// 未登记，或结果已被取走
+const IDLE: u8 = 0;
+/// 已登记，等待完成事件
+const ARMED: u8 = 1;
+/// 中断正在写入事件
+const WRITING: u8 = 2;
+/// 事件已写入
+const DONE: u8 = 3;
+/// 此后为`Cancel`
+const CANCELLED: u8 = 4;
+
+/// 驱动主动结束请求的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Cancel {
+    Disconnected,
+    DeviceReset,
+    /// 端点复位时丢弃的 TD
+    Stopped,
+}
+
+impl Cancel {
+    fn from_state(state: u8) -> Self {
+        match state - CANCELLED {
+            0 => Self::Disconnected,
+            1 => Self::DeviceReset,
+            _ => Self::Stopped,
+        }
+    }
+
+    fn error(self) -> USBError {
+        match self {
+            Self::Disconnected => USBError::Disconnected,
+            Self::DeviceReset => USBError::DeviceReset,
+            Self::Stopped => USBError::TransferEventError(CompletionCode::Stopped),
+        }
+    }
+}
+
+/// 命令环或某个 slot 的某个端点的传输环
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingKey {
+    slot_id: u8,
+    dci: u8,
+}
+
+impl RingKey {
+    pub const COMMAND: Self = Self { slot_id: 0, dci: 0 };
+
+    pub fn transfer(slot_id: u8, dci: u8) -> Self {
+        Self { slot_id, dci }
+    }
+
+    fn index(self) -> usize {
+        self.slot_id as usize * RINGS_PER_SLOT + self.dci as usize
+    }
+}
+
+/// 单个 TRB 的完成状态
+#[derive(Default)]
+struct Slot {
+    state: AtomicU8,
+    /// 完成事件 TRB 的原始数据，`state`为`DONE`时有效
+    event: [AtomicU32; 4],
+    /// TD 内其他 TRB 指向末尾 TRB 的下标加一，0 表示无
+    alias: AtomicU32,
+    waker: AtomicWaker,
+}
+
+impl Slot {
+    fn arm(&self) {
+        self.alias.store(0, Ordering::Relaxed);
+        self.state.store(ARMED, Ordering::Release);
+    }
+
+    /// 只有已登记的 TRB 接受事件，返回是否写入
+    fn complete(&self, raw: [u32; 4]) -> bool {
+        if self
+            .state
+            .compare_exchange(ARMED, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        for (word, v) in self.event.iter().zip(raw) {
+            word.store(v, Ordering::Relaxed);
+        }
+        self.state.store(DONE, Ordering::Release);
+        self.waker.wake();
+        true
+    }
+
+    fn cancel(&self, reason: Cancel) {
+        if self
+            .state
+            .compare_exchange(
+                ARMED,
+                CANCELLED + reason as u8,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            self.waker.wake();
+        }
+    }
+
+    /// 撤销登记，返回撤销前已写入的结果
+    fn disarm(&self) -> Option<Result<Allowed>> {
+        loop {
+            match self
+                .state
+                .compare_exchange(ARMED, IDLE, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return None,
+                Err(WRITING) => spin_loop(),
+                Err(_) => return self.take(),
+            }
+        }
+    }
+
+    fn take(&self) -> Option<Result<Allowed>> {
+        match self.state.load(Ordering::Acquire) {
+            ARMED | WRITING => None,
+            DONE => {
+                let raw = core::array::from_fn(|i| self.event[i].load(Ordering::Relaxed));
+                self.state.store(IDLE, Ordering::Release);
+                Some(Allowed::try_from(raw).map_err(|_| USBError::Unknown))
+            }
+            // 等待期间环被解绑或控制器被复位
+            IDLE => Some(Err(USBError::Disconnected)),
+            state => {
+                self.state.store(IDLE, Ordering::Release);
+                Some(Err(Cancel::from_state(state).error()))
+            }
+        }
+    }
+}
+
+/// 一个环的结果表，TRB 地址按所在段与段内偏移换算为下标
+struct RingTable {
+    /// 每段 TRB 数
+    seg_len: usize,
+    /// 各段的总线地址，按分配先后排列，0 表示未使用
+    bases: Box<[AtomicU64]>,
+    slots: Box<[Slot]>,
+}
+
+impl RingTable {
+    fn new(seg_len: usize, max_segs: usize) -> Self {
+        Self {
+            seg_len,
+            bases: (0..max_segs).map(|_| AtomicU64::new(0)).collect(),
+            slots: (0..seg_len * max_segs).map(|_| Slot::default()).collect(),
+        }
+    }
+
+    fn index(&self, trb_addr: u64) -> Option<usize> {
+        for (seg, base) in self.bases.iter().enumerate() {
+            let base = base.load(Ordering::Acquire);
+            if base == 0 {
+                break;
+            }
+            if let Some(off) = trb_addr.checked_sub(base) {
+                let i = off as usize / TRB_SIZE;
+                if i < self.seg_len {
+                    return Some(seg * self.seg_len + i);
+                }
+            }
+        }
+        None
+    }
+
+    fn slot(&self, trb_addr: u64) -> Option<&Slot> {
+        self.index(trb_addr).map(|i| &self.slots[i])
+    }
+
+    /// 登记环扩展出的新段，已有段的下标保持不变
+    fn sync(&self, ring: &Ring) {
+        for addr in ring.segment_addrs() {
+            if self.index(addr).is_some() {
+                continue;
+            }
+            if let Some(free) = self.bases.iter().find(|b| b.load(Ordering::Relaxed) == 0) {
+                free.store(addr, Ordering::Release);
+            }
+        }
+    }
+
+    /// 解除与环的绑定，等待者以`Disconnected`结束
+    fn clear(&self) {
+        for base in self.bases.iter() {
+            base.store(0, Ordering::Release);
+        }
+        for slot in self.slots.iter() {
+            if slot.state.swap(IDLE, Ordering::AcqRel) != IDLE {
+                slot.waker.wake();
+            }
+        }
+    }
+}
+
+/// 命令/传输完成结果，每个环一张定长表，中断中只做原子操作。
+/// 以 (slot, DCI) 定位表，表在控制器生命周期内不释放，解绑后可重新绑定新环
+pub struct Completions {
+    tables: Once<Box<[Once<RingTable>]>>,
+    /// 非命令/传输完成的事件，由`Xhci`处理
+    unsolicited: SegQueue<Allowed>,
+}
+
+impl Completions {
+    pub fn new() -> Self {
+        Self {
+            tables: Once::new(),
+            unsolicited: SegQueue::new(),
+        }
+    }
+
+    /// 按控制器支持的 slot 数分配表项，只在首次初始化时生效
+    pub fn init(&self, max_slots: usize) {
+        self.tables.call_once(|| {
+            (0..(max_slots + 1) * RINGS_PER_SLOT)
+                .map(|_| Once::new())
+                .collect()
+        });
+    }
+
+    fn table(&self, key: RingKey) -> Option<&RingTable> {
+        self.tables.get()?.get(key.index())?.get()
+    }
+
+    fn tables(&self) -> impl Iterator<Item = &RingTable> {
+        self.tables
+            .get()
+            .into_iter()
+            .flat_map(|t| t.iter().filter_map(|t| t.get()))
+    }
+
+    /// 控制器复位后解绑所有环
+    pub fn reset(&self) {
+        self.tables().for_each(RingTable::clear);
+        while self.unsolicited.pop().is_some() {}
+    }
+
+    /// 把`ring`绑定到`key`，原先绑定的环上的等待者以`Disconnected`结束
+    pub fn bind(&self, key: RingKey, ring: &Ring) -> Result {
+        let entry = self
+            .tables
+            .get()
+            .ok_or(USBError::NotInitialized)?
+            .get(key.index())
+            .ok_or(USBError::SlotLimitReached)?;
+        let table = entry.call_once(|| RingTable::new(ring.len(), ring.max_segments()));
+        if table.seg_len != ring.len() || table.bases.len() < ring.max_segments() {
+            return Err(USBError::NotSupported);
+        }
+        table.clear();
+        table.sync(ring);
+        Ok(())
+    }
+
+    /// 环释放后调用
+    pub fn unbind(&self, key: RingKey) {
+        if let Some(table) = self.table(key) {
+            table.clear();
+        }
+    }
+
+    /// 登记等待`trb_addr`的完成事件，需在敲门铃之前调用
+    pub fn arm(&self, key: RingKey, ring: &Ring, trb_addr: u64) -> Result {
+        let table = self.table(key).ok_or(USBError::NotInitialized)?;
+        table.sync(ring);
+        table.slot(trb_addr).ok_or(USBError::Unknown)?.arm();
+        Ok(())
+    }
+
+    /// 登记一个 TD，其中任一 TRB 上的事件（如中途 Stall）都投递到末尾 TRB，
+    /// 返回用于等待的地址
+    pub fn arm_td(&self, key: RingKey, ring: &Ring, trbs: &[u64]) -> Result<u64> {
+        let (&last, rest) = trbs.split_last().expect("empty TD");
+        let table = self.table(key).ok_or(USBError::NotInitialized)?;
+        table.sync(ring);
+
+        let target = table.index(last).ok_or(USBError::Unknown)?;
+        table.slots[target].arm();
+        for &addr in rest {
+            table
+                .slot(addr)
+                .ok_or(USBError::Unknown)?
+                .alias
+                .store(target as u32 + 1, Ordering::Release);
+        }
+        Ok(last)
+    }
+
+    /// 让`key`上所有等待中的请求以`reason`结束，已完成未取走的结果保持不变
+    pub fn fail_ring(&self, key: RingKey, reason: Cancel) {
+        if let Some(table) = self.table(key) {
+            for slot in table.slots.iter() {
+                slot.cancel(reason);
+            }
+        }
+    }
+
+    /// 取走结果，尚未完成时登记`waker`
+    pub fn poll_result(
+        &self,
+        key: RingKey,
+        trb_addr: u64,
+        waker: &Waker,
+    ) -> Option<Result<Allowed>> {
+        let Some(slot) = self.table(key).and_then(|t| t.slot(trb_addr)) else {
+            return Some(Err(USBError::Disconnected));
+        };
+        if let Some(res) = slot.take() {
+            return Some(res);
+        }
+        slot.waker.register(waker);
+        // 登记之后再检查一次，避免错过登记前写入的事件
+        slot.take()
+    }
+
+    /// 取走结果但不登记 waker，尚未完成时返回`None`
+    pub fn take(&self, key: RingKey, trb_addr: u64) -> Option<Result<Allowed>> {
+        match self.table(key).and_then(|t| t.slot(trb_addr)) {
+            Some(slot) => slot.take(),
+            None => Some(Err(USBError::Disconnected)),
+        }
+    }
+
+    /// 放弃等待`trb_addr`，此后到达的事件被忽略。返回放弃前已到达的结果，仍在等待时为`None`
+    pub fn disarm(&self, key: RingKey, trb_addr: u64) -> Option<Result<Allowed>> {
+        match self.table(key).and_then(|t| t.slot(trb_addr)) {
+            Some(slot) => slot.disarm(),
+            None => Some(Err(USBError::Disconnected)),
+        }
+    }
+
+    /// 从事件环取出的事件，可在中断中调用
+    pub fn push(&self, allowed: Allowed) {
+        match allowed {
+            // 命令环停止事件指向下一条待执行的命令，不是该命令的完成
+            Allowed::CommandCompletion(c)
+                if c.completion_code() == Ok(CompletionCode::CommandRingStopped) =>
+            {
+                debug!("Command ring stopped @{:X}", c.command_trb_pointer());
+            }
+            Allowed::CommandCompletion(c) => {
+                self.complete(RingKey::COMMAND, c.command_trb_pointer(), allowed);
+            }
+            // Stop Endpoint 中断的 TD 在端点重新启动后从停止处继续，不是该 TD 的完成
+            Allowed::TransferEvent(t)
+                if matches!(
+                    t.completion_code(),
+                    Ok(CompletionCode::Stopped
+                        | CompletionCode::StoppedLengthInvalid
+                        | CompletionCode::StoppedShortPacket)
+                ) =>
+            {
+                debug!(
+                    "Slot {} DCI {} stopped @{:X}",
+                    t.slot_id(),
+                    t.endpoint_id(),
+                    t.trb_pointer()
+                );
+            }
+            Allowed::TransferEvent(t) => {
+                let key = RingKey::transfer(t.slot_id(), t.endpoint_id());
+                self.complete(key, t.trb_pointer(), allowed);
+            }
+            Allowed::PortStatusChange(_) | Allowed::BandwidthRequest(_) => {
+                trace!("[EVENT] << {:?}", allowed);
+                self.unsolicited.push(allowed);
+            }
+            _ => {
+                debug!("unhandled event {:?}", allowed);
+            }
+        }
+    }
+
+    fn complete(&self, key: RingKey, addr: u64, allowed: Allowed) {
+        trace!("[EVENT] << {:?} @{:X}", allowed, addr);
+        let Some((table, i)) = self.table(key).and_then(|t| Some((t, t.index(addr)?))) else {
+            debug!("{:?} event for unknown TRB @{:X}", key, addr);
+            return;
+        };
+        let i = match table.slots[i].alias.load(Ordering::Acquire) {
+            0 => i,
+            target => target as usize - 1,
+        };
+        if !table.slots[i].complete(allowed.into_raw()) {
+            trace!("{:?} stale event @{:X}", key, addr);
+        }
+    }
+
+    pub fn take_unsolicited(&self) -> Option<Allowed> {
+        self.unsolicited.pop()
+    }
+
+    /// 唤醒所有等待者重新检查致命错误、端口断开等状态，可在中断中调用
+    pub fn wake_all(&self) {
+        for table in self.tables() {
+            for slot in table.slots.iter() {
+                slot.waker.wake();
+            }
+        }
+    }
+}
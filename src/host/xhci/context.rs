@@ -1,64 +1,211 @@
-use alloc::vec::Vec;
-use dma_api::{DBox, DVec};
-use xhci::context::{Device, Device64Byte, Input64Byte};
-
-use super::ring::Ring;
+use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
+use dma_api::DVec;
+use xhci::context::{
+    Device32Byte, Device64Byte, DeviceHandler, EndpointHandler, EndpointState, Input32Byte,
+    Input64Byte, InputHandler,
+};
+
+use super::{
+    device::{HubInfo, Route},
+    endpoint::EndpointConfig,
+    enumerate::DeviceState,
+    mem::{MemBox, MemPool, MemVec},
+    ring::Ring,
+};
 use crate::err::*;
 
+/// 传输环满时最多扩展到的段数
+const TRANSFER_RING_SEGMENTS: usize = 8;
+
 pub struct DeviceContextList {
-    pub dcbaa: DVec<u64>,
+    pub dcbaa: MemVec<u64>,
     pub device_context_list: Vec<DeviceContext>,
+    pub pool: MemPool,
     max_slots: usize,
+    ctx_64: bool,
 }
 
 pub struct DeviceContext {
-    pub out: DBox<Device64Byte>,
-    pub input: DBox<Input64Byte>,
-    pub transfer_rings: Vec<Ring>,
+    pub slot_id: u8,
+    /// 所在根端口
+    pub port_id: u8,
+    pub route: Route,
+    pub hub: Option<HubInfo>,
+    /// EP0 当前的最大包长
+    pub ep0_max_packet_size: u16,
+    pub state: DeviceState,
+    /// 枚举时读取的设备描述符
+    pub descriptor: Vec<u8>,
+    /// 枚举时读取的完整配置描述符，以配置索引为下标
+    pub configs: Vec<Vec<u8>>,
+    /// 最近一次 SET_CONFIGURATION 的值，复位后重新下发
+    pub configuration: Option<u8>,
+    /// 设备已断开，等待 Disable Slot
+    pub gone: bool,
+    pub out: OutputContext,
+    pub input: InputContext,
+    /// 以 Device Context Index 为键
+    pub transfer_rings: BTreeMap<u8, Ring>,
+    /// 已配置端点，复位后据此恢复
+    pub endpoints: BTreeMap<u8, EndpointConfig>,
+    /// 读取字符串描述符使用的语言，首次读取时协商
+    pub lang_id: Option<u16>,
+    /// 已读取的字符串描述符，以索引为键
+    pub strings: BTreeMap<u8, String>,
+}
+
+/// HCCPARAMS1.CSZ 决定上下文为 32 字节还是 64 字节
+pub enum OutputContext {
+    Byte32(MemBox<Device32Byte>),
+    Byte64(MemBox<Device64Byte>),
+}
+
+pub enum InputContext {
+    Byte32(MemBox<Input32Byte>),
+    Byte64(MemBox<Input64Byte>),
+}
+
+impl OutputContext {
+    fn new(pool: &MemPool, ctx_64: bool) -> Result<Self> {
+        let dir = dma_api::Direction::FromDevice;
+        Ok(if ctx_64 {
+            Self::Byte64(MemBox::zero_with_align(pool, dir, 64)?)
+        } else {
+            Self::Byte32(MemBox::zero_with_align(pool, dir, 64)?)
+        })
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        match self {
+            Self::Byte32(b) => b.bus_addr(),
+            Self::Byte64(b) => b.bus_addr(),
+        }
+    }
+
+    /// 控制器写回的端点状态与 TR Dequeue Pointer（已去掉 DCS 位）
+    pub fn endpoint(&self, dci: u8) -> (EndpointState, u64) {
+        let read = |ep: &dyn EndpointHandler| (ep.endpoint_state(), ep.tr_dequeue_pointer() & !0xF);
+        match self {
+            Self::Byte32(b) => read(b.read().endpoint(dci as usize)),
+            Self::Byte64(b) => read(b.read().endpoint(dci as usize)),
+        }
+    }
+}
+
+impl InputContext {
+    fn new(pool: &MemPool, ctx_64: bool) -> Result<Self> {
+        let dir = dma_api::Direction::ToDevice;
+        Ok(if ctx_64 {
+            Self::Byte64(MemBox::zero_with_align(pool, dir, 64)?)
+        } else {
+            Self::Byte32(MemBox::zero_with_align(pool, dir, 64)?)
+        })
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        match self {
+            Self::Byte32(b) => b.bus_addr(),
+            Self::Byte64(b) => b.bus_addr(),
+        }
+    }
+
+    pub fn modify(&mut self, f: impl FnOnce(&mut dyn InputHandler)) {
+        match self {
+            Self::Byte32(b) => b.modify(|c| f(c)),
+            Self::Byte64(b) => b.modify(|c| f(c)),
+        }
+    }
 }
 
 impl DeviceContext {
-    fn new() -> Result<Self> {
-        let out = DBox::zero(dma_api::Direction::ToDevice).ok_or(USBError::NoMemory)?;
-        let input = DBox::zero(dma_api::Direction::FromDevice).ok_or(USBError::NoMemory)?;
+    fn new(slot_id: u8, route: Route, pool: &MemPool, ctx_64: bool) -> Result<Self> {
         Ok(Self {
-            out,
-            input,
-            transfer_rings: Vec::new(),
+            slot_id,
+            port_id: route.root_port,
+            route,
+            hub: None,
+            ep0_max_packet_size: 0,
+            state: DeviceState::Default,
+            descriptor: Vec::new(),
+            configs: Vec::new(),
+            configuration: None,
+            gone: false,
+            out: OutputContext::new(pool, ctx_64)?,
+            input: InputContext::new(pool, ctx_64)?,
+            transfer_rings: BTreeMap::new(),
+            endpoints: BTreeMap::new(),
+            lang_id: None,
+            strings: BTreeMap::new(),
         })
     }
 }
 
 impl DeviceContextList {
-    pub fn new(max_slots: usize) -> Result<Self> {
-        let dcbaa =
-            DVec::zeros(256, 0x1000, dma_api::Direction::ToDevice).ok_or(USBError::NoMemory)?;
+    pub fn new(max_slots: usize, ctx_64: bool, pool: MemPool) -> Result<Self> {
+        let dcbaa = MemVec::zeros(&pool, 256, 0x1000, dma_api::Direction::ToDevice)?;
 
         Ok(Self {
             dcbaa,
             device_context_list: Vec::new(),
+            pool,
             max_slots,
+            ctx_64,
         })
     }
 
     pub fn new_slot(
         &mut self,
         slot: usize,
+        route: Route,
         num_ep: usize, // cannot lesser than 0, and consider about alignment, use usize
-    ) -> Result {
+    ) -> Result<&mut DeviceContext> {
         if slot > self.max_slots {
             Err(USBError::SlotLimitReached)?;
         }
 
-        let mut ctx = DeviceContext::new()?;
+        let mut ctx = DeviceContext::new(slot as _, route, &self.pool, self.ctx_64)?;
 
         self.dcbaa.set(slot, ctx.out.bus_addr());
 
-        ctx.transfer_rings = (0..num_ep)
-            .map(|_| Ring::new_with_len(32, true, dma_api::Direction::Bidirectional))
-            .try_collect()?;
+        for dci in 1..=num_ep as u8 {
+            ctx.transfer_rings.insert(dci, self.new_transfer_ring()?);
+        }
+
+        self.device_context_list.push(ctx);
+
+        Ok(self.device_context_list.last_mut().unwrap())
+    }
 
-        Ok(())
+    /// 从 DCBAA 中摘除，须在 Disable Slot 完成之后调用
+    pub fn remove(&mut self, slot_id: u8) -> Option<DeviceContext> {
+        let i = self
+            .device_context_list
+            .iter()
+            .position(|c| c.slot_id == slot_id)?;
+        self.dcbaa.set(slot_id as _, 0);
+        Some(self.device_context_list.remove(i))
+    }
+
+    pub fn slot_ids(&self) -> Vec<u8> {
+        self.device_context_list.iter().map(|c| c.slot_id).collect()
+    }
+
+    pub fn by_slot_mut(&mut self, slot_id: u8) -> Option<&mut DeviceContext> {
+        self.device_context_list
+            .iter_mut()
+            .find(|c| c.slot_id == slot_id)
+    }
+
+    pub fn new_transfer_ring(&self) -> Result<Ring> {
+        let mut ring = Ring::new_in(&self.pool, 32, true, dma_api::Direction::Bidirectional)?;
+        ring.set_max_segments(TRANSFER_RING_SEGMENTS);
+        Ok(ring)
+    }
+
+    pub fn by_port(&self, port_id: u8) -> Option<&DeviceContext> {
+        self.device_context_list
+            .iter()
+            .find(|c| c.port_id == port_id)
     }
 }
 
@@ -69,17 +216,21 @@ pub struct ScratchpadBufferArray {
 
 impl ScratchpadBufferArray {
     pub fn new(entries: usize) -> Result<Self> {
-        let entries =
+        let mut entries =
             DVec::zeros(entries, 64, dma_api::Direction::ToDevice).ok_or(USBError::NoMemory)?;
 
-        let pages = entries
-            .iter()
+        let pages: Vec<DVec<u8>> = (0..entries.len())
             .map(|_| {
-                DVec::<u8>::zeros(0x1000, 0x1000, dma_api::Direction::ToDevice)
+                // 由控制器读写，映射方向须为双向
+                DVec::<u8>::zeros(0x1000, 0x1000, dma_api::Direction::Bidirectional)
                     .ok_or(USBError::NoMemory)
             })
             .try_collect()?;
 
+        for (i, page) in pages.iter().enumerate() {
+            entries.set(i, page.bus_addr());
+        }
+
         Ok(Self { entries, pages })
     }
 
@@ -0,0 +1,647 @@
+use core::{fmt, hint::spin_loop, ops::Deref};
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    vec::Vec,
+};
+use dma_api::{DBox, DVec, Direction};
+use log::{debug, info, warn};
+use xhci::{
+    context::{Endpoint64Byte, EndpointHandler, EndpointType},
+    extended_capabilities::debug::Debug,
+    ring::trb::{
+        event::{Allowed, CompletionCode, TransferEvent},
+        transfer,
+    },
+};
+
+use super::{MemMapper, event::EventRing, ring::Ring};
+use crate::err::*;
+
+const DBC_MAX_PACKET_SIZE: usize = 1024;
+const DBC_STRING_SIZE: usize = 64;
+const DBC_REQ_NUM: usize = 8;
+const DBC_TX_LIMIT: usize = 0x10000;
+const DBC_ENABLE_TIMEOUT: usize = 1_000_000;
+
+const DOORBELL_OUT: u8 = 0;
+const DOORBELL_IN: u8 = 1;
+
+const DESCRIPTOR_TYPE_STRING: u8 = 3;
+const LANGID_EN_US: u16 = 0x0409;
+
+/// DbC 向调试主机报告的设备信息
+///
+/// 默认值与 Linux 的 DbC 实现一致，Linux 调试主机会自动以`usb_debug`驱动绑定，
+/// 生成`/dev/ttyUSBx`。
+#[derive(Debug, Clone)]
+pub struct DbcConfig {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_revision: u16,
+    pub protocol: u8,
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub serial: &'static str,
+}
+
+impl Default for DbcConfig {
+    fn default() -> Self {
+        Self {
+            vendor_id: 0x1d6b,
+            product_id: 0x0010,
+            device_revision: 0x0010,
+            protocol: 0,
+            manufacturer: "usb-host",
+            product: "Linux USB Debug Target",
+            serial: "0001",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbcState {
+    Disabled,
+    /// 已使能，等待调试线缆连接
+    Enabled,
+    /// 已连接，等待调试主机完成枚举
+    Connected,
+    /// 可以收发数据
+    Configured,
+    /// 端点被调试主机 halt
+    Stalled,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DbcInfoContext {
+    string0: u64,
+    manufacturer: u64,
+    product: u64,
+    serial: u64,
+    length: u32,
+    _reserved: [u32; 7],
+}
+
+/// DbC 的三个上下文固定为 64 字节，与 CSZ 无关
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct DbcContext {
+    info: DbcInfoContext,
+    out: Endpoint64Byte,
+    in_: Endpoint64Byte,
+}
+
+/// xHCI Debug Capability，以字节流的形式收发数据
+///
+/// DbC 没有中断，需要周期性调用`poll`，`read`/`write`内部也会调用。
+pub struct Dbc {
+    regs: Debug<MemMapper>,
+    state: DbcState,
+    event: EventRing,
+    out_ring: Ring,
+    in_ring: Ring,
+    ctx: DBox<DbcContext>,
+    strings: DVec<u8>,
+    out_bufs: Vec<DVec<u8>>,
+    out_free: Vec<usize>,
+    out_pending: BTreeMap<u64, usize>,
+    in_bufs: Vec<DVec<u8>>,
+    in_pending: BTreeMap<u64, usize>,
+    tx: VecDeque<u8>,
+    rx: VecDeque<u8>,
+}
+
+impl Dbc {
+    pub(crate) fn new(regs: Debug<MemMapper>, config: &DbcConfig) -> Result<Self> {
+        let event = EventRing::new()?;
+        let out_ring = Ring::new(true, Direction::Bidirectional)?;
+        let in_ring = Ring::new(true, Direction::Bidirectional)?;
+        let ctx = DBox::zero(Direction::Bidirectional).ok_or(USBError::NoMemory)?;
+
+        let mut raw = [0u8; DBC_STRING_SIZE * 4];
+        let mut length = 0u32;
+        {
+            let (string0, rest) = raw.split_at_mut(DBC_STRING_SIZE);
+            let (manufacturer, rest) = rest.split_at_mut(DBC_STRING_SIZE);
+            let (product, serial) = rest.split_at_mut(DBC_STRING_SIZE);
+
+            let lang = LANGID_EN_US.to_le_bytes();
+            string0[..4].copy_from_slice(&[4, DESCRIPTOR_TYPE_STRING, lang[0], lang[1]]);
+            length |= 4;
+            length |= (string_descriptor(config.manufacturer, manufacturer) as u32) << 8;
+            length |= (string_descriptor(config.product, product) as u32) << 16;
+            length |= (string_descriptor(config.serial, serial) as u32) << 24;
+        }
+        let mut strings =
+            DVec::zeros(raw.len(), 64, Direction::ToDevice).ok_or(USBError::NoMemory)?;
+        strings.copy_from_slice(&raw);
+
+        let out_bufs: Vec<DVec<u8>> = (0..DBC_REQ_NUM)
+            .map(|_| {
+                DVec::zeros(DBC_MAX_PACKET_SIZE, 64, Direction::ToDevice).ok_or(USBError::NoMemory)
+            })
+            .try_collect()?;
+        let in_bufs = (0..DBC_REQ_NUM)
+            .map(|_| {
+                DVec::zeros(DBC_MAX_PACKET_SIZE, 64, Direction::FromDevice)
+                    .ok_or(USBError::NoMemory)
+            })
+            .try_collect()?;
+
+        let mut dbc = Self {
+            regs,
+            state: DbcState::Disabled,
+            event,
+            out_ring,
+            in_ring,
+            ctx,
+            out_free: (0..out_bufs.len()).collect(),
+            out_bufs,
+            out_pending: BTreeMap::new(),
+            in_bufs,
+            in_pending: BTreeMap::new(),
+            strings,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+        };
+
+        let strings_addr = dbc.strings.bus_addr();
+        dbc.ctx.modify(|ctx| {
+            ctx.info.string0 = strings_addr;
+            ctx.info.manufacturer = strings_addr + DBC_STRING_SIZE as u64;
+            ctx.info.product = strings_addr + 2 * DBC_STRING_SIZE as u64;
+            ctx.info.serial = strings_addr + 3 * DBC_STRING_SIZE as u64;
+            ctx.info.length = length;
+        });
+        dbc.init_endpoints();
+
+        dbc.regs.dcddi1.update_volatile(|r| {
+            r.set_vendor_id(config.vendor_id);
+            r.set_dbc_protocol(config.protocol);
+        });
+        dbc.regs.dcddi2.update_volatile(|r| {
+            r.set_product_id(config.product_id);
+            r.set_device_revision(config.device_revision);
+        });
+
+        Ok(dbc)
+    }
+
+    fn init_endpoints(&mut self) {
+        let max_burst = self.regs.dcctrl.read_volatile().debug_max_burst_size();
+        let out_deq = self.out_ring.bus_addr();
+        let out_cycle = self.out_ring.cycle;
+        let in_deq = self.in_ring.bus_addr();
+        let in_cycle = self.in_ring.cycle;
+
+        self.ctx.modify(|ctx| {
+            for (ep, ty, deq, cycle) in [
+                (&mut ctx.out, EndpointType::BulkOut, out_deq, out_cycle),
+                (&mut ctx.in_, EndpointType::BulkIn, in_deq, in_cycle),
+            ] {
+                ep.set_endpoint_type(ty);
+                ep.set_max_packet_size(DBC_MAX_PACKET_SIZE as _);
+                ep.set_max_burst_size(max_burst);
+                ep.set_tr_dequeue_pointer(deq);
+                if cycle {
+                    ep.set_dequeue_cycle_state();
+                } else {
+                    ep.clear_dequeue_cycle_state();
+                }
+            }
+        });
+    }
+
+    pub(crate) fn enable(&mut self) -> Result {
+        let erstsz = self.event.len() as u16;
+        let erstba = self.event.erstba();
+        let erdp = self.event.erdp();
+        let dccp = self.ctx.bus_addr();
+
+        self.regs.dcerstsz.update_volatile(|r| r.set(erstsz));
+        self.regs.dcerstba.update_volatile(|r| r.set(erstba));
+        self.regs.dcerdp.update_volatile(|r| {
+            r.set_dequeue_pointer(erdp);
+            r.set_dequeue_erst_segment_index(0);
+        });
+        self.regs.dccp.update_volatile(|r| r.set(dccp));
+
+        self.regs.dcctrl.update_volatile(|r| {
+            r.set_debug_capability_enable();
+            r.set_link_status_event_enable();
+        });
+
+        let mut n = 0;
+        while !self.regs.dcctrl.read_volatile().debug_capability_enable() {
+            n += 1;
+            if n > DBC_ENABLE_TIMEOUT {
+                return Err(USBError::Timeout);
+            }
+            spin_loop();
+        }
+
+        self.regs.dcportsc.update_volatile(|r| {
+            r.set_port_enabled_disabled();
+        });
+
+        self.state = DbcState::Enabled;
+        info!("DbC enabled");
+        Ok(())
+    }
+
+    pub fn disable(&mut self) {
+        if self.state == DbcState::Disabled {
+            return;
+        }
+        self.regs.dcctrl.update_volatile(|r| {
+            r.clear_debug_capability_enable();
+        });
+        self.state = DbcState::Disabled;
+        self.flush_requests();
+        debug!("DbC disabled");
+    }
+
+    pub fn state(&self) -> DbcState {
+        self.state
+    }
+
+    /// 推进状态机并处理事件环，返回当前状态
+    pub fn poll(&mut self) -> DbcState {
+        match self.state {
+            DbcState::Disabled => return self.state,
+            DbcState::Enabled => {
+                if !self.regs.dcportsc.read_volatile().current_connect_status() {
+                    return self.state;
+                }
+                debug!("DbC connected");
+                self.state = DbcState::Connected;
+            }
+            DbcState::Connected => {
+                if !self.regs.dcctrl.read_volatile().dbc_run() {
+                    return self.state;
+                }
+                info!("DbC configured");
+                self.state = DbcState::Configured;
+                // 清除 change 位
+                self.regs.dcportsc.update_volatile(|_| {});
+                self.queue_all_in();
+            }
+            DbcState::Configured => {
+                let portsc = self.regs.dcportsc.read_volatile();
+                if !portsc.port_enabled_disabled() && !portsc.current_connect_status() {
+                    info!("DbC cable unplugged");
+                    self.state = DbcState::Enabled;
+                    self.reset_endpoints();
+                    return self.state;
+                }
+                if portsc.port_reset_change() {
+                    info!("DbC port reset");
+                    self.regs.dcportsc.update_volatile(|_| {});
+                    self.state = DbcState::Enabled;
+                    self.reset_endpoints();
+                    return self.state;
+                }
+                let ctrl = self.regs.dcctrl.read_volatile();
+                if ctrl.halt_in_tr() || ctrl.halt_out_tr() {
+                    info!("DbC endpoint stall");
+                    self.state = DbcState::Stalled;
+                    return self.state;
+                }
+                if ctrl.dbc_run_change() {
+                    self.regs.dcctrl.update_volatile(|_| {});
+                }
+            }
+            DbcState::Stalled => {
+                let ctrl = self.regs.dcctrl.read_volatile();
+                if ctrl.halt_in_tr() || ctrl.halt_out_tr() || !ctrl.dbc_run() {
+                    return self.state;
+                }
+                info!("DbC endpoint stall cleared");
+                self.state = DbcState::Configured;
+                // halt 清除后端点不会自行恢复，重新敲门铃；积压的发送数据随后由`flush_tx`送出
+                self.ring_doorbell(DOORBELL_IN);
+                if !self.out_pending.is_empty() {
+                    self.ring_doorbell(DOORBELL_OUT);
+                }
+            }
+        }
+
+        self.handle_events();
+        self.flush_tx();
+        self.state
+    }
+
+    /// 写入发送缓冲，调试主机未连接时最多保留最近 64KiB 数据
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        self.tx.extend(data);
+        if self.tx.len() > DBC_TX_LIMIT {
+            let drop = self.tx.len() - DBC_TX_LIMIT;
+            self.tx.drain(..drop);
+        }
+        self.poll();
+        data.len()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.poll();
+        let n = buf.len().min(self.rx.len());
+        for (dst, src) in buf.iter_mut().zip(self.rx.drain(..n)) {
+            *dst = src;
+        }
+        n
+    }
+
+    /// 等待发送缓冲全部送出，调试主机未就绪时立即返回
+    pub fn flush(&mut self) {
+        while self.poll() == DbcState::Configured
+            && (!self.tx.is_empty() || !self.out_pending.is_empty())
+        {
+            spin_loop();
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let mut updated = false;
+
+        while let Some((allowed, _cycle)) = self.event.next() {
+            updated = true;
+            match allowed {
+                Allowed::TransferEvent(ev) => self.handle_transfer(ev),
+                Allowed::PortStatusChange(_) => {
+                    self.regs.dcportsc.update_volatile(|_| {});
+                }
+                _ => {
+                    debug!("DbC unhandled event {:?}", allowed);
+                }
+            }
+        }
+
+        if updated {
+            let erdp = self.event.erdp();
+            self.regs
+                .dcerdp
+                .update_volatile(|r| r.set_dequeue_pointer(erdp));
+        }
+    }
+
+    fn handle_transfer(&mut self, ev: TransferEvent) {
+        let addr = ev.trb_pointer();
+        let code = ev.completion_code();
+
+        if let Some(i) = self.out_pending.remove(&addr) {
+            self.out_ring.set_dequeue(addr);
+            if !matches!(code, Ok(CompletionCode::Success)) {
+                warn!("DbC OUT transfer failed: {:?}", code);
+            }
+            self.out_free.push(i);
+        } else if let Some(i) = self.in_pending.remove(&addr) {
+            self.in_ring.set_dequeue(addr);
+            match code {
+                Ok(CompletionCode::Success) | Ok(CompletionCode::ShortPacket) => {
+                    let len = DBC_MAX_PACKET_SIZE - ev.trb_transfer_length() as usize;
+                    self.rx.extend(&self.in_bufs[i].deref()[..len]);
+                }
+                _ => warn!("DbC IN transfer failed: {:?}", code),
+            }
+            self.queue_in(i);
+            self.ring_doorbell(DOORBELL_IN);
+        } else {
+            debug!("DbC transfer event for unknown TRB {:#X}", addr);
+        }
+    }
+
+    fn queue_in(&mut self, i: usize) {
+        let mut trb = transfer::Normal::new();
+        trb.set_data_buffer_pointer(self.in_bufs[i].bus_addr())
+            .set_trb_transfer_length(DBC_MAX_PACKET_SIZE as _)
+            .set_interrupt_on_short_packet()
+            .set_interrupt_on_completion();
+
+        match self.in_ring.enque_transfer(transfer::Allowed::Normal(trb)) {
+            Ok(addr) => {
+                self.in_pending.insert(addr, i);
+            }
+            Err(e) => warn!("DbC IN queue failed: {}", e),
+        }
+    }
+
+    fn queue_all_in(&mut self) {
+        for i in 0..self.in_bufs.len() {
+            if !self.in_pending.values().any(|&p| p == i) {
+                self.queue_in(i);
+            }
+        }
+        self.ring_doorbell(DOORBELL_IN);
+    }
+
+    fn flush_tx(&mut self) {
+        if self.state != DbcState::Configured {
+            return;
+        }
+
+        let mut queued = false;
+        while !self.tx.is_empty() {
+            let Some(i) = self.out_free.pop() else {
+                break;
+            };
+            let len = self.tx.len().min(DBC_MAX_PACKET_SIZE);
+            // `DVec::copy_from_slice`实际要求长度相同，不足一包时补零
+            let mut packet = [0u8; DBC_MAX_PACKET_SIZE];
+            packet[..len].copy_from_slice(&self.tx.make_contiguous()[..len]);
+            self.out_bufs[i].copy_from_slice(&packet);
+
+            let mut trb = transfer::Normal::new();
+            trb.set_data_buffer_pointer(self.out_bufs[i].bus_addr())
+                .set_trb_transfer_length(len as _)
+                .set_interrupt_on_completion();
+
+            let Ok(addr) = self.out_ring.enque_transfer(transfer::Allowed::Normal(trb)) else {
+                self.out_free.push(i);
+                break;
+            };
+            self.tx.drain(..len);
+            self.out_pending.insert(addr, i);
+            queued = true;
+        }
+
+        if queued {
+            self.ring_doorbell(DOORBELL_OUT);
+        }
+    }
+
+    fn ring_doorbell(&mut self, target: u8) {
+        self.regs
+            .dcdb
+            .update_volatile(|r| r.set_doorbell_target(target));
+    }
+
+    fn flush_requests(&mut self) {
+        for (_, i) in core::mem::take(&mut self.out_pending) {
+            self.out_free.push(i);
+        }
+        self.in_pending.clear();
+    }
+
+    /// 线缆拔出或端口复位后，端点会重新从上下文读取 dequeue 指针
+    fn reset_endpoints(&mut self) {
+        self.flush_requests();
+        match (
+            Ring::new(true, Direction::Bidirectional),
+            Ring::new(true, Direction::Bidirectional),
+        ) {
+            (Ok(out_ring), Ok(in_ring)) => {
+                self.out_ring = out_ring;
+                self.in_ring = in_ring;
+                self.init_endpoints();
+            }
+            _ => {
+                warn!("DbC: no memory to reset transfer rings");
+                self.disable();
+            }
+        }
+    }
+}
+
+impl Drop for Dbc {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+impl fmt::Write for Dbc {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// 编码 USB 字符串描述符，返回描述符长度
+fn string_descriptor(s: &str, out: &mut [u8]) -> u8 {
+    let mut len = 2;
+    for c in s.encode_utf16() {
+        if len + 2 > out.len() {
+            break;
+        }
+        out[len..len + 2].copy_from_slice(&c.to_le_bytes());
+        len += 2;
+    }
+    out[0] = len as u8;
+    out[1] = DESCRIPTOR_TYPE_STRING;
+    len as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use core::ptr;
+
+    use super::*;
+    use crate::host::xhci::MemMapper;
+
+    const DCDB: usize = 0x04 / 4;
+    const DCCTRL: usize = 0x20 / 4;
+    const DCPORTSC: usize = 0x28 / 4;
+
+    const DCCTRL_RUN: u32 = 1 << 0;
+    const DCCTRL_HOT: u32 = 1 << 2;
+    const DCCTRL_HIT: u32 = 1 << 3;
+    /// CCS | PED
+    const PORTSC_CONNECTED: u32 = 0b11;
+    /// 测试前写入门铃寄存器，用于判断之后是否敲过门铃
+    const DOORBELL_NONE: u8 = 0xFF;
+
+    /// 以普通内存模拟 DbC 寄存器
+    #[repr(C, align(64))]
+    struct FakeRegs([u32; 16]);
+
+    struct Fixture {
+        regs: *mut u32,
+        dbc: Option<Dbc>,
+    }
+
+    impl Fixture {
+        /// 已完成枚举、处于 Configured 的 DbC
+        fn configured() -> Self {
+            let regs = Box::into_raw(Box::new(FakeRegs([0; 16]))).cast::<u32>();
+            let debug = unsafe { Debug::new(regs as usize, &MemMapper) };
+            let mut dbc = Dbc::new(debug, &DbcConfig::default()).unwrap();
+            dbc.state = DbcState::Configured;
+            let mut fx = Self {
+                regs,
+                dbc: Some(dbc),
+            };
+            fx.set(DCPORTSC, PORTSC_CONNECTED);
+            fx.set(DCCTRL, DCCTRL_RUN);
+            fx
+        }
+
+        fn dbc(&mut self) -> &mut Dbc {
+            self.dbc.as_mut().unwrap()
+        }
+
+        fn set(&mut self, reg: usize, value: u32) {
+            unsafe { ptr::write_volatile(self.regs.add(reg), value) }
+        }
+
+        fn doorbell(&self) -> u8 {
+            (unsafe { ptr::read_volatile(self.regs.add(DCDB)) } >> 8) as u8
+        }
+
+        fn clear_doorbell(&mut self) {
+            self.set(DCDB, (DOORBELL_NONE as u32) << 8);
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            // `Dbc`析构时仍会写寄存器
+            drop(self.dbc.take());
+            drop(unsafe { Box::from_raw(self.regs.cast::<FakeRegs>()) });
+        }
+    }
+
+    #[test]
+    fn stall_holds_until_halt_cleared() {
+        let mut fx = Fixture::configured();
+        fx.set(DCCTRL, DCCTRL_RUN | DCCTRL_HOT);
+        assert_eq!(fx.dbc().poll(), DbcState::Stalled);
+
+        // halt 期间不发送
+        fx.dbc().write(b"hello");
+        assert!(fx.dbc().out_pending.is_empty());
+
+        fx.set(DCCTRL, DCCTRL_RUN | DCCTRL_HIT);
+        assert_eq!(fx.dbc().poll(), DbcState::Stalled);
+        // 调试主机未运行时保持 Stalled
+        fx.set(DCCTRL, 0);
+        assert_eq!(fx.dbc().poll(), DbcState::Stalled);
+    }
+
+    #[test]
+    fn stall_cleared_rearms_in() {
+        let mut fx = Fixture::configured();
+        fx.set(DCCTRL, DCCTRL_RUN | DCCTRL_HIT);
+        assert_eq!(fx.dbc().poll(), DbcState::Stalled);
+
+        fx.clear_doorbell();
+        fx.set(DCCTRL, DCCTRL_RUN);
+        assert_eq!(fx.dbc().poll(), DbcState::Configured);
+        assert_eq!(fx.doorbell(), DOORBELL_IN);
+    }
+
+    #[test]
+    fn stall_cleared_flushes_tx() {
+        let mut fx = Fixture::configured();
+        fx.set(DCCTRL, DCCTRL_RUN | DCCTRL_HOT);
+        assert_eq!(fx.dbc().poll(), DbcState::Stalled);
+        fx.dbc().write(b"hello");
+
+        fx.clear_doorbell();
+        fx.set(DCCTRL, DCCTRL_RUN);
+        assert_eq!(fx.dbc().poll(), DbcState::Configured);
+        assert!(fx.dbc().tx.is_empty());
+        assert_eq!(fx.dbc().out_pending.len(), 1);
+        assert_eq!(fx.doorbell(), DOORBELL_OUT);
+    }
+}
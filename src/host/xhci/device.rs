@@ -0,0 +1,395 @@
+use alloc::vec::Vec;
+use log::debug;
+use xhci::ring::trb::{command, event::CompletionCode};
+
+use super::{
+    Data, Xhci,
+    completion::{Cancel, RingKey},
+    context::DeviceContext,
+    endpoint::EndpointConfig,
+    enumerate::DeviceState,
+    transfer::DCI_EP0,
+};
+use crate::{DeviceHandle, HostEvent, Speed, err::*};
+
+/// Route String 最多 5 层 hub
+const MAX_HUB_TIERS: u32 = 5;
+
+/// Evaluate Context 可更新的字段，`None`表示保持不变
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ContextUpdate {
+    pub ep0_max_packet_size: Option<u16>,
+    pub max_exit_latency: Option<u16>,
+    pub interrupter_target: Option<u16>,
+}
+
+/// hub 驱动读取 hub 描述符后提供的信息
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HubInfo {
+    pub ports: u8,
+    /// 高速 hub 已通过 SET_INTERFACE 启用 Multi-TT
+    pub multi_tt: bool,
+    /// wHubCharacteristics 中的 TT Think Time，取值 0..=3
+    pub tt_think_time: u8,
+}
+
+/// LS/FS 设备经由高速 hub 时使用的 Transaction Translator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tt {
+    pub hub_slot_id: u8,
+    pub port: u8,
+    pub multi_tt: bool,
+}
+
+/// 设备在 hub 树中的位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub root_port: u8,
+    pub route_string: u32,
+    pub speed: Speed,
+    pub tt: Option<Tt>,
+}
+
+impl Route {
+    pub(super) fn root(port_id: u8, speed: Speed) -> Self {
+        Self {
+            root_port: port_id,
+            route_string: 0,
+            speed,
+            tt: None,
+        }
+    }
+
+    fn tiers(&self) -> u32 {
+        (32 - self.route_string.leading_zeros()).div_ceil(4)
+    }
+
+    /// 是否位于`hub`之下
+    pub fn is_below(&self, hub: &Route) -> bool {
+        let tiers = hub.tiers();
+        self.root_port == hub.root_port
+            && self.tiers() > tiers
+            && self.route_string & ((1 << (tiers * 4)) - 1) == hub.route_string
+    }
+
+    /// 挂在本设备（hub）`port`上的子设备
+    fn child(&self, hub_slot_id: u8, hub: &HubInfo, port: u8, speed: Speed) -> Result<Self> {
+        let tier = self.tiers();
+        if tier >= MAX_HUB_TIERS || port == 0 || port > hub.ports {
+            return Err(USBError::NotSupported);
+        }
+
+        let tt = match (self.speed, speed) {
+            (Speed::High, Speed::Low | Speed::Full) => Some(Tt {
+                hub_slot_id,
+                port,
+                multi_tt: hub.multi_tt,
+            }),
+            _ => self.tt,
+        };
+
+        Ok(Self {
+            root_port: self.root_port,
+            route_string: self.route_string | ((port.min(15) as u32) << (tier * 4)),
+            speed,
+            tt,
+        })
+    }
+}
+
+impl Data {
+    pub(super) fn device(&mut self, dev: DeviceHandle) -> Result<&mut DeviceContext> {
+        self.dev_list
+            .by_slot_mut(dev.slot_id())
+            .filter(|c| c.port_id == dev.port_id() && !c.gone)
+            .ok_or(USBError::Disconnected)
+    }
+}
+
+impl Xhci {
+    /// hub 驱动复位下游端口后调用，为其上的设备分配 slot 并完成寻址
+    pub async fn attach_hub_port(
+        &self,
+        hub: DeviceHandle,
+        port: u8,
+        speed: Speed,
+    ) -> Result<DeviceHandle> {
+        let route = self.with_device(hub, |ctx| {
+            let info = ctx.hub.ok_or(USBError::NotSupported)?;
+            ctx.route.child(hub.slot_id(), &info, port, speed)
+        })?;
+
+        let dev = self.enumerate(route, 0).await?;
+        self.events.push(HostEvent::Attached(dev));
+        Ok(dev)
+    }
+
+    /// 在不跨越 await 的短临界区内访问未断开设备的上下文
+    pub(super) fn with_device<R>(
+        &self,
+        dev: DeviceHandle,
+        f: impl FnOnce(&mut DeviceContext) -> Result<R>,
+    ) -> Result<R> {
+        self.with_data(|data| f(data.device(dev)?))
+    }
+
+    pub(super) async fn disable_slot(&self, slot_id: u8) -> Result {
+        let mut cmd = command::DisableSlot::new();
+        cmd.set_slot_id(slot_id);
+        self.post_cmd(command::Allowed::DisableSlot(cmd)).await?;
+
+        self.with_data(|data| {
+            if let Some(ctx) = data.dev_list.remove(slot_id) {
+                for &dci in ctx.transfer_rings.keys() {
+                    self.completions.unbind(RingKey::transfer(slot_id, dci));
+                }
+            }
+            Ok(())
+        })?;
+        debug!("Slot {} disabled", slot_id);
+        Ok(())
+    }
+
+    /// 让设备及其下游设备立即失效，等待中的传输以`Disconnected`结束
+    pub(super) fn mark_gone(&self, data: &mut Data, pred: impl Fn(&Route) -> bool) {
+        for ctx in data
+            .dev_list
+            .device_context_list
+            .iter_mut()
+            .filter(|c| !c.gone && pred(&c.route))
+        {
+            ctx.gone = true;
+            for &dci in ctx.transfer_rings.keys() {
+                self.completions
+                    .fail_ring(RingKey::transfer(ctx.slot_id, dci), Cancel::Disconnected);
+            }
+            debug!("Slot {} gone", ctx.slot_id);
+            self.events.push(HostEvent::Detached(DeviceHandle::new(
+                ctx.slot_id,
+                ctx.port_id,
+            )));
+        }
+    }
+
+    /// 为已断开的设备执行 Disable Slot，释放上下文与传输环
+    pub(super) async fn remove_gone(&self) -> Result {
+        let gone: Vec<u8> = self.with_data(|data| {
+            Ok(data
+                .dev_list
+                .device_context_list
+                .iter()
+                .filter(|c| c.gone)
+                .map(|c| c.slot_id)
+                .collect())
+        })?;
+
+        for slot_id in gone {
+            self.disable_slot(slot_id).await?;
+        }
+        Ok(())
+    }
+
+    /// hub 驱动发现下游设备断开时调用，挂在其下的设备一并移除
+    pub async fn detach(&self, dev: DeviceHandle) -> Result {
+        self.with_data(|data| {
+            let route = data.device(dev)?.route;
+            self.mark_gone(data, |r| r == &route || r.is_below(&route));
+            Ok(())
+        })?;
+        self.remove_gone().await
+    }
+
+    /// 复位设备并以同一 slot 重新寻址，句柄保持有效，之前选择的配置会重新下发。
+    /// hub 下的设备需由 hub 驱动先复位其所在端口
+    pub async fn reset_device(&self, dev: DeviceHandle) -> Result {
+        let slot_id = dev.slot_id();
+        let (route, configuration) = self.with_device(dev, |ctx| {
+            for &dci in ctx.transfer_rings.keys() {
+                self.completions
+                    .fail_ring(RingKey::transfer(slot_id, dci), Cancel::DeviceReset);
+            }
+            ctx.state = DeviceState::Addressed;
+            Ok((ctx.route, ctx.configuration.take()))
+        })?;
+
+        if route.route_string == 0 {
+            self.reset_port(route.root_port, true).await?;
+        }
+
+        let mut cmd = command::ResetDevice::new();
+        cmd.set_slot_id(slot_id);
+        self.post_cmd(command::Allowed::ResetDevice(cmd)).await?;
+        debug!("Slot {} reset", slot_id);
+
+        let endpoints = {
+            let _guard = self.ctx_lock.lock().await;
+
+            // 除 EP0 外的端点已被禁用，EP0 从当前入队位置继续
+            let (endpoints, input) = self.with_data(|data| {
+                let ctx = data
+                    .dev_list
+                    .by_slot_mut(slot_id)
+                    .ok_or(USBError::Disconnected)?;
+                for &dci in ctx.transfer_rings.split_off(&(DCI_EP0 + 1)).keys() {
+                    self.completions.unbind(RingKey::transfer(slot_id, dci));
+                }
+                let endpoints = core::mem::take(&mut ctx.endpoints);
+                Ok((endpoints, ep0_address_input(ctx)?))
+            })?;
+
+            let mut cmd = command::AddressDevice::new();
+            cmd.set_input_context_pointer(input).set_slot_id(slot_id);
+            self.post_cmd(command::Allowed::AddressDevice(cmd)).await?;
+            debug!("Slot {} re-addressed", slot_id);
+            endpoints
+        };
+
+        let endpoints: Vec<EndpointConfig> = endpoints.into_values().collect();
+        match configuration {
+            Some(value) => self.select_configuration(dev, value, &endpoints).await,
+            None if !endpoints.is_empty() => self.configure_endpoints(dev, &endpoints, &[]).await,
+            None => Ok(()),
+        }
+    }
+
+    /// 在不重新配置设备的情况下更新 EP0 最大包长及 slot 字段
+    pub async fn evaluate_context(&self, dev: DeviceHandle, update: ContextUpdate) -> Result {
+        let _guard = self.ctx_lock.lock().await;
+        let input = self.with_device(dev, |ctx| {
+            ctx.input.modify(|input| {
+                clear_context_flags(input);
+
+                if update.max_exit_latency.is_some() || update.interrupter_target.is_some() {
+                    input.control_mut().set_add_context_flag(0);
+                    let slot = input.device_mut().slot_mut();
+                    if let Some(v) = update.max_exit_latency {
+                        slot.set_max_exit_latency(v);
+                    }
+                    if let Some(v) = update.interrupter_target {
+                        slot.set_interrupter_target(v);
+                    }
+                }
+
+                if let Some(mps) = update.ep0_max_packet_size {
+                    input.control_mut().set_add_context_flag(DCI_EP0 as _);
+                    input
+                        .device_mut()
+                        .endpoint_mut(DCI_EP0 as _)
+                        .set_max_packet_size(mps);
+                }
+            });
+            Ok(ctx.input.bus_addr())
+        })?;
+
+        let mut cmd = command::EvaluateContext::new();
+        cmd.set_input_context_pointer(input)
+            .set_slot_id(dev.slot_id());
+
+        self.post_cmd(command::Allowed::EvaluateContext(cmd))
+            .await?;
+        if let Some(mps) = update.ep0_max_packet_size {
+            self.with_device(dev, |ctx| {
+                ctx.ep0_max_packet_size = mps;
+                Ok(())
+            })?;
+        }
+
+        debug!("Slot {} context evaluated: {:?}", dev.slot_id(), update);
+        Ok(())
+    }
+
+    /// 设备完成 SET_CONFIGURATION 后由 hub 驱动调用，将 slot 标记为 hub
+    pub async fn set_hub(&self, dev: DeviceHandle, info: HubInfo) -> Result {
+        let _guard = self.ctx_lock.lock().await;
+        self.with_device(dev, |ctx| {
+            let high_speed = ctx.route.speed == Speed::High;
+            ctx.input.modify(|input| {
+                clear_context_flags(input);
+                input.control_mut().set_add_context_flag(0);
+
+                let slot = input.device_mut().slot_mut();
+                slot.set_hub();
+                slot.set_number_of_ports(info.ports);
+                if high_speed {
+                    slot.set_tt_think_time(info.tt_think_time);
+                    if info.multi_tt {
+                        slot.set_multi_tt();
+                    } else {
+                        slot.clear_multi_tt();
+                    }
+                }
+            });
+            Ok(())
+        })?;
+
+        self.configure_endpoint(dev).await?;
+        // 控制器接受后才记录，失败时下游端口仍不可用
+        self.with_device(dev, |ctx| {
+            ctx.hub = Some(info);
+            Ok(())
+        })?;
+
+        debug!("Slot {} is hub: {:?}", dev.slot_id(), info);
+        Ok(())
+    }
+
+    /// 按输入上下文中当前的 Add/Drop 标志执行 Configure Endpoint，调用方须持有`ctx_lock`，
+    /// 带宽或控制器资源不足时返回`Bandwidth`/`NoResources`
+    pub(super) async fn configure_endpoint(&self, dev: DeviceHandle) -> Result {
+        let input = self.with_device(dev, |ctx| Ok(ctx.input.bus_addr()))?;
+
+        let mut cmd = command::ConfigureEndpoint::new();
+        cmd.set_input_context_pointer(input)
+            .set_slot_id(dev.slot_id());
+
+        match self
+            .post_cmd(command::Allowed::ConfigureEndpoint(cmd))
+            .await
+        {
+            Err(USBError::CommandFailed(
+                CompletionCode::BandwidthError | CompletionCode::SecondaryBandwidthError,
+            )) => Err(USBError::Bandwidth),
+            Err(USBError::CommandFailed(CompletionCode::ResourceError)) => {
+                Err(USBError::NoResources)
+            }
+            res => res.map(|_| ()),
+        }
+    }
+}
+
+/// 供 Address Device 使用的输入上下文：只添加 slot 与 EP0，EP0 从当前入队位置继续
+pub(super) fn ep0_address_input(ctx: &mut DeviceContext) -> Result<u64> {
+    let ep0 = ctx
+        .transfer_rings
+        .get_mut(&DCI_EP0)
+        .ok_or(USBError::NotSupported)?;
+    ep0.discard();
+    let deq = ep0.current_trb_addr();
+    let cycle = ep0.cycle;
+
+    ctx.input.modify(|input| {
+        clear_context_flags(input);
+        input.control_mut().set_add_context_flag(0);
+        input.control_mut().set_add_context_flag(1);
+
+        input.device_mut().slot_mut().set_context_entries(1);
+        let ep0 = input.device_mut().endpoint_mut(1);
+        ep0.set_tr_dequeue_pointer(deq);
+        if cycle {
+            ep0.set_dequeue_cycle_state();
+        } else {
+            ep0.clear_dequeue_cycle_state();
+        }
+    });
+    Ok(ctx.input.bus_addr())
+}
+
+pub(super) fn clear_context_flags(input: &mut dyn xhci::context::InputHandler) {
+    let control = input.control_mut();
+    for i in 0..32 {
+        control.clear_add_context_flag(i);
+    }
+    for i in 2..32 {
+        control.clear_drop_context_flag(i);
+    }
+}
@@ -0,0 +1,284 @@
+use core::ops::Deref;
+
+use alloc::vec::Vec;
+use dma_api::{DVec, Direction};
+use log::debug;
+use xhci::{context::EndpointType, ring::trb::command};
+
+use super::{Data, Xhci, completion::RingKey, device::clear_context_flags, transfer::DCI_EP0};
+use crate::{DeviceHandle, HostEvent, Speed, err::*};
+
+/// 端点传输类型，对应 bmAttributes 低 2 位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+/// 端点配置，字段取自端点描述符及 SuperSpeed 端点伴随描述符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointConfig {
+    /// bEndpointAddress
+    pub address: u8,
+    pub kind: TransferKind,
+    /// wMaxPacketSize，高速周期端点的 bit 11..=12 为每微帧额外事务数
+    pub max_packet_size: u16,
+    /// bInterval
+    pub interval: u8,
+    /// 伴随描述符的 bMaxBurst
+    pub max_burst: u8,
+    /// 伴随描述符 bmAttributes 中的 Mult，仅用于 SuperSpeed 等时端点
+    pub mult: u8,
+    /// 伴随描述符的 wBytesPerInterval，为 0 时按包长计算
+    pub max_esit_payload: u32,
+}
+
+impl EndpointConfig {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    /// Device Context Index
+    pub fn dci(&self) -> u8 {
+        let num = self.address & 0x0F;
+        if self.kind == TransferKind::Control || self.is_in() {
+            num * 2 + 1
+        } else {
+            num * 2
+        }
+    }
+
+    fn is_periodic(&self) -> bool {
+        matches!(
+            self.kind,
+            TransferKind::Isochronous | TransferKind::Interrupt
+        )
+    }
+
+    fn endpoint_type(&self) -> EndpointType {
+        match (self.kind, self.is_in()) {
+            (TransferKind::Control, _) => EndpointType::Control,
+            (TransferKind::Isochronous, false) => EndpointType::IsochOut,
+            (TransferKind::Isochronous, true) => EndpointType::IsochIn,
+            (TransferKind::Bulk, false) => EndpointType::BulkOut,
+            (TransferKind::Bulk, true) => EndpointType::BulkIn,
+            (TransferKind::Interrupt, false) => EndpointType::InterruptOut,
+            (TransferKind::Interrupt, true) => EndpointType::InterruptIn,
+        }
+    }
+
+    /// 转换为 xHCI 的 Interval：2^n × 125us
+    fn xhci_interval(&self, speed: Speed) -> u8 {
+        match (self.kind, speed) {
+            (TransferKind::Control | TransferKind::Bulk, _) => 0,
+            // 低速/全速中断端点的 bInterval 以帧（1ms）为单位
+            (TransferKind::Interrupt, Speed::Low | Speed::Full) => {
+                let uframes = self.interval.max(1) as u32 * 8;
+                (31 - uframes.leading_zeros()).clamp(3, 10) as u8
+            }
+            (TransferKind::Isochronous, Speed::Full) => self.interval.clamp(1, 16) + 2,
+            _ => self.interval.clamp(1, 16) - 1,
+        }
+    }
+
+    fn max_burst(&self, speed: Speed) -> u8 {
+        match speed {
+            Speed::Super | Speed::SuperPlus => self.max_burst,
+            Speed::High if self.is_periodic() => ((self.max_packet_size >> 11) & 0x3) as u8,
+            _ => 0,
+        }
+    }
+
+    fn mult(&self, speed: Speed) -> u8 {
+        match speed {
+            Speed::Super | Speed::SuperPlus if self.kind == TransferKind::Isochronous => {
+                self.mult & 0x3
+            }
+            _ => 0,
+        }
+    }
+
+    fn max_esit_payload(&self, speed: Speed) -> u32 {
+        if !self.is_periodic() {
+            return 0;
+        }
+        if self.max_esit_payload != 0 {
+            return self.max_esit_payload;
+        }
+        let mps = (self.max_packet_size & 0x7FF) as u32;
+        mps * (self.max_burst(speed) as u32 + 1) * (self.mult(speed) as u32 + 1)
+    }
+}
+
+impl Xhci {
+    /// 添加`add`中的端点并移除地址在`drop`中的端点，切换备用设置时两者同时给出。
+    /// 带宽不足时返回`Bandwidth`，已有配置保持不变，可改选其他备用设置重试
+    pub async fn configure_endpoints(
+        &self,
+        dev: DeviceHandle,
+        add: &[EndpointConfig],
+        drop: &[u8],
+    ) -> Result {
+        if add.iter().any(|ep| ep.dci() <= DCI_EP0 || ep.dci() > 31) {
+            return Err(USBError::NotSupported);
+        }
+
+        let _guard = self.ctx_lock.lock().await;
+        let (rings, drop) = self.with_data(|data| {
+            data.device(dev)?;
+            let mut rings = Vec::with_capacity(add.len());
+            for ep in add {
+                rings.push((ep.dci(), data.dev_list.new_transfer_ring()?));
+            }
+
+            let ctx = data.device(dev)?;
+            let speed = ctx.route.speed;
+
+            let drop: Vec<u8> = ctx
+                .endpoints
+                .values()
+                .filter(|ep| drop.contains(&ep.address))
+                .map(|ep| ep.dci())
+                .collect();
+
+            // 已启用的端点须同时置 Drop 与 Add 才能替换，否则命令以 Parameter Error 失败
+            let replaced: Vec<u8> = add
+                .iter()
+                .map(|ep| ep.dci())
+                .filter(|dci| ctx.transfer_rings.contains_key(dci) && !drop.contains(dci))
+                .collect();
+
+            let context_entries = ctx
+                .transfer_rings
+                .keys()
+                .copied()
+                .filter(|dci| !drop.contains(dci))
+                .chain(add.iter().map(|ep| ep.dci()))
+                .max()
+                .unwrap_or(DCI_EP0);
+
+            ctx.input.modify(|input| {
+                clear_context_flags(input);
+                input.control_mut().set_add_context_flag(0);
+                for &dci in drop.iter().chain(&replaced) {
+                    input.control_mut().set_drop_context_flag(dci as _);
+                }
+
+                input
+                    .device_mut()
+                    .slot_mut()
+                    .set_context_entries(context_entries);
+
+                for (ep, (_, ring)) in add.iter().zip(&rings) {
+                    let dci = ep.dci();
+                    input.control_mut().set_add_context_flag(dci as _);
+
+                    let esit = ep.max_esit_payload(speed);
+                    let ctx = input.device_mut().endpoint_mut(dci as _);
+                    ctx.set_endpoint_type(ep.endpoint_type());
+                    ctx.set_max_packet_size(ep.max_packet_size & 0x7FF);
+                    ctx.set_max_burst_size(ep.max_burst(speed));
+                    ctx.set_mult(ep.mult(speed));
+                    ctx.set_interval(ep.xhci_interval(speed));
+                    ctx.set_error_count(if ep.kind == TransferKind::Isochronous {
+                        0
+                    } else {
+                        3
+                    });
+                    ctx.set_tr_dequeue_pointer(ring.bus_addr());
+                    if ring.cycle {
+                        ctx.set_dequeue_cycle_state();
+                    } else {
+                        ctx.clear_dequeue_cycle_state();
+                    }
+                    ctx.set_average_trb_length(match ep.kind {
+                        TransferKind::Control => 8,
+                        TransferKind::Interrupt => 1024,
+                        _ => 3072,
+                    });
+                    ctx.set_max_endpoint_service_time_interval_payload_low(esit as u16);
+                    ctx.set_max_endpoint_service_time_interval_payload_high((esit >> 16) as u8);
+                }
+            });
+            Ok((rings, drop))
+        })?;
+
+        self.configure_endpoint(dev).await?;
+
+        self.with_data(|data| {
+            let ctx = data
+                .dev_list
+                .by_slot_mut(dev.slot_id())
+                .ok_or(USBError::Disconnected)?;
+            for dci in drop {
+                if ctx.transfer_rings.remove(&dci).is_some() {
+                    self.completions
+                        .unbind(RingKey::transfer(dev.slot_id(), dci));
+                }
+                ctx.endpoints.remove(&dci);
+            }
+            for (ep, (dci, ring)) in add.iter().zip(rings) {
+                // 替换已启用的端点（已置 Drop）时，旧环上的等待者随重新绑定结束
+                self.completions
+                    .bind(RingKey::transfer(dev.slot_id(), dci), &ring)?;
+                ctx.transfer_rings.insert(dci, ring);
+                ctx.endpoints.insert(dci, *ep);
+            }
+
+            debug!(
+                "Slot {} endpoints configured: {:?}",
+                dev.slot_id(),
+                ctx.endpoints.keys()
+            );
+            Ok(())
+        })
+    }
+
+    /// 查询根端口（`hub`为`None`）或 hub 各下游端口上`speed`设备可用的带宽百分比，
+    /// 下标 0 对应端口 1
+    pub async fn port_bandwidth(&self, hub: Option<DeviceHandle>, speed: Speed) -> Result<Vec<u8>> {
+        let (ports, hub_slot_id) = match hub {
+            Some(hub) => {
+                let info = self.with_device(hub, |ctx| ctx.hub.ok_or(USBError::NotSupported))?;
+                (info.ports as usize, hub.slot_id())
+            }
+            None => (self.port_count(), 0),
+        };
+
+        // 第 0 字节保留
+        let ctx =
+            DVec::<u8>::zeros(ports + 1, 64, Direction::FromDevice).ok_or(USBError::NoMemory)?;
+
+        let mut cmd = command::GetPortBandwidth::new();
+        cmd.set_port_bandwidth_context_pointer(ctx.bus_addr())
+            .set_dev_speed(speed as u8)
+            .set_hub_slot_id(hub_slot_id);
+        self.post_cmd(command::Allowed::GetPortBandwidth(cmd))
+            .await?;
+
+        Ok(ctx.deref()[1..].to_vec())
+    }
+
+    /// 控制器建议该设备重新协商带宽，中断中收到的请求推迟到任务中处理
+    pub(super) fn bandwidth_request(&self, data: &mut Data, slot_id: u8) {
+        let Some(ctx) = data.dev_list.by_slot_mut(slot_id) else {
+            return;
+        };
+        let dev = DeviceHandle::new(slot_id, ctx.port_id);
+        debug!("Slot {} bandwidth request", slot_id);
+        self.events.push(HostEvent::BandwidthRequest(dev));
+    }
+
+    /// 请求控制器重新评估周期端点带宽，完成后可能收到`BandwidthRequest`事件
+    pub async fn negotiate_bandwidth(&self, dev: DeviceHandle) -> Result {
+        self.with_device(dev, |_| Ok(()))?;
+
+        let mut cmd = command::NegotiateBandwidth::new();
+        cmd.set_slot_id(dev.slot_id());
+        self.post_cmd(command::Allowed::NegotiateBandwidth(cmd))
+            .await?;
+        Ok(())
+    }
+}
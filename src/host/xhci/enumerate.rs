@@ -0,0 +1,460 @@
+use core::{sync::atomic::Ordering, time::Duration};
+
+use alloc::{vec, vec::Vec};
+use log::{debug, info, warn};
+use xhci::{
+    context::EndpointType,
+    ring::trb::{command, event::CompletionCode},
+};
+
+use super::{
+    Xhci,
+    completion::RingKey,
+    device::{ContextUpdate, Route, ep0_address_input},
+    endpoint::EndpointConfig,
+    transfer::DCI_EP0,
+};
+use crate::{
+    DeviceHandle, SetupPacket, Speed,
+    descriptor::{Configuration, ConfigurationDescriptor, DeviceDescriptor, TYPE_CONFIGURATION},
+    err::*,
+    sleep,
+};
+
+/// 根端口上复位并重新分配 slot 的最多次数，同 Linux PORT_INIT_TRIES
+const PORT_INIT_TRIES: usize = 4;
+/// 单个 GET_DESCRIPTOR 出错时的最多尝试次数
+const GET_DESCRIPTOR_TRIES: usize = 2;
+/// 两次枚举尝试之间的等待
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// 同 Linux USB_MAXCONFIG，超出的配置不读取
+const MAX_CONFIGS: u8 = 8;
+const CLASS_VENDOR_SPECIFIC: u8 = 0xFF;
+
+/// 设备状态，见 USB 2.0 9.1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// 已分配 slot，以默认地址 0 通信（BSR=1 寻址后）
+    Default,
+    Addressed,
+    Configured,
+}
+
+/// PORTSC 中的默认 Protocol Speed ID
+fn speed_from_psi(psi: u8) -> Result<Speed> {
+    Ok(match psi {
+        1 => Speed::Full,
+        2 => Speed::Low,
+        3 => Speed::High,
+        4 => Speed::Super,
+        5 => Speed::SuperPlus,
+        _ => return Err(USBError::NotSupported),
+    })
+}
+
+/// 读取设备描述符之前 EP0 使用的最大包长。同 Linux，全速设备按 64 处理，
+/// bMaxPacketSize0 较小时首个包按短包结束，仍能读到描述符的前 8 字节
+fn default_max_packet_size(speed: Speed) -> u16 {
+    match speed {
+        Speed::Low => 8,
+        Speed::Full | Speed::High => 64,
+        Speed::Super | Speed::SuperPlus => 512,
+    }
+}
+
+/// 校验 bMaxPacketSize0：SuperSpeed 固定为 512，低速只能为 8，全速/高速为 8/16/32/64
+fn ep0_max_packet_size(speed: Speed, raw: u8) -> Option<u16> {
+    match (speed, raw) {
+        (Speed::Super | Speed::SuperPlus, _) => Some(512),
+        (Speed::Low, 8) => Some(8),
+        (Speed::Full | Speed::High, 8 | 16 | 32 | 64) => Some(raw as u16),
+        _ => None,
+    }
+}
+
+/// 参照 Linux `usb_choose_configuration`：优先第一个接口不是厂商自定义类的配置，
+/// 都不满足时取第一个
+fn choose_configuration(configs: &[Vec<u8>]) -> Option<usize> {
+    let first_class = |raw: &Vec<u8>| {
+        let config = Configuration::parse(raw).ok()?;
+        let alt = config.interfaces.first()?.alt_settings.first()?;
+        Some(alt.descriptor.class())
+    };
+    configs
+        .iter()
+        .position(|raw| first_class(raw).is_some_and(|c| c != CLASS_VENDOR_SPECIFIC))
+        .or((!configs.is_empty()).then_some(0))
+}
+
+impl Xhci {
+    /// 为 true 时全速/低速/高速设备按 Linux 的 new scheme 枚举：先以 BSR=1 寻址并读取
+    /// EP0 最大包长，再复位端口后正式寻址；前两次尝试失败后退回直接寻址。只对根端口生效
+    pub fn set_bsr_first(&self, enable: bool) {
+        self.bsr_first.store(enable, Ordering::Relaxed);
+    }
+
+    pub fn device_state(&self, dev: DeviceHandle) -> Result<DeviceState> {
+        self.with_device(dev, |ctx| Ok(ctx.state))
+    }
+
+    /// 枚举时读取的设备描述符，可用`DeviceDescriptor::parse`解析
+    pub fn device_descriptor(&self, dev: DeviceHandle) -> Result<Vec<u8>> {
+        self.with_device(dev, |ctx| Ok(ctx.descriptor.clone()))
+    }
+
+    /// 枚举时读取的第`index`个完整配置描述符，可用`Configuration::parse`解析
+    pub fn config_descriptor(&self, dev: DeviceHandle, index: u8) -> Result<Vec<u8>> {
+        self.with_device(dev, |ctx| {
+            ctx.configs
+                .get(index as usize)
+                .cloned()
+                .ok_or(USBError::NotSupported)
+        })
+    }
+
+    /// 当前的 bConfigurationValue，未配置时为`None`
+    pub fn active_configuration(&self, dev: DeviceHandle) -> Result<Option<u8>> {
+        self.with_device(dev, |ctx| Ok(ctx.configuration))
+    }
+
+    /// 根端口上新连接的设备：消抖后复位端口并枚举，失败时重新复位重试
+    pub(super) async fn attach(&self, port_id: u8) -> Result<DeviceHandle> {
+        self.debounce(port_id).await?;
+
+        let mut last = USBError::Unknown;
+        for attempt in 0..PORT_INIT_TRIES {
+            if attempt > 0 {
+                sleep(RETRY_DELAY).await;
+                if !self.portsc(port_id).current_connect_status() {
+                    return Err(USBError::Disconnected);
+                }
+            }
+
+            // 首次尝试时已使能的 USB3 端口无需复位
+            let res = match self.reset_port(port_id, attempt > 0).await {
+                Ok(()) => match speed_from_psi(self.portsc(port_id).port_speed()) {
+                    Ok(speed) => self.enumerate(Route::root(port_id, speed), attempt).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(dev) => return Ok(dev),
+                Err(e) if !self.retryable(&e) => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Port {} enumeration attempt {} failed: {}",
+                        port_id,
+                        attempt + 1,
+                        e
+                    );
+                    last = e;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    fn retryable(&self, e: &USBError) -> bool {
+        self.error.get().is_none()
+            && !matches!(
+                e,
+                USBError::Disconnected
+                    | USBError::NotSupported
+                    | USBError::NoMemory
+                    | USBError::SlotLimitReached
+                    | USBError::CommandFailed(CompletionCode::NoSlotsAvailableError)
+            )
+    }
+
+    /// 分配 slot 并完成寻址、读取描述符及选择配置，出错时释放 slot。
+    /// `attempt`为根端口上的第几次尝试，决定是否先以 BSR=1 寻址
+    pub(super) async fn enumerate(&self, route: Route, attempt: usize) -> Result<DeviceHandle> {
+        let slot_id = self
+            .post_cmd(command::Allowed::EnableSlot(command::EnableSlot::new()))
+            .await?
+            .slot_id();
+        debug!("{:?} -> slot {}", route, slot_id);
+
+        let dev = DeviceHandle::new(slot_id, route.root_port);
+        if let Err(e) = self.init_device(dev, route, attempt).await {
+            if self.error.get().is_none() {
+                let _ = self.disable_slot(slot_id).await;
+            }
+            return Err(e);
+        }
+
+        Ok(dev)
+    }
+
+    async fn init_device(&self, dev: DeviceHandle, route: Route, attempt: usize) -> Result {
+        self.init_slot(dev.slot_id(), route)?;
+
+        let bsr = self.bsr_first.load(Ordering::Relaxed)
+            && route.route_string == 0
+            && matches!(route.speed, Speed::Low | Speed::Full | Speed::High)
+            && attempt < PORT_INIT_TRIES / 2;
+
+        if bsr {
+            // 部分设备只在请求 64 字节时正确回应第一次 GET_DESCRIPTOR
+            self.address_device(dev, true).await?;
+            self.update_ep0_max_packet_size(dev, route.speed, 64)
+                .await?;
+            self.reset_port(route.root_port, true).await?;
+            self.address_device(dev, false).await?;
+        } else {
+            self.address_device(dev, false).await?;
+            self.update_ep0_max_packet_size(dev, route.speed, 8).await?;
+        }
+
+        let raw = self.read_device_descriptor(dev).await?;
+        let desc = DeviceDescriptor::parse(&raw)?;
+        self.read_configs(dev, desc.num_configurations()).await?;
+        info!(
+            "Slot {}: {:04x}:{:04x} USB {:x}.{:02x}, {:?}",
+            dev.slot_id(),
+            desc.vendor_id(),
+            desc.product_id(),
+            desc.usb_version() >> 8,
+            desc.usb_version() & 0xFF,
+            route.speed
+        );
+
+        // 配置失败时设备保持 Addressed，交由类驱动处理
+        match self.set_default_configuration(dev).await {
+            Err(e @ USBError::Disconnected) => return Err(e),
+            Err(e) if self.error.get().is_some() => return Err(e),
+            Err(e) => warn!("Slot {} left unconfigured: {}", dev.slot_id(), e),
+            Ok(()) => {}
+        }
+        Ok(())
+    }
+
+    fn init_slot(&self, slot_id: u8, route: Route) -> Result {
+        self.with_data(|data| {
+            let ctx = data.dev_list.new_slot(slot_id as _, route, 1)?;
+            self.completions.bind(
+                RingKey::transfer(slot_id, DCI_EP0),
+                &ctx.transfer_rings[&DCI_EP0],
+            )?;
+            ctx.ep0_max_packet_size = default_max_packet_size(route.speed);
+
+            ctx.input.modify(|input| {
+                let slot = input.device_mut().slot_mut();
+                slot.set_route_string(route.route_string);
+                slot.set_speed(route.speed as u8);
+                slot.set_root_hub_port_number(route.root_port);
+                if let Some(tt) = route.tt {
+                    slot.set_parent_hub_slot_id(tt.hub_slot_id);
+                    slot.set_parent_port_number(tt.port);
+                    if tt.multi_tt {
+                        slot.set_multi_tt();
+                    }
+                }
+
+                let ep0 = input.device_mut().endpoint_mut(1);
+                ep0.set_endpoint_type(EndpointType::Control);
+                ep0.set_max_packet_size(default_max_packet_size(route.speed));
+                ep0.set_error_count(3);
+                ep0.set_average_trb_length(8);
+            });
+            Ok(())
+        })
+    }
+
+    /// `bsr`为 true 时控制器不发送 SET_ADDRESS，设备停留在 Default 状态
+    async fn address_device(&self, dev: DeviceHandle, bsr: bool) -> Result {
+        let _guard = self.ctx_lock.lock().await;
+        let input = self.with_device(dev, ep0_address_input)?;
+
+        let mut cmd = command::AddressDevice::new();
+        cmd.set_input_context_pointer(input)
+            .set_slot_id(dev.slot_id());
+        if bsr {
+            cmd.set_block_set_address_request();
+        }
+        self.post_cmd(command::Allowed::AddressDevice(cmd)).await?;
+
+        self.with_device(dev, |ctx| {
+            ctx.state = if bsr {
+                DeviceState::Default
+            } else {
+                DeviceState::Addressed
+            };
+            Ok(())
+        })?;
+        debug!("Slot {} addressed, BSR {}", dev.slot_id(), bsr);
+        Ok(())
+    }
+
+    /// 读取设备描述符前`len`字节得到 bMaxPacketSize0，与默认值不同时更新 EP0
+    async fn update_ep0_max_packet_size(
+        &self,
+        dev: DeviceHandle,
+        speed: Speed,
+        len: u16,
+    ) -> Result {
+        let mut head = vec![0u8; len as usize];
+        let setup = SetupPacket::get_device_descriptor(len);
+        let n = self.get_descriptor(dev, setup, &mut head).await?;
+        if n < 8 {
+            return Err(USBError::InvalidDescriptor);
+        }
+
+        let mps = ep0_max_packet_size(speed, head[7]).ok_or_else(|| {
+            warn!("Slot {} invalid ep0 maxpacket {}", dev.slot_id(), head[7]);
+            USBError::InvalidDescriptor
+        })?;
+        if mps != default_max_packet_size(speed) {
+            debug!("Slot {} EP0 max packet size {}", dev.slot_id(), mps);
+            self.evaluate_context(
+                dev,
+                ContextUpdate {
+                    ep0_max_packet_size: Some(mps),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// 读取完整的设备描述符并保存，返回原始字节
+    async fn read_device_descriptor(&self, dev: DeviceHandle) -> Result<Vec<u8>> {
+        let mut raw = vec![0u8; DeviceDescriptor::LENGTH];
+        let setup = SetupPacket::get_device_descriptor(raw.len() as u16);
+        let n = self.get_descriptor(dev, setup, &mut raw).await?;
+        raw.truncate(n);
+        DeviceDescriptor::parse(&raw)?;
+
+        self.with_device(dev, |ctx| {
+            ctx.descriptor = raw.clone();
+            Ok(())
+        })?;
+        Ok(raw)
+    }
+
+    /// 依次读取各配置的完整描述符：先读 9 字节头部得到 wTotalLength
+    async fn read_configs(&self, dev: DeviceHandle, count: u8) -> Result {
+        let mut configs = Vec::new();
+        for index in 0..count.min(MAX_CONFIGS) {
+            let mut head = [0u8; ConfigurationDescriptor::LENGTH];
+            let setup =
+                SetupPacket::get_descriptor(TYPE_CONFIGURATION, index, 0, head.len() as u16);
+            let n = self.get_descriptor(dev, setup, &mut head).await?;
+            let total = ConfigurationDescriptor::parse(&head[..n])?.total_length();
+
+            let mut raw = vec![0u8; total as usize];
+            let setup = SetupPacket::get_descriptor(TYPE_CONFIGURATION, index, 0, total);
+            let n = self.get_descriptor(dev, setup, &mut raw).await?;
+            raw.truncate(n);
+            Configuration::parse(&raw)?;
+            configs.push(raw);
+        }
+
+        self.with_device(dev, |ctx| {
+            ctx.configs = configs;
+            Ok(())
+        })
+    }
+
+    /// 配置选定配置中各接口备用设置 0 的端点，再发送 SET_CONFIGURATION
+    async fn set_default_configuration(&self, dev: DeviceHandle) -> Result {
+        let raw = self.with_device(dev, |ctx| {
+            Ok(choose_configuration(&ctx.configs).map(|i| ctx.configs[i].clone()))
+        })?;
+        let Some(raw) = raw else {
+            return Err(USBError::NotSupported);
+        };
+
+        let config = Configuration::parse(&raw)?;
+        let value = config.descriptor.configuration_value();
+        let endpoints: Vec<EndpointConfig> = config
+            .interfaces
+            .iter()
+            .filter_map(|i| i.alt_setting(0))
+            .flat_map(|alt| alt.endpoint_configs())
+            .collect();
+
+        self.select_configuration(dev, value, &endpoints).await
+    }
+
+    /// 先为`endpoints`执行 Configure Endpoint，再发送 SET_CONFIGURATION，
+    /// 后者失败时撤销已添加的端点
+    pub(super) async fn select_configuration(
+        &self,
+        dev: DeviceHandle,
+        value: u8,
+        endpoints: &[EndpointConfig],
+    ) -> Result {
+        if !endpoints.is_empty() {
+            self.configure_endpoints(dev, endpoints, &[]).await?;
+        }
+        if let Err(e) = self
+            .control_out(dev, SetupPacket::set_configuration(value), &[])
+            .await
+        {
+            if !endpoints.is_empty() {
+                let drop: Vec<u8> = endpoints.iter().map(|ep| ep.address).collect();
+                let _ = self.configure_endpoints(dev, &[], &drop).await;
+            }
+            return Err(e);
+        }
+
+        debug!("Slot {} configuration {} selected", dev.slot_id(), value);
+        Ok(())
+    }
+
+    /// STALL、传输错误或超时时重试，部分设备刚复位后的首次请求不可靠
+    async fn get_descriptor(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut tries = GET_DESCRIPTOR_TRIES;
+        loop {
+            tries -= 1;
+            match self.control_in(dev, setup, buf).await {
+                Err(e @ (USBError::TransferEventError(_) | USBError::Timeout)) if tries > 0 => {
+                    debug!(
+                        "Slot {} GET_DESCRIPTOR {:#06x} failed: {}, retrying",
+                        dev.slot_id(),
+                        setup.value,
+                        e
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_ep0_max_packet_size() {
+        assert_eq!(default_max_packet_size(Speed::Low), 8);
+        assert_eq!(default_max_packet_size(Speed::Full), 64);
+        assert_eq!(default_max_packet_size(Speed::High), 64);
+        assert_eq!(default_max_packet_size(Speed::Super), 512);
+        assert_eq!(default_max_packet_size(Speed::SuperPlus), 512);
+    }
+
+    #[test]
+    fn validate_ep0_max_packet_size() {
+        assert_eq!(ep0_max_packet_size(Speed::Low, 8), Some(8));
+        assert_eq!(ep0_max_packet_size(Speed::Low, 64), None);
+        for raw in [8, 16, 32, 64] {
+            assert_eq!(ep0_max_packet_size(Speed::Full, raw), Some(raw as u16));
+            assert_eq!(ep0_max_packet_size(Speed::High, raw), Some(raw as u16));
+        }
+        assert_eq!(ep0_max_packet_size(Speed::Full, 12), None);
+        assert_eq!(ep0_max_packet_size(Speed::High, 0), None);
+        // SuperSpeed 的 bMaxPacketSize0 为指数 9
+        assert_eq!(ep0_max_packet_size(Speed::Super, 9), Some(512));
+    }
+}
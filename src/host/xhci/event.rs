@@ -1,19 +1,12 @@
-use core::{
-    cell::UnsafeCell,
-    future::Future,
-    sync::atomic::{AtomicBool, Ordering, fence},
-    task::{Poll, Waker},
-};
+use core::sync::atomic::{Ordering, fence};
 
-use alloc::{collections::btree_map::BTreeMap, sync::Arc};
-use dma_api::DVec;
-use futures::{FutureExt, future::LocalBoxFuture, task::AtomicWaker};
-use log::{debug, trace};
-use spin::{mutex::Mutex, rwlock::RwLock};
-use xhci::ring::trb::event::{Allowed, CompletionCode};
+use xhci::ring::trb::event::Allowed;
 
-use super::ring::Ring;
-use crate::err::*;
+use super::{
+    mem::{MemPool, MemVec},
+    ring::{Ring, TrbData},
+};
+use crate::{err::*, page_size};
 
 #[repr(C)]
 pub struct EventRingSte {
@@ -24,75 +17,32 @@ pub struct EventRingSte {
 
 pub struct EventRing {
     pub ring: Ring,
-    pub ste: DVec<EventRingSte>,
-    cmd_results: UnsafeCell<BTreeMap<u64, ResultCell>>,
+    pub ste: MemVec<EventRingSte>,
 }
 
 unsafe impl Send for EventRing {}
 unsafe impl Sync for EventRing {}
 
 impl EventRing {
-    pub fn new(cmd_ring: &Ring) -> Result<Self> {
-        let ring = Ring::new(true, dma_api::Direction::Bidirectional)?;
+    pub fn new() -> Result<Self> {
+        Self::new_in(&MemPool::default())
+    }
 
-        let mut ste =
-            DVec::zeros(1, 64, dma_api::Direction::Bidirectional).ok_or(USBError::NoMemory)?;
+    pub fn new_in(pool: &MemPool) -> Result<Self> {
+        let len = page_size() / size_of::<TrbData>();
+        let ring = Ring::new_in(pool, len, true, dma_api::Direction::Bidirectional)?;
+
+        let mut ste = MemVec::zeros(pool, 1, 64, dma_api::Direction::Bidirectional)?;
 
         let ste0 = EventRingSte {
-            addr: ring.trbs.bus_addr(),
+            addr: ring.bus_addr(),
             size: ring.len() as _,
             _reserved: [0; 6],
         };
 
         ste.set(0, ste0);
 
-        let mut results = BTreeMap::new();
-
-        for i in 0..cmd_ring.len() {
-            let addr = cmd_ring.trb_bus_addr(i);
-            results.insert(addr, ResultCell::default());
-        }
-
-        Ok(Self {
-            ring,
-            ste,
-            cmd_results: UnsafeCell::new(results),
-        })
-    }
-
-    pub fn wait_result(&mut self, trb_addr: u64) -> LocalBoxFuture<'_, Allowed> {
-        EventWaiter {
-            trb_addr,
-            ring: self,
-        }
-        .boxed_local()
-    }
-
-    pub fn clean_events(&mut self) -> usize {
-        let mut count = 0;
-
-        while let Some((allowed, _cycle)) = self.next() {
-            match allowed {
-                Allowed::CommandCompletion(c) => {
-                    let addr = c.command_trb_pointer();
-                    trace!("[EVENT] << {:?} @{:X}", allowed, addr);
-
-                    if let Some(res) = unsafe { &mut *self.cmd_results.get() }.get_mut(&addr) {
-                        res.result.replace(allowed);
-
-                        if let Some(wake) = res.waker.take() {
-                            wake.wake();
-                        }
-                    }
-                }
-                _ => {
-                    debug!("unhandled event {:?}", allowed);
-                }
-            }
-            count += 1;
-        }
-
-        count
+        Ok(Self { ring, ste })
     }
 
     /// 完成一次循环返回 true
@@ -122,38 +72,3 @@ impl EventRing {
         self.ste.len()
     }
 }
-
-#[derive(Default)]
-struct ResultCell {
-    result: Option<Allowed>,
-    waker: AtomicWaker,
-}
-
-struct EventWaiter<'a> {
-    trb_addr: u64,
-    ring: &'a EventRing,
-}
-
-impl Future for EventWaiter<'_> {
-    type Output = Allowed;
-
-    fn poll(
-        self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> core::task::Poll<Self::Output> {
-        let addr = self.trb_addr;
-        let entry = {
-            unsafe { &mut *self.ring.cmd_results.get() }
-                .get_mut(&addr)
-                .unwrap()
-        };
-
-        match entry.result.take() {
-            Some(v) => Poll::Ready(v),
-            None => {
-                entry.waker.register(cx.waker());
-                Poll::Pending
-            }
-        }
-    }
-}
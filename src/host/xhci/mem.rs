@@ -0,0 +1,329 @@
+use core::{cell::Cell, num::NonZeroUsize, ops::Deref, ptr::NonNull};
+
+use alloc::{rc::Rc, sync::Arc, vec, vec::Vec};
+use dma_api::{DBox, DVec, Direction};
+use log::{debug, info};
+use spin::Mutex;
+use xhci::{
+    accessor::Mapper,
+    extended_capabilities::{ExtendedCapability, List, XhciLocalMemory},
+};
+
+use super::Xhci;
+use crate::{dma_coherent, err::*};
+
+/// 环段等结构不能跨越 64KiB 边界
+const BOUNDARY: usize = 0x10000;
+/// 分配粒度，保证按 u32 访问
+const GRANULE: usize = 64;
+
+/// 控制器片上内存的分配器，首次适配，释放时合并相邻空闲块
+struct LocalHeap {
+    virt: NonNull<u8>,
+    bus: u64,
+    /// 空闲块 (偏移, 长度)，按偏移排序
+    free: Vec<(usize, usize)>,
+}
+
+// 片上内存位于控制器 MMIO 空间，与具体线程无关
+unsafe impl Send for LocalHeap {}
+
+impl LocalHeap {
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let size = size.next_multiple_of(GRANULE);
+        let align = align.max(GRANULE);
+
+        for i in 0..self.free.len() {
+            let (off, len) = self.free[i];
+            let bus = self.bus as usize;
+            let mut start = (bus + off).next_multiple_of(align) - bus;
+            if size <= BOUNDARY && (bus + start) / BOUNDARY != (bus + start + size - 1) / BOUNDARY {
+                start = (bus + start).next_multiple_of(BOUNDARY) - bus;
+            }
+            if start + size > off + len {
+                continue;
+            }
+
+            self.free.remove(i);
+            if start + size < off + len {
+                self.free
+                    .insert(i, (start + size, off + len - start - size));
+            }
+            if start > off {
+                self.free.insert(i, (off, start - off));
+            }
+            return Some(start);
+        }
+        None
+    }
+
+    fn dealloc(&mut self, off: usize, size: usize) {
+        let size = size.next_multiple_of(GRANULE);
+        let i = self.free.partition_point(|&(o, _)| o < off);
+        self.free.insert(i, (off, size));
+
+        if i + 1 < self.free.len() && off + size == self.free[i + 1].0 {
+            self.free[i].1 += self.free.remove(i + 1).1;
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == off {
+            self.free[i - 1].1 += self.free.remove(i).1;
+        }
+    }
+}
+
+/// 数据结构的分配来源：有 Local Memory 时优先使用，用尽后回退到 dma_api
+#[derive(Clone, Default)]
+pub struct MemPool {
+    local: Option<Arc<Mutex<LocalHeap>>>,
+}
+
+impl MemPool {
+    fn alloc_local<T>(&self, len: usize, align: usize) -> Option<LocalBlock<T>> {
+        let heap = self.local.as_ref()?;
+        let size = size_of::<T>() * len;
+        let (virt, bus, off) = {
+            let mut h = heap.lock();
+            let off = h.alloc(size, align)?;
+            (h.virt, h.bus, off)
+        };
+
+        let ptr = unsafe { virt.add(off) };
+        // MMIO 空间须按 u32 逐个清零
+        let words = ptr.cast::<u32>();
+        for i in 0..size.next_multiple_of(GRANULE) / 4 {
+            unsafe { words.add(i).write_volatile(0) };
+        }
+
+        Some(LocalBlock {
+            ptr: ptr.cast(),
+            len,
+            off,
+            bus: bus + off as u64,
+            heap: heap.clone(),
+        })
+    }
+}
+
+pub struct LocalBlock<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    off: usize,
+    bus: u64,
+    heap: Arc<Mutex<LocalHeap>>,
+}
+
+impl<T> Drop for LocalBlock<T> {
+    fn drop(&mut self) {
+        self.heap
+            .lock()
+            .dealloc(self.off, size_of::<T>() * self.len);
+    }
+}
+
+/// 与`DVec`接口一致的数组。`Dma`同时保存分配时取得的指针，
+/// DMA 一致的平台上直接访问，跳过 dma_api 的缓存维护
+pub enum MemVec<T> {
+    Dma(DVec<T>, NonNull<T>),
+    Local(LocalBlock<T>),
+}
+
+impl<T> MemVec<T> {
+    pub fn zeros(pool: &MemPool, len: usize, align: usize, direction: Direction) -> Result<Self> {
+        if let Some(block) = pool.alloc_local(len, align) {
+            return Ok(Self::Local(block));
+        }
+        let v = DVec::zeros(len, align, direction).ok_or(USBError::NoMemory)?;
+        let ptr = NonNull::from(v.deref()).cast();
+        Ok(Self::Dma(v, ptr))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Dma(v, _) => v.len(),
+            Self::Local(b) => b.len,
+        }
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        match self {
+            Self::Dma(v, _) => v.bus_addr(),
+            Self::Local(b) => b.bus,
+        }
+    }
+
+    /// 不做缓存维护时可直接访问的指针
+    fn raw(&self) -> Option<NonNull<T>> {
+        match self {
+            Self::Dma(_, ptr) if dma_coherent() => Some(*ptr),
+            Self::Dma(..) => None,
+            Self::Local(b) => Some(b.ptr),
+        }
+    }
+
+    /// 读取前使该元素的缓存失效
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        match (self, self.raw()) {
+            (_, Some(ptr)) => Some(unsafe { ptr.add(index).read_volatile() }),
+            (Self::Dma(v, _), None) => v.get(index),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 写入后回写该元素所在的缓存行
+    pub fn set(&mut self, index: usize, value: T) {
+        assert!(index < self.len());
+        match (self.raw(), self) {
+            (Some(ptr), _) => unsafe { ptr.add(index).write_volatile(value) },
+            (None, Self::Dma(v, _)) => v.set(index, value),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<T: Copy> MemVec<T> {
+    /// 从头写入`src`并回写缓存
+    pub fn copy_from_slice(&mut self, src: &[T]) {
+        assert!(src.len() <= self.len());
+        match (self.raw(), self) {
+            (Some(ptr), _) => {
+                for (i, v) in src.iter().enumerate() {
+                    unsafe { ptr.add(i).write_volatile(*v) };
+                }
+            }
+            (None, Self::Dma(v, _)) => v.copy_from_slice(src),
+            _ => unreachable!(),
+        }
+    }
+
+    /// 使缓存失效后读出前`out.len()`个元素
+    pub fn copy_to_slice(&self, out: &mut [T]) {
+        assert!(out.len() <= self.len());
+        match (self, self.raw()) {
+            (_, Some(ptr)) => {
+                for (i, v) in out.iter_mut().enumerate() {
+                    *v = unsafe { ptr.add(i).read_volatile() };
+                }
+            }
+            (Self::Dma(v, _), None) => out.copy_from_slice(&v.deref()[..out.len()]),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// 与`DBox`接口一致的单个结构
+pub enum MemBox<T> {
+    Dma(DBox<T>, NonNull<T>),
+    Local(LocalBlock<T>),
+}
+
+impl<T> MemBox<T> {
+    pub fn zero_with_align(pool: &MemPool, direction: Direction, align: usize) -> Result<Self> {
+        if let Some(block) = pool.alloc_local(1, align) {
+            return Ok(Self::Local(block));
+        }
+        let mut b = DBox::zero_with_align(direction, align).ok_or(USBError::NoMemory)?;
+        let mut ptr = NonNull::dangling();
+        b.modify(|v| ptr = NonNull::from(v));
+        Ok(Self::Dma(b, ptr))
+    }
+
+    pub fn bus_addr(&self) -> u64 {
+        match self {
+            Self::Dma(b, _) => b.bus_addr(),
+            Self::Local(b) => b.bus,
+        }
+    }
+
+    /// 使缓存失效后读出
+    pub fn read(&self) -> T {
+        match self {
+            Self::Dma(b, _) if !dma_coherent() => b.read(),
+            Self::Dma(_, ptr) => unsafe { ptr.read_volatile() },
+            Self::Local(b) => unsafe { b.ptr.read_volatile() },
+        }
+    }
+
+    /// 修改前使缓存失效，修改后回写
+    pub fn modify(&mut self, f: impl FnOnce(&mut T)) {
+        let ptr = match self {
+            Self::Dma(b, _) if !dma_coherent() => return b.modify(f),
+            Self::Dma(_, ptr) => *ptr,
+            Self::Local(b) => b.ptr,
+        };
+        unsafe {
+            let mut value = ptr.read_volatile();
+            f(&mut value);
+            ptr.write_volatile(value);
+        }
+    }
+}
+
+impl Xhci {
+    /// 寄存器空间的总线地址，用于计算 Local Memory 的总线地址；
+    /// PCI 控制器从 BAR0 读取，其他控制器由内核设置
+    pub fn set_mmio_bus_addr(&mut self, addr: u64) {
+        self.mmio_bus = Some(addr);
+    }
+
+    /// 查找并启用 Local Memory，须在控制器复位之后、分配数据结构之前调用
+    pub(super) fn local_memory(&self) -> MemPool {
+        let Some((mut cap, virt)) = self.find_local_memory() else {
+            return MemPool::default();
+        };
+        let Some(mmio_bus) = self.mmio_bus else {
+            debug!("Local memory present but MMIO bus address unknown, ignored");
+            return MemPool::default();
+        };
+
+        cap.header.update_volatile(|h| h.set_local_memory_enable());
+
+        let size = cap.memory.len();
+        let data = virt - self.mmio_base.as_ptr() as usize;
+        info!(
+            "Local memory: {} KiB @{:#x}",
+            size / 1024,
+            mmio_bus + data as u64
+        );
+
+        MemPool {
+            local: Some(Arc::new(Mutex::new(LocalHeap {
+                virt: unsafe { self.mmio_base.add(data) },
+                bus: mmio_bus + data as u64,
+                free: vec![(0, size)],
+            }))),
+        }
+    }
+
+    /// 返回 Local Memory Capability 及其数据区的地址
+    fn find_local_memory(&self) -> Option<(XhciLocalMemory<RangeMapper>, usize)> {
+        let hccparams1 = self.regs().capability.hccparams1.read_volatile();
+        let mapper = RangeMapper::default();
+        let mut list =
+            unsafe { List::new(self.mmio_base.as_ptr() as usize, hccparams1, mapper.clone()) }?;
+        for cap in &mut list {
+            match cap {
+                // 数据区在能力头之后映射，此时记录的就是它的地址
+                Ok(ExtendedCapability::XhciLocalMemory(cap)) => return Some((cap, mapper.0.get())),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        None
+    }
+}
+
+/// 恒等映射，并记录最近一次映射的起始地址；accessor 不公开数组的地址
+#[derive(Clone, Default)]
+struct RangeMapper(Rc<Cell<usize>>);
+
+impl Mapper for RangeMapper {
+    unsafe fn map(&mut self, phys_start: usize, _bytes: usize) -> NonZeroUsize {
+        self.0.set(phys_start);
+        unsafe { NonZeroUsize::new_unchecked(phys_start) }
+    }
+
+    fn unmap(&mut self, _virt_start: usize, _bytes: usize) {}
+}
@@ -1,11 +1,25 @@
-use core::{hint::spin_loop, num::NonZeroUsize, ptr::NonNull, time::Duration};
+use core::{
+    hint::spin_loop,
+    num::NonZeroUsize,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU16},
+    task::Poll,
+    time::Duration,
+};
 
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
+use buffer::DmaPolicy;
+use completion::{Completions, RingKey};
 use context::ScratchpadBufferArray;
-use future::LocalBoxFuture;
+use crossbeam::queue::SegQueue;
+use event::EventRing;
+use future::{Either, LocalBoxFuture};
 use futures::prelude::*;
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
+use mem::MemPool;
 use ring::{Ring, TrbData};
+use spin::Mutex;
+use sync::{AsyncLock, FatalError, PortSet};
 use xhci::{
     ExtendedCapability,
     accessor::Mapper,
@@ -14,73 +28,164 @@ use xhci::{
         usb_legacy_support_capability::{UsbLegacySupport, UsbLegacySupportControlStatus},
     },
     registers::doorbell,
-    ring::trb::{self, command, event::CommandCompletion},
+    ring::trb::{
+        self, command,
+        event::{CommandCompletion, CompletionCode},
+    },
 };
 
+mod buffer;
+mod cancel;
+mod completion;
 mod context;
+mod dbc;
+mod device;
+mod endpoint;
+mod enumerate;
 mod event;
+mod mem;
+mod port;
+mod quirks;
 mod ring;
-
-use super::Controller;
+mod strings;
+mod sync;
+mod transfer;
+
+pub use buffer::{DmaStats, InBuf, OutBuf};
+pub use dbc::{Dbc, DbcConfig, DbcState};
+pub use device::{ContextUpdate, HubInfo};
+pub use endpoint::{EndpointConfig, TransferKind};
+pub use enumerate::DeviceState;
+pub use quirks::{PciConfig, PciId, Quirks};
+pub use strings::{DeviceStrings, LANG_EN_US};
+
+use super::{Controller, DeviceHandle, EventMode, HostEvent};
 use crate::{err::*, sleep};
 
 type Registers = xhci::Registers<MemMapper>;
 type RegistersExtList = xhci::extended_capabilities::List<MemMapper>;
 type SupportedProtocol = xhci::extended_capabilities::XhciSupportedProtocol<MemMapper>;
 
+const CMD_TIMEOUT: Duration = Duration::from_secs(5);
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+/// `Drop`中无法`sleep`，以轮询次数近似停机超时
+const HALT_SPIN_LIMIT: usize = 1_000_000;
+
 pub struct Xhci {
     mmio_base: NonNull<u8>,
-    data: Option<Data>,
+    /// 命令环与设备上下文，只在不跨越 await 的短临界区内持有，中断中不访问
+    data: Mutex<Option<Data>>,
+    /// 事件环的消费端，中断中只尝试加锁
+    event: Mutex<Option<EventRing>>,
+    completions: Completions,
+    /// 修改输入上下文并等待对应命令完成的过程须互斥
+    ctx_lock: AsyncLock,
+    events: SegQueue<HostEvent>,
+    /// 致命错误，`recover`之前所有请求都以此失败
+    error: FatalError,
+    /// 中断中发现断开、尚未标记设备失效的根端口
+    gone_ports: PortSet,
+    /// 中断中收到的带宽请求（slot id），在任务中转换为`HostEvent`
+    bw_requests: SegQueue<u8>,
+    /// 调用方缓冲区的映射/中转策略及统计
+    dma: DmaPolicy,
+    /// 读取字符串描述符时优先选用的语言
+    string_lang: AtomicU16,
+    /// 非 SuperSpeed 设备先以 BSR=1 寻址并读取 EP0 最大包长
+    bsr_first: AtomicBool,
+    pci: Mutex<Option<Box<dyn PciConfig>>>,
+    quirks: Quirks,
+    /// 寄存器空间的总线地址，Local Memory 需要
+    mmio_bus: Option<u64>,
+    mode: EventMode,
 }
 
 impl Xhci {
-    pub fn new(mmio_base: NonNull<u8>) -> Self {
+    pub fn new(mmio_base: NonNull<u8>, mode: EventMode) -> Self {
         Self {
             mmio_base,
-            data: None,
+            data: Mutex::new(None),
+            event: Mutex::new(None),
+            completions: Completions::new(),
+            ctx_lock: AsyncLock::new(),
+            events: SegQueue::new(),
+            error: FatalError::new(),
+            gone_ports: PortSet::new(),
+            bw_requests: SegQueue::new(),
+            dma: DmaPolicy::new(),
+            string_lang: AtomicU16::new(LANG_EN_US),
+            bsr_first: AtomicBool::new(false),
+            pci: Mutex::new(None),
+            quirks: Quirks::empty(),
+            mmio_bus: None,
+            mode,
         }
     }
 
+    /// PCI 控制器，按厂商/设备/版本号查找需要的规避措施
+    pub fn new_pci(mmio_base: NonNull<u8>, pci: Box<dyn PciConfig>, mode: EventMode) -> Self {
+        let id = PciId::read(pci.as_ref());
+        let quirks = Quirks::lookup(id);
+        info!(
+            "xHCI {:04x}:{:04x} rev {:#x}, quirks: {:?}",
+            id.vendor, id.device, id.revision, quirks
+        );
+
+        let mut s = Self::new(mmio_base, mode);
+        s.mmio_bus = Some(pci_bar0(pci.as_ref()));
+        s.pci = Mutex::new(Some(pci));
+        s.quirks = quirks;
+        s
+    }
+
     fn regs(&self) -> Registers {
         let mapper = MemMapper {};
         unsafe { Registers::new(self.mmio_base.as_ptr() as usize, mapper) }
     }
 
-    async fn chip_hardware_reset(&mut self) -> Result {
+    async fn chip_hardware_reset(&self) -> Result {
         debug!("Reset begin ...");
         let mut regs = self.regs();
         regs.operational.usbcmd.update_volatile(|c| {
             c.clear_run_stop();
         });
 
-        while !regs.operational.usbsts.read_volatile().hc_halted() {
-            sleep(Duration::from_millis(10)).await;
-        }
+        wait_for(
+            || regs.operational.usbsts.read_volatile().hc_halted(),
+            RESET_TIMEOUT,
+        )
+        .await?;
 
         debug!("Halted");
         let o = &mut regs.operational;
         debug!("Wait for ready...");
-        while o.usbsts.read_volatile().controller_not_ready() {
-            sleep(Duration::from_millis(10)).await;
-        }
+        wait_for(
+            || !o.usbsts.read_volatile().controller_not_ready(),
+            RESET_TIMEOUT,
+        )
+        .await?;
         debug!("Ready");
 
         o.usbcmd.update_volatile(|f| {
             f.set_host_controller_reset();
         });
+        self.reset_delay().await;
 
         debug!("Reset HC");
-        while o.usbcmd.read_volatile().host_controller_reset()
-            || o.usbsts.read_volatile().controller_not_ready()
-        {
-            sleep(Duration::from_millis(10)).await;
-        }
+        wait_for(
+            || {
+                !o.usbcmd.read_volatile().host_controller_reset()
+                    && !o.usbsts.read_volatile().controller_not_ready()
+            },
+            RESET_TIMEOUT,
+        )
+        .await?;
         debug!("Reset finish");
 
         Ok(())
     }
 
-    fn setup_max_device_slots(&mut self) -> u8 {
+    fn setup_max_device_slots(&self) -> u8 {
         let mut regs = self.regs();
         let max_slots = regs
             .capability
@@ -97,8 +202,8 @@ impl Xhci {
         max_slots
     }
 
-    fn setup_dcbaap(&mut self) -> Result {
-        let dcbaa_addr = self.data()?.dev_list.dcbaa.bus_addr();
+    fn setup_dcbaap(&self) -> Result {
+        let dcbaa_addr = self.with_data(|data| Ok(data.dev_list.dcbaa.bus_addr()))?;
         debug!("DCBAAP: {:X}", dcbaa_addr);
         self.regs().operational.dcbaap.update_volatile(|r| {
             r.set(dcbaa_addr);
@@ -107,9 +212,8 @@ impl Xhci {
         Ok(())
     }
 
-    fn set_cmd_ring(&mut self) -> Result {
-        let crcr = self.data()?.cmd.trbs.bus_addr();
-        let cycle = self.data()?.cmd.cycle;
+    fn set_cmd_ring(&self) -> Result {
+        let (crcr, cycle) = self.with_data(|data| Ok((data.cmd.bus_addr(), data.cmd.cycle)))?;
 
         debug!("CRCR: {:X}", crcr);
         self.regs().operational.crcr.update_volatile(|r| {
@@ -124,7 +228,7 @@ impl Xhci {
         Ok(())
     }
 
-    fn init_irq(&mut self) -> Result {
+    fn init_irq(&self) -> Result {
         debug!("Disable interrupts");
         let mut regs = self.regs();
 
@@ -132,9 +236,11 @@ impl Xhci {
             r.clear_interrupter_enable();
         });
 
-        let erstz = self.data()?.event.len();
-        let erdp = self.data()?.event.erdp();
-        let erstba = self.data()?.event.erstba();
+        let (erstz, erdp, erstba) = {
+            let event = self.event.lock();
+            let event = event.as_ref().ok_or(USBError::NotInitialized)?;
+            (event.len(), event.erdp(), event.erstba())
+        };
 
         {
             let mut ir0 = regs.interrupter_register_set.interrupter_mut(0);
@@ -160,6 +266,11 @@ impl Xhci {
             });
         }
 
+        if self.mode == EventMode::Polling {
+            debug!("Polling mode, interrupts stay disabled");
+            return Ok(());
+        }
+
         {
             debug!("Enabling primary interrupter.");
             regs.interrupter_register_set
@@ -180,7 +291,7 @@ impl Xhci {
         Ok(())
     }
 
-    fn setup_scratchpads(&mut self) -> Result {
+    fn setup_scratchpads(&self) -> Result {
         let scratchpad_buf_arr = {
             let buf_count = {
                 let count = self
@@ -197,20 +308,22 @@ impl Xhci {
             }
             let scratchpad_buf_arr = ScratchpadBufferArray::new(buf_count as _)?;
 
-            let bus_addr = scratchpad_buf_arr.bus_addr();
-
-            self.data()?.dev_list.dcbaa.set(0, bus_addr);
-
-            debug!("Setting up {} scratchpads, at {:#0x}", buf_count, bus_addr);
+            debug!(
+                "Setting up {} scratchpads, at {:#0x}",
+                buf_count,
+                scratchpad_buf_arr.bus_addr()
+            );
             scratchpad_buf_arr
         };
 
-        self.data()?.scratchpad_buf_arr = Some(scratchpad_buf_arr);
-
-        Ok(())
+        self.with_data(|data| {
+            data.dev_list.dcbaa.set(0, scratchpad_buf_arr.bus_addr());
+            data.scratchpad_buf_arr = Some(scratchpad_buf_arr);
+            Ok(())
+        })
     }
 
-    async fn start(&mut self) -> Result {
+    async fn start(&self) -> Result {
         let mut regs = self.regs();
         debug!("Start run");
 
@@ -230,23 +343,347 @@ impl Xhci {
         Ok(())
     }
 
-    async fn post_cmd(&mut self, trb: command::Allowed) -> Result {
-        let trb_addr = self.data()?.cmd.enque_command(trb);
+    async fn post_cmd(&self, trb: command::Allowed) -> Result<CommandCompletion> {
+        if let Some(err) = self.error.get() {
+            return Err(err);
+        }
+
+        let trb_addr = self.with_data(|data| self.queue_cmd(&mut data.cmd, trb))?;
 
+        let res = {
+            let wait = self
+                .wait_event(RingKey::COMMAND, trb_addr, None)
+                .boxed_local();
+            match future::select(wait, sleep(CMD_TIMEOUT).boxed_local()).await {
+                Either::Left((res, _)) => Some(res),
+                Either::Right(_) => None,
+            }
+        };
+
+        let res = match res {
+            Some(res) => res?,
+            None => return Err(self.abort_command(trb_addr).await),
+        };
+        // 命令按顺序完成，并发提交时先完成者推进 dequeue 后，后者的地址仍在未完成区间内
+        self.with_data(|data| {
+            data.cmd.set_dequeue(trb_addr);
+            Ok(())
+        })?;
+
+        let trb::event::Allowed::CommandCompletion(c) = res else {
+            return Err(USBError::Unknown);
+        };
+
+        match c.completion_code() {
+            Ok(CompletionCode::Success) => Ok(c),
+            Ok(code) => Err(USBError::CommandFailed(code)),
+            Err(_) => Err(USBError::Unknown),
+        }
+    }
+
+    /// 入队命令并敲门铃，结果由调用方取走后推进命令环的 dequeue 指针
+    fn queue_cmd(&self, cmd: &mut Ring, trb: command::Allowed) -> Result<u64> {
+        let trb_addr = cmd.enque_command(trb)?;
+        self.completions.arm(RingKey::COMMAND, cmd, trb_addr)?;
         self.regs()
             .doorbell
             .write_volatile_at(0, doorbell::Register::default());
+        Ok(trb_addr)
+    }
 
-        let res = self.data()?.event.wait_result(trb_addr).await;
+    /// 命令超时：置 CRCR.CA 中止命令环，停止后把超时的命令改为 No Op 并重新启动，
+    /// 收到该 TRB 的完成事件（Command Aborted 或 No Op 完成）后回收其空间。
+    /// 控制器未响应时标记致命错误
+    async fn abort_command(&self, trb_addr: u64) -> USBError {
+        let err = self.check_alive();
+        if err != USBError::Timeout {
+            return err;
+        }
+        warn!("Command @{:#X} timed out, aborting", trb_addr);
+
+        let crcr = || self.regs().operational.crcr.read_volatile();
+        if crcr().command_ring_running() {
+            // 命令环运行时写入 CRCR 的指针字段被忽略
+            self.regs().operational.crcr.update_volatile(|r| {
+                r.set_command_abort();
+            });
+        }
+        if wait_for(|| !crcr().command_ring_running(), CMD_TIMEOUT)
+            .await
+            .is_err()
+        {
+            self.fail(USBError::ControllerNotResponding);
+            return USBError::ControllerNotResponding;
+        }
 
-        if let trb::event::Allowed::CommandCompletion(c) = res {
+        if let Err(e) = self.with_data(|data| {
+            data.cmd.cancel_command(trb_addr);
+            Ok(())
+        }) {
+            return e;
+        }
+        self.regs()
+            .doorbell
+            .write_volatile_at(0, doorbell::Register::default());
+
+        let wait = self
+            .wait_event(RingKey::COMMAND, trb_addr, None)
+            .boxed_local();
+        match future::select(wait, sleep(CMD_TIMEOUT).boxed_local()).await {
+            Either::Left((Ok(_), _)) => {
+                let _ = self.with_data(|data| {
+                    data.cmd.set_dequeue(trb_addr);
+                    Ok(())
+                });
+                USBError::Timeout
+            }
+            Either::Left((Err(e), _)) => e,
+            Either::Right(_) => {
+                self.fail(USBError::ControllerNotResponding);
+                USBError::ControllerNotResponding
+            }
+        }
+    }
+
+    /// 等待`key`环上`trb_addr`的完成事件，轮询模式下由等待方驱动事件环。
+    /// `port`为传输所在的根端口，端口断开后立即以`Disconnected`结束
+    async fn wait_event(
+        &self,
+        key: RingKey,
+        trb_addr: u64,
+        port: Option<u8>,
+    ) -> Result<trb::event::Allowed> {
+        let polling = self.mode == EventMode::Polling;
+        future::poll_fn(|cx| {
+            if polling {
+                self.process_events();
+            }
+            // 取消中的 TD 所在端点在取消结束前不会启动，等待者也推进取消请求
+            if let Some(mut data) = self.data.try_lock()
+                && let Some(data) = data.as_mut()
+                && !data.cancels.is_empty()
+            {
+                self.advance_cancels(data, Some(cx.waker()));
+            }
+            if let Some(res) = self.completions.poll_result(key, trb_addr, cx.waker()) {
+                return Poll::Ready(res);
+            }
+            if let Some(err) = self.error.get() {
+                return Poll::Ready(Err(err));
+            }
+            if port.is_some_and(|p| self.gone_ports.contains(p)) {
+                return Poll::Ready(Err(USBError::Disconnected));
+            }
+            if polling {
+                // 让出执行权，下次轮询时继续读取事件环
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// 取出事件环中的所有事件并更新 ERDP，处理端口变化等非请求事件及致命错误。
+    /// 可在中断中与任务并发调用，事件环已被其他 CPU 占用时由对方处理
+    fn process_events(&self) {
+        let polling = self.mode == EventMode::Polling;
+        let mut sts = self.regs().operational.usbsts.read_volatile();
+        if (polling || sts.event_interrupt())
+            && let Some(mut event) = self.event.try_lock()
+        {
+            if let Some(event) = event.as_mut() {
+                let mut count = 0;
+                while let Some((allowed, _cycle)) = event.next() {
+                    self.completions.push(allowed);
+                    count += 1;
+                }
+                if count > 0 || !polling {
+                    let erdp = event.erdp();
+                    let mut regs = self.regs();
+                    let mut irq = regs.interrupter_register_set.interrupter_mut(0);
+
+                    irq.erdp.update_volatile(|r| {
+                        r.set_event_ring_dequeue_pointer(erdp);
+                        r.clear_event_handler_busy();
+                    });
+
+                    irq.iman.update_volatile(|r| {
+                        r.clear_interrupt_pending();
+                    });
+                }
+            }
+
+            sts.clear_event_interrupt();
+        }
+        while let Some(event) = self.completions.take_unsolicited() {
+            match event {
+                trb::event::Allowed::PortStatusChange(p) => self.port_changed(p.port_id()),
+                trb::event::Allowed::BandwidthRequest(b) => self.bw_requests.push(b.slot_id()),
+                _ => {}
+            }
+        }
+        if sts.port_change_detect() {
+            debug!("Port Change Detected");
+
+            sts.clear_port_change_detect();
+        }
+
+        if sts.host_system_error() {
+            self.fail(USBError::HostSystemError);
+            sts.clear_host_system_error();
+        } else if sts.host_controller_error() {
+            self.fail(USBError::HostControllerError);
+        }
+
+        self.regs().operational.usbsts.write_volatile(sts);
+    }
+
+    /// 命令超时后判断控制器是否已失去响应
+    fn check_alive(&self) -> USBError {
+        let sts = self.regs().operational.usbsts.read_volatile();
+        if sts.hc_halted() || sts.host_controller_error() || sts.controller_not_ready() {
+            self.fail(USBError::ControllerNotResponding);
+            USBError::ControllerNotResponding
         } else {
-            panic!("Invalid event type")
+            USBError::Timeout
         }
+    }
 
+    /// 标记致命错误，让所有等待中的请求失败并通知上层
+    fn fail(&self, err: USBError) {
+        if !self.error.set(&err) {
+            return;
+        }
+        error!("xHCI fatal error: {}", err);
+
+        self.events.push(HostEvent::Error(err));
+        self.completions.wake_all();
+    }
+
+    /// 复位控制器并重建`Data`，`init`与`recover`共用
+    async fn setup(&self) -> Result {
+        self.chip_hardware_reset().await?;
+        self.intel_port_switch(true);
+        let max_slots = self.setup_max_device_slots();
+        let hccparams1 = self.regs().capability.hccparams1.read_volatile();
+        let ctx_64 = hccparams1.context_size();
+        self.dma.set_ac64(hccparams1.addressing_capability());
+        // 控制器已复位，旧的 DMA 结构可以安全释放
+        *self.event.lock() = None;
+        *self.data.lock() = None;
+        self.completions.reset();
+        let pool = self.local_memory();
+        let event = EventRing::new_in(&pool)?;
+        let data = Data::new(max_slots as _, ctx_64, pool)?;
+        self.completions.init(max_slots as _);
+        self.completions.bind(RingKey::COMMAND, &data.cmd)?;
+        *self.event.lock() = Some(event);
+        *self.data.lock() = Some(data);
+        self.setup_dcbaap()?;
+        self.set_cmd_ring()?;
+        self.init_irq()?;
+        self.setup_scratchpads()?;
+        self.start().await?;
         Ok(())
     }
 
+    async fn recover(&self) -> Result {
+        let Some(err) = self.error.get() else {
+            return Ok(());
+        };
+        warn!("Recovering from {}", err);
+
+        self.setup().await?;
+        self.error.clear();
+        self.events.push(HostEvent::Reset);
+        info!("Controller recovered, re-enumerating");
+
+        self.probe().await?;
+        Ok(())
+    }
+
+    /// 禁用所有 slot 并停机，解除控制器对 DMA 结构的引用后释放`Data`，
+    /// `release`为 true 时通过 USB Legacy Support 将所有权交还 BIOS
+    async fn shutdown(&self, release: bool) -> Result {
+        if self.data.lock().is_none() {
+            return Ok(());
+        }
+        info!("Shutdown begin");
+
+        if self.error.get().is_none() {
+            for slot_id in self.with_data(|data| Ok(data.dev_list.slot_ids()))? {
+                if let Err(e) = self.disable_slot(slot_id).await {
+                    warn!("Disable slot {} failed: {}", slot_id, e);
+                }
+            }
+        }
+
+        self.stop();
+        let regs = self.regs();
+        wait_for(
+            || regs.operational.usbsts.read_volatile().hc_halted(),
+            RESET_TIMEOUT,
+        )
+        .await?;
+        debug!("Halted");
+
+        if self.quirks.contains(Quirks::SPURIOUS_WAKEUP) {
+            self.chip_hardware_reset().await?;
+        }
+
+        self.clear_dma_regs();
+        *self.event.lock() = None;
+        *self.data.lock() = None;
+        self.completions.reset();
+        self.error.clear();
+        while self.events.pop().is_some() {}
+
+        if release {
+            self.intel_port_switch(false);
+            self.legacy_release();
+        }
+
+        info!("Shutdown finish");
+        Ok(())
+    }
+
+    /// 清除 R/S 与中断使能，控制器应在 16ms 内停机
+    fn stop(&self) {
+        self.regs().operational.usbcmd.update_volatile(|r| {
+            r.clear_run_stop();
+            r.clear_interrupter_enable();
+            r.clear_host_system_error_enable();
+        });
+    }
+
+    /// 停机后清除控制器持有的 DMA 地址
+    fn clear_dma_regs(&self) {
+        let mut regs = self.regs();
+        {
+            let mut ir0 = regs.interrupter_register_set.interrupter_mut(0);
+            ir0.iman.update_volatile(|r| {
+                r.clear_interrupt_enable();
+                r.clear_interrupt_pending();
+            });
+            ir0.erstsz.update_volatile(|r| r.set(0));
+            ir0.erstba.update_volatile(|r| {
+                r.set(0);
+            });
+            ir0.erdp.update_volatile(|r| {
+                r.set_event_ring_dequeue_pointer(0);
+                r.clear_event_handler_busy();
+            });
+        }
+
+        regs.operational.dcbaap.update_volatile(|r| {
+            r.set(0);
+        });
+        regs.operational.crcr.update_volatile(|r| {
+            r.set_command_ring_pointer(0);
+            r.clear_ring_cycle_state();
+        });
+    }
+
     fn extended_capabilities(&self) -> Vec<ExtendedCapability<MemMapper>> {
         let hccparams1 = self.regs().capability.hccparams1.read_volatile();
         let mapper = MemMapper {};
@@ -268,7 +705,7 @@ impl Xhci {
         out
     }
 
-    async fn init_ext_caps(&mut self) -> Result {
+    async fn init_ext_caps(&self) -> Result {
         let caps = self.extended_capabilities();
         debug!("Extended capabilities: {:?}", caps.len());
 
@@ -280,7 +717,8 @@ impl Xhci {
                 ExtendedCapability::XhciSupportedProtocol(xhci_supported_protocol) => {}
                 ExtendedCapability::HciExtendedPowerManagementCapability(generic) => {}
                 ExtendedCapability::XhciMessageInterrupt(xhci_message_interrupt) => {}
-                ExtendedCapability::XhciLocalMemory(xhci_local_memory) => {}
+                // 由`local_memory`在分配数据结构之前启用
+                ExtendedCapability::XhciLocalMemory(_) => {}
                 ExtendedCapability::Debug(debug) => {}
                 ExtendedCapability::XhciExtendedMessageInterrupt(generic) => {}
             }
@@ -289,7 +727,28 @@ impl Xhci {
         Ok(())
     }
 
-    async fn legacy_init(&mut self, mut usb_legacy_support: UsbLegacySupport<MemMapper>) -> Result {
+    /// 启用 Debug Capability，可在`init`之前调用，用于早期日志输出
+    pub fn debug_capability(&self, config: &DbcConfig) -> Result<Dbc> {
+        let regs = self
+            .extended_capabilities()
+            .into_iter()
+            .find_map(|cap| match cap {
+                ExtendedCapability::Debug(debug) => Some(debug),
+                _ => None,
+            })
+            .ok_or(USBError::NotSupported)?;
+
+        let mut dbc = Dbc::new(regs, config)?;
+        dbc.enable()?;
+        Ok(dbc)
+    }
+
+    /// 批量/中断传输直接使用调用方缓冲区与经驱动缓冲区中转的统计
+    pub fn dma_stats(&self) -> DmaStats {
+        self.dma.stats()
+    }
+
+    async fn legacy_init(&self, mut usb_legacy_support: UsbLegacySupport<MemMapper>) -> Result {
         debug!("legacy init");
         usb_legacy_support.usblegsup.update_volatile(|r| {
             r.set_hc_os_owned_semaphore();
@@ -320,55 +779,101 @@ impl Xhci {
         Ok(())
     }
 
-    fn data(&mut self) -> Result<&mut Data> {
-        self.data.as_mut().ok_or(USBError::NotInitialized)
+    fn legacy_release(&self) {
+        for cap in self.extended_capabilities() {
+            if let ExtendedCapability::UsbLegacySupport(mut usb_legacy_support) = cap {
+                usb_legacy_support.usblegsup.update_volatile(|r| {
+                    r.clear_hc_os_owned_semaphore();
+                });
+                debug!("released ownership to BIOS");
+            }
+        }
+    }
+
+    /// 在不跨越 await 的短临界区内访问`Data`，先处理中断中推迟的工作
+    fn with_data<R>(&self, f: impl FnOnce(&mut Data) -> Result<R>) -> Result<R> {
+        let mut data = self.data.lock();
+        let data = data.as_mut().ok_or(USBError::NotInitialized)?;
+        self.apply_deferred(data);
+        f(data)
+    }
+
+    /// 中断中不访问`Data`，端口断开、带宽请求与 TD 的取消推迟到此处理
+    fn apply_deferred(&self, data: &mut Data) {
+        for port in self.gone_ports.take() {
+            self.mark_gone(data, |r| r.root_port == port);
+        }
+        while let Some(slot_id) = self.bw_requests.pop() {
+            self.bandwidth_request(data, slot_id);
+        }
+        self.advance_cancels(data, None);
     }
 }
 
+// 寄存器访问均为 MMIO，可变状态由锁或原子变量保护，可在中断与多个 CPU 间共享
+unsafe impl Send for Xhci {}
+unsafe impl Sync for Xhci {}
+
 struct Data {
     dev_list: context::DeviceContextList,
     cmd: Ring,
-    event: event::EventRing,
     scratchpad_buf_arr: Option<ScratchpadBufferArray>,
+    /// 被放弃、尚未退役的 TD
+    cancels: Vec<cancel::TdCancel>,
 }
 
 impl Data {
-    fn new(max_slots: usize) -> Result<Self> {
-        let cmd = Ring::new_with_len(
+    fn new(max_slots: usize, ctx_64: bool, pool: MemPool) -> Result<Self> {
+        let cmd = Ring::new_in(
+            &pool,
             0x1000 / size_of::<TrbData>(),
             true,
             dma_api::Direction::Bidirectional,
         )?;
-        let event = event::EventRing::new(&cmd)?;
 
         Ok(Self {
-            dev_list: context::DeviceContextList::new(max_slots)?,
+            dev_list: context::DeviceContextList::new(max_slots, ctx_64, pool)?,
             cmd,
-            event,
             scratchpad_buf_arr: None,
+            cancels: Vec::new(),
         })
     }
 }
 
 impl Controller for Xhci {
-    fn init(&mut self) -> LocalBoxFuture<'_, Result> {
+    fn init(&self) -> LocalBoxFuture<'_, Result> {
         async {
             self.init_ext_caps().await?;
-            self.chip_hardware_reset().await?;
-            let max_slots = self.setup_max_device_slots();
-            self.data = Some(Data::new(max_slots as _)?);
-            self.setup_dcbaap()?;
-            self.set_cmd_ring()?;
-            self.init_irq()?;
-            self.setup_scratchpads()?;
-            self.start().await?;
+            self.setup().await?;
 
             Ok(())
         }
         .boxed_local()
     }
 
-    fn test_cmd(&mut self) -> LocalBoxFuture<'_, Result> {
+    fn probe(&self) -> LocalBoxFuture<'_, Result<Vec<DeviceHandle>>> {
+        Xhci::probe(self).boxed_local()
+    }
+
+    fn recover(&self) -> LocalBoxFuture<'_, Result> {
+        Xhci::recover(self).boxed_local()
+    }
+
+    fn poll_event(&self) -> Option<HostEvent> {
+        if self.mode == EventMode::Polling {
+            self.process_events();
+        }
+        if let Some(data) = self.data.lock().as_mut() {
+            self.apply_deferred(data);
+        }
+        self.events.pop()
+    }
+
+    fn shutdown(&self, release: bool) -> LocalBoxFuture<'_, Result> {
+        Xhci::shutdown(self, release).boxed_local()
+    }
+
+    fn test_cmd(&self) -> LocalBoxFuture<'_, Result> {
         async {
             self.post_cmd(command::Allowed::Noop(command::Noop::new()))
                 .await?;
@@ -377,43 +882,56 @@ impl Controller for Xhci {
         .boxed_local()
     }
 
-    fn handle_irq(&mut self) {
-        let mut sts = self.regs().operational.usbsts.read_volatile();
-        if sts.event_interrupt() {
-            let erdp = {
-                let event = &mut self.data().unwrap().event;
-                event.clean_events();
-                event.erdp()
-            };
-            {
-                let mut regs = self.regs();
-                let mut irq = regs.interrupter_register_set.interrupter_mut(0);
+    fn handle_irq(&self) {
+        self.process_events();
+    }
+}
 
-                irq.erdp.update_volatile(|r| {
-                    r.set_event_ring_dequeue_pointer(erdp);
-                    r.clear_event_handler_busy();
-                });
+impl Drop for Xhci {
+    fn drop(&mut self) {
+        if self.data.get_mut().is_none() {
+            return;
+        }
 
-                irq.iman.update_volatile(|r| {
-                    r.clear_interrupt_pending();
-                });
-            }
+        self.stop();
+        let regs = self.regs();
+        let halted = (0..HALT_SPIN_LIMIT).any(|_| {
+            spin_loop();
+            regs.operational.usbsts.read_volatile().hc_halted()
+        });
 
-            sts.clear_event_interrupt();
+        if halted {
+            self.clear_dma_regs();
+        } else {
+            // 控制器仍可能写入这些内存，宁可泄漏也不能释放
+            error!("xHCI not halted, leaking DMA memory");
+            core::mem::forget(self.data.get_mut().take());
+            core::mem::forget(self.event.get_mut().take());
         }
-        if sts.port_change_detect() {
-            debug!("Port Change Detected");
+    }
+}
 
-            sts.clear_port_change_detect();
-        }
+/// BAR0 的总线地址，64 位 BAR 占用 BAR0/BAR1
+fn pci_bar0(pci: &dyn PciConfig) -> u64 {
+    let low = pci.read(0x10);
+    let mut addr = (low & !0xF) as u64;
+    if (low >> 1) & 0x3 == 0x2 {
+        addr |= (pci.read(0x14) as u64) << 32;
+    }
+    addr
+}
 
-        if sts.host_system_error() {
-            debug!("Host System Error");
-            sts.clear_host_system_error();
+async fn wait_for(mut f: impl FnMut() -> bool, timeout: Duration) -> Result {
+    let interval = Duration::from_millis(10);
+    let mut waited = Duration::ZERO;
+    while !f() {
+        if waited >= timeout {
+            return Err(USBError::Timeout);
         }
-
-        self.regs().operational.usbsts.write_volatile(sts);
+        sleep(interval).await;
+        waited += interval;
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -0,0 +1,185 @@
+use core::time::Duration;
+
+use alloc::vec::Vec;
+use log::{debug, warn};
+use xhci::registers::operational::PortStatusAndControlRegister;
+
+use super::{Xhci, wait_for};
+use crate::{DeviceHandle, HostEvent, err::*, sleep};
+
+const PORT_RESET_TIMEOUT: Duration = Duration::from_millis(500);
+/// USB 2.0 TRSTRCY，复位结束后给设备的恢复时间
+const PORT_RESET_RECOVERY: Duration = Duration::from_millis(10);
+/// 连接状态需保持稳定的时间，USB 2.0 TATTDB
+const DEBOUNCE_STABLE: Duration = Duration::from_millis(100);
+const DEBOUNCE_STEP: Duration = Duration::from_millis(25);
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(2000);
+
+impl Xhci {
+    pub(super) fn port_count(&self) -> usize {
+        self.regs().port_register_set.len()
+    }
+
+    /// `port_id`从 1 开始
+    pub(super) fn portsc(&self, port_id: u8) -> PortStatusAndControlRegister {
+        self.regs()
+            .port_register_set
+            .read_volatile_at(port_id as usize - 1)
+            .portsc
+    }
+
+    /// 等待连接状态保持稳定，期间抖动则重新计时；稳定后未连接返回`Disconnected`
+    pub(super) async fn debounce(&self, port_id: u8) -> Result {
+        let mut connected = self.portsc(port_id).current_connect_status();
+        let mut stable = Duration::ZERO;
+        let mut elapsed = Duration::ZERO;
+
+        while stable < DEBOUNCE_STABLE {
+            if elapsed >= DEBOUNCE_TIMEOUT {
+                warn!("Port {} connection not stable", port_id);
+                return Err(USBError::Timeout);
+            }
+            sleep(DEBOUNCE_STEP).await;
+            elapsed += DEBOUNCE_STEP;
+
+            let now = self.portsc(port_id).current_connect_status();
+            if now == connected {
+                stable += DEBOUNCE_STEP;
+            } else {
+                connected = now;
+                stable = Duration::ZERO;
+            }
+        }
+
+        if !connected {
+            return Err(USBError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// `force`为 false 时跳过已使能的端口
+    pub(super) async fn reset_port(&self, port_id: u8, force: bool) -> Result {
+        let i = port_id as usize - 1;
+        let mut regs = self.regs();
+
+        // USB3 端口在链路训练完成后自动使能，枚举时无需复位
+        if !force
+            && regs
+                .port_register_set
+                .read_volatile_at(i)
+                .portsc
+                .port_enabled_disabled()
+        {
+            return Ok(());
+        }
+
+        debug!("Port {} reset", port_id);
+        regs.port_register_set.update_volatile_at(i, |r| {
+            port_neutral(&mut r.portsc);
+            r.portsc.set_port_reset();
+        });
+
+        wait_for(
+            || {
+                regs.port_register_set
+                    .read_volatile_at(i)
+                    .portsc
+                    .port_reset_change()
+            },
+            PORT_RESET_TIMEOUT,
+        )
+        .await?;
+
+        regs.port_register_set.update_volatile_at(i, |r| {
+            port_neutral(&mut r.portsc);
+            r.portsc.clear_port_reset_change();
+        });
+
+        sleep(PORT_RESET_RECOVERY).await;
+
+        if !regs
+            .port_register_set
+            .read_volatile_at(i)
+            .portsc
+            .port_enabled_disabled()
+        {
+            warn!("Port {} not enabled after reset", port_id);
+            return Err(USBError::Unknown);
+        }
+
+        Ok(())
+    }
+
+    /// 处理 Port Status Change 事件，可在中断中调用；
+    /// 连接状态变化时先让该端口上等待中的传输结束，设备在任务中标记为失效
+    pub(super) fn port_changed(&self, port_id: u8) {
+        let i = port_id as usize - 1;
+        let mut regs = self.regs();
+        let portsc = regs.port_register_set.read_volatile_at(i).portsc;
+        debug!("Port {} changed: {:?}", port_id, portsc);
+
+        // PRC 留给`reset_port`清除
+        regs.port_register_set.update_volatile_at(i, |r| {
+            port_neutral(&mut r.portsc);
+            r.portsc.clear_connect_status_change();
+        });
+
+        // CCS 仍为 1 时是快速重插，端口上已不是原来的设备，同样视为断开，由`probe`重新枚举
+        if portsc.connect_status_change() {
+            self.gone_ports.insert(port_id);
+            self.completions.wake_all();
+        }
+    }
+
+    /// 扫描根端口：移除已断开的设备，为尚未寻址的已连接设备分配 slot
+    pub(super) async fn probe(&self) -> Result<Vec<DeviceHandle>> {
+        let mut out = Vec::new();
+
+        self.with_data(|data| {
+            for port_id in 1..=self.port_count() as u8 {
+                if !self.portsc(port_id).current_connect_status() {
+                    self.mark_gone(data, |r| r.root_port == port_id);
+                }
+            }
+            Ok(())
+        })?;
+        self.remove_gone().await?;
+
+        for port_id in 1..=self.port_count() as u8 {
+            if !self.portsc(port_id).current_connect_status() {
+                continue;
+            }
+            if self.with_data(|data| Ok(data.dev_list.by_port(port_id).is_some()))? {
+                continue;
+            }
+
+            match self.attach(port_id).await {
+                Ok(dev) => {
+                    self.events.push(HostEvent::Attached(dev));
+                    out.push(dev);
+                }
+                Err(e) => {
+                    if self.error.get().is_some() {
+                        return Err(e);
+                    }
+                    warn!("Port {} attach failed: {}", port_id, e);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// 回写 PORTSC 时避免误清 RW1C 位，尤其是 PED（写 1 会禁用端口）
+fn port_neutral(r: &mut PortStatusAndControlRegister) {
+    r.set_0_port_enabled_disabled();
+    r.set_0_connect_status_change();
+    r.set_0_port_enabled_disabled_change();
+    r.set_0_warm_port_reset_change();
+    r.set_0_over_current_change();
+    r.set_0_port_reset_change();
+    r.set_0_port_link_state_change();
+    r.set_0_port_config_error_change();
+    r.clear_port_link_state_write_strobe();
+}
@@ -0,0 +1,229 @@
+use core::time::Duration;
+
+use bitflags::bitflags;
+use log::{debug, info};
+
+use super::Xhci;
+use crate::sleep;
+
+/// 由内核提供的 PCI 配置空间访问，按 32 位对齐读写
+pub trait PciConfig: Send {
+    fn read(&self, offset: u16) -> u32;
+    fn write(&mut self, offset: u16, value: u32);
+}
+
+bitflags! {
+    /// 控制器缺陷及对应的规避措施
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Quirks: u32 {
+        /// Intel PCH 的 USB2/USB3 端口默认路由到 EHCI，需通过 XUSB2PR/USB3_PSSEN 切换
+        const INTEL_PORT_SWITCH = 1 << 0;
+        /// 置位 HCRST 后需等待一段时间才能访问寄存器
+        const RESET_DELAY = 1 << 1;
+        /// MSI 不可用。驱动不配置 PCI 中断，仅供内核选择 INTx 或轮询模式
+        const BROKEN_MSI = 1 << 2;
+        /// 关机后可能被立即唤醒，停机后需再复位一次控制器
+        const SPURIOUS_WAKEUP = 1 << 3;
+        /// 唤醒后状态不可信，需重新初始化控制器。驱动不保存控制器状态，`init`与`recover`
+        /// 总是先 HCRST；内核应以`shutdown`加`init`实现休眠唤醒，而不是保留原有状态
+        const RESET_ON_RESUME = 1 << 4;
+    }
+}
+
+/// PCI 标识，`revision`取自配置空间偏移 0x08
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+    pub revision: u8,
+}
+
+impl PciId {
+    pub fn read(pci: &dyn PciConfig) -> Self {
+        let id = pci.read(0x00);
+        let class = pci.read(0x08);
+        Self {
+            vendor: id as u16,
+            device: (id >> 16) as u16,
+            revision: class as u8,
+        }
+    }
+}
+
+const VENDOR_INTEL: u16 = 0x8086;
+const VENDOR_VIA: u16 = 0x1106;
+const VENDOR_ETRON: u16 = 0x1B6F;
+const VENDOR_FRESCO: u16 = 0x1B73;
+
+/// Intel 端口路由寄存器（配置空间）
+const XUSB2PR: u16 = 0xD0;
+const XUSB2PRM: u16 = 0xD4;
+const USB3_PSSEN: u16 = 0xD8;
+const USB3PRM: u16 = 0xDC;
+
+/// `Quirks::RESET_DELAY`在 HCRST 之后的等待时间
+const HCRST_DELAY: Duration = Duration::from_millis(1);
+
+/// `device`/`revision`为`None`时匹配该厂商的所有设备
+struct QuirkEntry {
+    vendor: u16,
+    device: Option<u16>,
+    revision: Option<u8>,
+    quirks: Quirks,
+}
+
+const fn entry(
+    vendor: u16,
+    device: Option<u16>,
+    revision: Option<u8>,
+    quirks: Quirks,
+) -> QuirkEntry {
+    QuirkEntry {
+        vendor,
+        device,
+        revision,
+        quirks,
+    }
+}
+
+/// 各项均取自 Linux 驱动，注释注明出处
+const QUIRK_TABLE: &[QuirkEntry] = &[
+    // xhci-pci.c 对所有 Intel 控制器置 XHCI_INTEL_HOST，xhci_reset() 据此在 HCRST 后等待 1ms
+    entry(VENDOR_INTEL, None, None, Quirks::RESET_DELAY),
+    // Panther Point，pci-quirks.c usb_is_intel_ppt_switchable_xhci()
+    entry(VENDOR_INTEL, Some(0x1E31), None, Quirks::INTEL_PORT_SWITCH),
+    // Lynx Point，pci-quirks.c usb_is_intel_lpt_switchable_xhci()
+    entry(VENDOR_INTEL, Some(0x8C31), None, Quirks::INTEL_PORT_SWITCH),
+    // Lynx Point-LP，同上；XHCI_SPURIOUS_WAKEUP 见 xhci-pci.c xhci_pci_quirks()
+    entry(
+        VENDOR_INTEL,
+        Some(0x9C31),
+        None,
+        Quirks::INTEL_PORT_SWITCH.union(Quirks::SPURIOUS_WAKEUP),
+    ),
+    // Wildcat Point-LP，xhci-pci.c xhci_pci_quirks() XHCI_SPURIOUS_WAKEUP
+    entry(VENDOR_INTEL, Some(0x9CB1), None, Quirks::SPURIOUS_WAKEUP),
+    // xhci-pci.c 对所有 VIA 控制器置 XHCI_RESET_ON_RESUME
+    entry(VENDOR_VIA, None, None, Quirks::RESET_ON_RESUME),
+    // EJ168 / EJ188，xhci-pci.c XHCI_RESET_ON_RESUME
+    entry(VENDOR_ETRON, Some(0x7023), None, Quirks::RESET_ON_RESUME),
+    entry(VENDOR_ETRON, Some(0x7052), None, Quirks::RESET_ON_RESUME),
+    // xhci-pci.c：Fresco Logic 确认其所有型号与版本的 MSI 均不可用，XHCI_BROKEN_MSI
+    entry(VENDOR_FRESCO, None, None, Quirks::BROKEN_MSI),
+];
+
+impl Quirks {
+    /// 合并表中所有匹配项
+    pub fn lookup(id: PciId) -> Self {
+        QUIRK_TABLE
+            .iter()
+            .filter(|e| {
+                e.vendor == id.vendor
+                    && e.device.is_none_or(|d| d == id.device)
+                    && e.revision.is_none_or(|r| r == id.revision)
+            })
+            .fold(Quirks::empty(), |acc, e| acc | e.quirks)
+    }
+}
+
+impl Xhci {
+    /// 生效的规避措施。`BROKEN_MSI`与`RESET_ON_RESUME`由内核处理，其余在驱动内部生效
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// 追加表中没有的规避措施，如非 PCI 的 SoC 控制器
+    pub fn add_quirks(&mut self, quirks: Quirks) {
+        self.quirks |= quirks;
+    }
+
+    /// HCRST 之后、读取寄存器之前调用
+    pub(super) async fn reset_delay(&self) {
+        if self.quirks.contains(Quirks::RESET_DELAY) {
+            sleep(HCRST_DELAY).await;
+        }
+    }
+
+    /// 把可切换的端口从 EHCI 路由到 xHCI，`enable`为 false 时交还 EHCI
+    pub(super) fn intel_port_switch(&self, enable: bool) {
+        if !self.quirks.contains(Quirks::INTEL_PORT_SWITCH) {
+            return;
+        }
+        let mut pci = self.pci.lock();
+        let Some(pci) = pci.as_mut() else {
+            return;
+        };
+
+        let (usb3, usb2) = if enable {
+            (pci.read(USB3PRM), pci.read(XUSB2PRM))
+        } else {
+            (0, 0)
+        };
+        pci.write(USB3_PSSEN, usb3);
+        pci.write(XUSB2PR, usb2);
+        debug!(
+            "USB3_PSSEN: {:#x}, XUSB2PR: {:#x}",
+            pci.read(USB3_PSSEN),
+            pci.read(XUSB2PR)
+        );
+        if enable {
+            info!("Switched Intel PCH ports to xHCI");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(vendor: u16, device: u16, revision: u8) -> PciId {
+        PciId {
+            vendor,
+            device,
+            revision,
+        }
+    }
+
+    #[test]
+    fn lookup_merges_matching_entries() {
+        assert_eq!(
+            Quirks::lookup(id(VENDOR_INTEL, 0x9C31, 0)),
+            Quirks::RESET_DELAY | Quirks::INTEL_PORT_SWITCH | Quirks::SPURIOUS_WAKEUP
+        );
+        assert_eq!(
+            Quirks::lookup(id(VENDOR_INTEL, 0x8C31, 0)),
+            Quirks::RESET_DELAY | Quirks::INTEL_PORT_SWITCH
+        );
+        assert_eq!(
+            Quirks::lookup(id(VENDOR_INTEL, 0xA36D, 0x10)),
+            Quirks::RESET_DELAY
+        );
+    }
+
+    #[test]
+    fn lookup_fresco_msi() {
+        // FL1000 各版本、FL1009、FL1100
+        for (device, revision) in [(0x1000, 0), (0x1000, 4), (0x1400, 2), (0x1100, 0x10)] {
+            assert_eq!(
+                Quirks::lookup(id(VENDOR_FRESCO, device, revision)),
+                Quirks::BROKEN_MSI
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_device_specific() {
+        assert_eq!(
+            Quirks::lookup(id(VENDOR_ETRON, 0x7023, 1)),
+            Quirks::RESET_ON_RESUME
+        );
+        assert_eq!(Quirks::lookup(id(VENDOR_ETRON, 0x7024, 1)), Quirks::empty());
+        assert_eq!(
+            Quirks::lookup(id(VENDOR_VIA, 0x3483, 1)),
+            Quirks::RESET_ON_RESUME
+        );
+        // AMD、ASMedia 无已知缺陷
+        assert_eq!(Quirks::lookup(id(0x1022, 0x43D5, 0)), Quirks::empty());
+        assert_eq!(Quirks::lookup(id(0x1B21, 0x1142, 0)), Quirks::empty());
+    }
+}
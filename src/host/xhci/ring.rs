@@ -1,12 +1,24 @@
-use dma_api::DVec;
+use core::mem;
+
+use alloc::{vec, vec::Vec};
 pub use dma_api::Direction;
-use log::trace;
-use xhci::ring::trb::{Link, command};
+use log::{debug, trace};
+use xhci::ring::trb::{Link, command, transfer};
 
+use super::{
+    buffer::{BounceTarget, DmaPolicy, InBuf, InPlan, OutBuf, OutPlan, SgList},
+    mem::{MemPool, MemVec},
+};
 use crate::{err::*, page_size};
 
-const TRB_LEN: usize = 4;
-const TRB_SIZE: usize = size_of::<TrbData>();
+pub const TRB_LEN: usize = 4;
+pub const TRB_SIZE: usize = size_of::<TrbData>();
+/// IDT 可携带的最大字节数
+const IDT_MAX: usize = 8;
+/// 单个 TRB 的缓冲区不能跨越 64KiB 边界
+pub const TRB_MAX_BUFFER: usize = 0x10000;
+/// Event Data 事件的 EDTLA 字段为 24 位
+const TD_MAX_LEN: usize = 0xFF_FFFF;
 
 #[derive(Clone)]
 #[repr(transparent)]
@@ -25,22 +37,215 @@ impl From<command::Allowed> for TrbData {
     }
 }
 
+impl From<transfer::Allowed> for TrbData {
+    fn from(value: transfer::Allowed) -> Self {
+        let raw = value.into_raw();
+        Self(raw)
+    }
+}
+
+/// 传输数据：不超过 8 字节的 OUT 数据直接写入 TRB（IDT），其余使用 DMA 缓冲区，
+/// 或直接使用映射后的调用方缓冲区。端点最大包长小于 8 时规范禁止 IDT，一律使用缓冲区
+pub enum TrbBuffer<'a> {
+    None,
+    Immediate([u8; IDT_MAX], usize),
+    Dma(MemVec<u8>),
+    Mapped(SgList<'a>),
+    /// 调用方缓冲区不满足控制器限制，IN 数据完成后复制回去
+    Bounce(MemVec<u8>, BounceTarget<'a>),
+}
+
+impl<'a> TrbBuffer<'a> {
+    pub fn out(data: &[u8], max_packet_size: u16) -> Result<Self> {
+        Ok(match data.len() {
+            0 => Self::None,
+            len if allow_idt(len, max_packet_size) => Self::immediate(data),
+            len => {
+                let mut buf = Self::alloc(len, Direction::ToDevice)?;
+                buf.copy_from_slice(data);
+                Self::Dma(buf)
+            }
+        })
+    }
+
+    pub fn input(len: usize) -> Result<Self> {
+        Ok(match len {
+            0 => Self::None,
+            len => Self::Dma(Self::alloc(len, Direction::FromDevice)?),
+        })
+    }
+
+    /// 零拷贝 OUT，不超过 8 字节的切片在允许时仍走 IDT
+    pub fn map_out(data: OutBuf<'a>, max_packet_size: u16, dma: &DmaPolicy) -> Result<Self> {
+        match data.as_slice() {
+            Some([]) => return Ok(Self::None),
+            Some(s) if allow_idt(s.len(), max_packet_size) => return Ok(Self::immediate(s)),
+            _ => {}
+        }
+        if data.len() > TD_MAX_LEN {
+            return Err(USBError::NotSupported);
+        }
+        Ok(match dma.place_out(data)? {
+            OutPlan::Direct(sg) => Self::Mapped(sg),
+            OutPlan::Bounce(data) => {
+                let mut buf = Self::alloc(data.len(), Direction::ToDevice)?;
+                buf.copy_from_slice(data);
+                Self::Dma(buf)
+            }
+        })
+    }
+
+    /// 零拷贝 IN，完成后需调用`complete`
+    pub fn map_in(buf: InBuf<'a>, dma: &DmaPolicy) -> Result<Self> {
+        let len = buf.len();
+        if len > TD_MAX_LEN {
+            return Err(USBError::NotSupported);
+        }
+        if len == 0 {
+            return Ok(Self::None);
+        }
+        Ok(match dma.place_in(buf)? {
+            InPlan::Direct(sg) => Self::Mapped(sg),
+            InPlan::Bounce(target) => {
+                Self::Bounce(Self::alloc(len, Direction::FromDevice)?, target)
+            }
+        })
+    }
+
+    fn immediate(data: &[u8]) -> Self {
+        let mut raw = [0; IDT_MAX];
+        raw[..data.len()].copy_from_slice(data);
+        Self::Immediate(raw, data.len())
+    }
+
+    fn alloc(len: usize, direction: Direction) -> Result<MemVec<u8>> {
+        if len > TD_MAX_LEN {
+            return Err(USBError::NotSupported);
+        }
+        MemVec::zeros(&MemPool::default(), len, 64, direction)
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Immediate(_, len) => *len,
+            Self::Dma(buf) => buf.len(),
+            Self::Mapped(sg) => sg.len(),
+            Self::Bounce(buf, _) => buf.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_immediate(&self) -> bool {
+        matches!(self, Self::Immediate(..))
+    }
+
+    /// 按 64KiB 边界拆分，每段为一个 TRB 的 (Data Buffer 字段, 长度)
+    fn chunks(&self) -> Vec<(u64, usize)> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Immediate(raw, len) => vec![(u64::from_le_bytes(*raw), *len)],
+            Self::Dma(buf) | Self::Bounce(buf, _) => split_boundary(&[(buf.bus_addr(), buf.len())]),
+            Self::Mapped(sg) => split_boundary(sg.segments()),
+        }
+    }
+
+    /// 作为一个 TD 入队所需的 TRB 数，含末尾的 Event Data TRB
+    pub fn td_trbs(&self) -> usize {
+        self.chunks().len() + 1
+    }
+
+    /// 零拷贝 IN 传输结束后调用，使调用方缓冲区的 CPU 缓存失效，
+    /// 或把中转缓冲区中实际收到的`actual`字节复制回去
+    pub fn complete(&mut self, actual: usize) {
+        match self {
+            Self::Mapped(sg) => sg.complete(),
+            Self::Bounce(buf, target) => {
+                target.fill(actual.min(buf.len()), |out| buf.copy_to_slice(out))
+            }
+            _ => {}
+        }
+    }
+
+    /// 取走驱动分配的缓冲区，调用方缓冲区的映射仍留在原处
+    pub fn take_owned(&mut self) -> Option<MemVec<u8>> {
+        match mem::replace(self, Self::None) {
+            Self::Dma(buf) | Self::Bounce(buf, _) => Some(buf),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    /// IN 传输完成后取回数据
+    pub fn read(&self, out: &mut [u8]) {
+        if let Self::Dma(buf) = self {
+            let n = out.len().min(buf.len());
+            buf.copy_to_slice(&mut out[..n]);
+        }
+    }
+}
+
+/// 数据不超过 8 字节且端点最大包长不小于 8 时才能使用 IDT（xHCI 规范 6.4.1.1）
+fn allow_idt(len: usize, max_packet_size: u16) -> bool {
+    len <= IDT_MAX && max_packet_size as usize >= IDT_MAX
+}
+
+/// 按 64KiB 边界拆分各段，每段为一个 TRB 的 (Data Buffer 字段, 长度)
+pub fn split_boundary(segs: &[(u64, usize)]) -> Vec<(u64, usize)> {
+    let mut chunks = Vec::new();
+    for &(mut addr, mut left) in segs {
+        while left > 0 {
+            let boundary = TRB_MAX_BUFFER - (addr as usize & (TRB_MAX_BUFFER - 1));
+            let n = left.min(boundary);
+            chunks.push((addr, n));
+            addr += n as u64;
+            left -= n;
+        }
+    }
+    chunks
+}
+
 pub struct Ring {
     link: bool,
-    pub trbs: DVec<TrbData>,
+    direction: Direction,
+    segs: Vec<MemVec<TrbData>>,
+    pool: MemPool,
+    /// 入队位置所在的段
+    seg: usize,
     pub i: usize,
     pub cycle: bool,
+    /// 控制器下一个要处理的位置（段，下标），由完成事件推进
+    deq: (usize, usize),
+    max_segs: usize,
+    /// 正在为取消 TD 而停止端点的请求数，非零时入队后不敲门铃，由取消流程结束后重新启动
+    pub stopping: usize,
 }
 
 impl Ring {
     pub fn new_with_len(len: usize, link: bool, direction: Direction) -> Result<Self> {
-        let trbs = DVec::zeros(len, 64, direction).ok_or(USBError::NoMemory)?;
+        Self::new_in(&MemPool::default(), len, link, direction)
+    }
+
+    /// 从`pool`分配，环扩展时的新段也来自同一`pool`
+    pub fn new_in(pool: &MemPool, len: usize, link: bool, direction: Direction) -> Result<Self> {
+        let trbs = MemVec::zeros(pool, len, 64, direction)?;
 
         Ok(Self {
             link,
-            trbs,
+            direction,
+            segs: vec![trbs],
+            pool: pool.clone(),
+            seg: 0,
             i: 0,
-            cycle: link,
+            cycle: true,
+            deq: (0, 0),
+            max_segs: 1,
+            stopping: 0,
         })
     }
 
@@ -49,56 +254,140 @@ impl Ring {
         Self::new_with_len(len, link, direction)
     }
 
+    /// 环满时允许扩展到`max`个段，默认不扩展
+    pub fn set_max_segments(&mut self, max: usize) {
+        self.max_segs = max.max(1);
+    }
+
+    pub fn max_segments(&self) -> usize {
+        self.max_segs
+    }
+
+    /// 每个段的 TRB 数
     pub fn len(&self) -> usize {
-        self.trbs.len()
+        self.segs[0].len()
+    }
+
+    /// 每个段中可入队的 TRB 数，link 模式下最后一个是 Link
+    fn usable(&self) -> usize {
+        if self.link {
+            self.len() - 1
+        } else {
+            self.len()
+        }
     }
 
     fn get_trb(&self) -> Option<TrbData> {
-        self.trbs.get(self.i)
+        self.segs[self.seg].get(self.i)
     }
 
+    /// 第一个段的地址，即控制器开始处理的位置
     pub fn bus_addr(&self) -> u64 {
-        self.trbs.bus_addr()
+        self.segs[0].bus_addr()
     }
 
-    pub fn enque_command(&mut self, mut trb: command::Allowed) -> u64 {
+    pub fn enque_command(&mut self, mut trb: command::Allowed) -> Result<u64> {
         if self.cycle {
             trb.set_cycle_bit();
         } else {
             trb.clear_cycle_bit();
         }
-        let addr = self.enque_trb(trb.into());
+        let addr = self.enque_trb(trb.into())?;
         trace!("[CMD] >> {:?} @{:X}", trb, addr);
-        addr
+        Ok(addr)
+    }
+
+    pub fn enque_transfer(&mut self, mut trb: transfer::Allowed) -> Result<u64> {
+        if self.cycle {
+            trb.set_cycle_bit();
+        } else {
+            trb.clear_cycle_bit();
+        }
+        let addr = self.enque_trb(trb.into())?;
+        trace!("[Transfer] >> {:?} @{:X}", trb, addr);
+        Ok(addr)
+    }
+
+    /// 把`buf`作为一个 TD 入队：数据按 64KiB 边界拆成链式 TRB，末尾附带 IOC 的
+    /// Event Data TRB。短包会让控制器直接跳到 TD 末尾，由 Event Data TRB 以
+    /// Short Packet 报告实际传输的总字节数，因此每个 TD 只产生一个完成事件。
+    /// `data_stage`为真时首个 TRB 为控制传输的数据阶段。
+    /// 返回 TD 内所有 TRB 的地址，最后一个为 Event Data TRB
+    pub fn enque_td(
+        &mut self,
+        buf: &TrbBuffer<'_>,
+        dir_in: bool,
+        data_stage: bool,
+    ) -> Result<Vec<u64>> {
+        let chunks = buf.chunks();
+        self.reserve(chunks.len() + 1)?;
+
+        let mut addrs = Vec::with_capacity(chunks.len() + 1);
+        for (i, &(param, len)) in chunks.iter().enumerate() {
+            let trb = if i == 0 && data_stage {
+                let mut trb = transfer::DataStage::new();
+                trb.set_data_buffer_pointer(param)
+                    .set_trb_transfer_length(len as _)
+                    .set_direction(dir_in.into())
+                    .set_chain_bit();
+                if buf.is_immediate() {
+                    trb.set_immediate_data();
+                }
+                transfer::Allowed::DataStage(trb)
+            } else {
+                let mut trb = transfer::Normal::new();
+                trb.set_data_buffer_pointer(param)
+                    .set_trb_transfer_length(len as _)
+                    .set_chain_bit();
+                if buf.is_immediate() {
+                    trb.set_immediate_data();
+                }
+                transfer::Allowed::Normal(trb)
+            };
+            addrs.push(self.enque_transfer(trb)?);
+        }
+
+        // 以 Event Data TRB 自身地址作为事件数据，完成事件按此地址投递
+        let mut trb = transfer::EventData::new();
+        trb.set_event_data(self.current_trb_addr())
+            .set_interrupt_on_completion();
+        addrs.push(self.enque_transfer(transfer::Allowed::EventData(trb))?);
+        Ok(addrs)
     }
 
-    pub fn enque_trb(&mut self, trb: TrbData) -> u64 {
-        self.trbs.set(self.i, trb);
-        let addr = self.trb_bus_addr(self.i);
+    /// 环满且无法扩展时返回`RingFull`，等待完成事件推进出队位置后重试
+    pub fn enque_trb(&mut self, trb: TrbData) -> Result<u64> {
+        self.reserve(1)?;
+
+        self.segs[self.seg].set(self.i, trb);
+        let addr = self.current_trb_addr();
         trace!(
-            "enqueued {} @{:#X}------------------------------------------------",
-            self.i, addr
+            "enqueued {}:{} @{:#X}------------------------------------------------",
+            self.seg, self.i, addr
         );
         self.next_index();
-        addr
+        Ok(addr)
     }
 
     pub fn current_data(&mut self) -> (TrbData, bool) {
         (self.get_trb().unwrap(), self.cycle)
     }
 
-    fn next_index(&mut self) -> usize {
+    fn next_index(&mut self) {
         self.i += 1;
-        let mut need_link = false;
         let len = self.len();
 
-        // link模式下，最后一个是Link
+        // link模式下，最后一个是Link，指向下一个段，最后一段翻转 cycle
         if self.link && self.i >= len - 1 {
-            self.i = 0;
-            trace!("link!");
-            let address = self.trb_bus_addr(0);
+            let last = self.seg + 1 == self.segs.len();
+            let next = if last { 0 } else { self.seg + 1 };
+            trace!("link! {} -> {}", self.seg, next);
+
             let mut link = Link::new();
-            link.set_ring_segment_pointer(address).set_toggle_cycle();
+            link.set_ring_segment_pointer(self.segs[next].bus_addr());
+            if last {
+                link.set_toggle_cycle();
+            }
 
             if self.cycle {
                 link.set_cycle_bit();
@@ -107,14 +396,167 @@ impl Ring {
             }
             let trb = command::Allowed::Link(link);
 
-            self.trbs.set(len - 1, trb.into());
+            self.segs[self.seg].set(len - 1, trb.into());
 
-            self.cycle = !self.cycle;
+            self.seg = next;
+            self.i = 0;
+            if last {
+                self.cycle = !self.cycle;
+            }
         } else if self.i >= len {
             self.i = 0;
         }
+    }
+
+    fn pos(&self, (seg, i): (usize, usize)) -> usize {
+        seg * self.usable() + i
+    }
+
+    /// 还可入队的 TRB 数
+    pub fn free_trbs(&self) -> usize {
+        let total = self.segs.len() * self.usable();
+        let used = (self.pos((self.seg, self.i)) + total - self.pos(self.deq)) % total;
+        total - used - 1
+    }
+
+    /// 确保还能入队`n`个 TRB，多 TRB 的 TD 需在入队前整体预留
+    pub fn reserve(&mut self, n: usize) -> Result {
+        while self.free_trbs() < n {
+            // 出队位置紧跟在同一段内时，插入新段也无法腾出空间
+            if self.segs.len() >= self.max_segs || (self.deq.0 == self.seg && self.deq.1 > self.i) {
+                return Err(USBError::RingFull);
+            }
+            self.grow()?;
+        }
+        Ok(())
+    }
+
+    /// 在入队段之后插入新段，Link 在经过时才写入，因此会自动串入新段
+    fn grow(&mut self) -> Result {
+        let len = self.len();
+        let mut seg = MemVec::zeros(&self.pool, len, 64, self.direction)?;
+
+        // 新段的 cycle 位须与当前相反，否则控制器会把空 TRB 当作有效
+        if !self.cycle {
+            for i in 0..len {
+                seg.set(i, TrbData([0, 0, 0, 1]));
+            }
+        }
+
+        let at = self.seg + 1;
+        self.segs.insert(at, seg);
+        if self.deq.0 >= at {
+            self.deq.0 += 1;
+        }
+
+        debug!("Ring grew to {} segments", self.segs.len());
+        Ok(())
+    }
+
+    /// 控制器已处理完`trb_addr`，推进出队位置；不在未完成区间内的地址忽略
+    pub fn set_dequeue(&mut self, trb_addr: u64) {
+        let Some(done) = self.locate(trb_addr).filter(|&p| self.in_flight(p)) else {
+            return;
+        };
+
+        self.deq = if done.1 + 1 < self.usable() {
+            (done.0, done.1 + 1)
+        } else {
+            ((done.0 + 1) % self.segs.len(), 0)
+        };
+    }
+
+    /// 把`trb_addr`处的命令改为 No Op 并保留 cycle 位，用于命令环中止后撤销超时的命令
+    pub fn cancel_command(&mut self, trb_addr: u64) {
+        let Some((seg, i)) = self.locate(trb_addr) else {
+            return;
+        };
+        let Some(old) = self.segs[seg].get(i) else {
+            return;
+        };
+        let mut noop = command::Noop::new();
+        if old.0[3] & 1 != 0 {
+            noop.set_cycle_bit();
+        } else {
+            noop.clear_cycle_bit();
+        }
+        self.segs[seg].set(i, command::Allowed::Noop(noop).into());
+    }
+
+    /// 读取 TD 内各 TRB 当前的原始数据，取消时据此确认这些位置没有被复用
+    pub fn snapshot(&self, trbs: &[u64]) -> Vec<(u64, [u32; TRB_LEN])> {
+        trbs.iter()
+            .filter_map(|&addr| {
+                let (seg, i) = self.locate(addr)?;
+                Some((addr, self.segs[seg].get(i)?.to_raw()))
+            })
+            .collect()
+    }
+
+    /// 端点停止后退役被放弃的 TD：各 TRB 改为只保留 cycle 位的 Transfer No Op，
+    /// 控制器经过时不产生事件，同一端点上的其他 TD 不受影响。`hw_deq`为端点上下文中的
+    /// TR Dequeue Pointer，控制器停在该 TD 内时返回 TD 之后的位置及其 cycle 状态，
+    /// 需以 Set TR Dequeue Pointer 跳过。TD 已出队或位置已被复用时不做修改
+    pub fn retire_td(&mut self, td: &[(u64, [u32; TRB_LEN])], hw_deq: u64) -> Option<(u64, bool)> {
+        let mut pos = Vec::with_capacity(td.len());
+        for &(addr, raw) in td {
+            let p = self.locate(addr).filter(|&p| self.in_flight(p))?;
+            if self.segs[p.0].get(p.1)?.to_raw() != raw {
+                return None;
+            }
+            pos.push(p);
+        }
+        for (&(_, raw), (seg, i)) in td.iter().zip(pos) {
+            let mut noop = transfer::Noop::new();
+            if raw[3] & 1 != 0 {
+                noop.set_cycle_bit();
+            } else {
+                noop.clear_cycle_bit();
+            }
+            self.segs[seg].set(i, transfer::Allowed::Noop(noop).into());
+        }
+
+        let &(last, _) = td.last()?;
+        td.iter()
+            .any(|&(addr, _)| addr == hw_deq)
+            .then(|| self.after(last))
+            .flatten()
+    }
+
+    /// `trb_addr`之后的位置，及控制器到达该处时的 cycle 状态
+    fn after(&self, trb_addr: u64) -> Option<(u64, bool)> {
+        let (seg, i) = self.locate(trb_addr)?;
+        let cycle = self.segs[seg].get(i)?.to_raw()[3] & 1 != 0;
+        Some(if i + 1 < self.usable() {
+            (
+                self.segs[seg].bus_addr() + ((i + 1) * TRB_SIZE) as u64,
+                cycle,
+            )
+        } else {
+            // 经过最后一段的 Link 时翻转 cycle
+            let next = (seg + 1) % self.segs.len();
+            (self.segs[next].bus_addr(), cycle ^ (next == 0))
+        })
+    }
+
+    /// 位置是否已入队、尚未确认完成
+    fn in_flight(&self, p: (usize, usize)) -> bool {
+        let total = self.segs.len() * self.usable();
+        let deq = self.pos(self.deq);
+        let in_flight = (self.pos((self.seg, self.i)) + total - deq) % total;
+        (self.pos(p) + total - deq) % total < in_flight
+    }
 
-        self.i
+    /// 丢弃所有未完成的 TRB，用于端点停止后将 dequeue 指针移到入队位置
+    pub fn discard(&mut self) {
+        self.deq = (self.seg, self.i);
+    }
+
+    fn locate(&self, trb_addr: u64) -> Option<(usize, usize)> {
+        self.segs.iter().enumerate().find_map(|(seg, trbs)| {
+            let i = trb_addr.checked_sub(trbs.bus_addr())? as usize / TRB_SIZE;
+            (i < self.usable()).then_some((seg, i))
+        })
     }
 
     /// 完成一次循环返回true
@@ -131,12 +573,343 @@ impl Ring {
         is_cycle
     }
 
-    pub fn trb_bus_addr(&self, i: usize) -> u64 {
-        let base = self.trbs.bus_addr();
-        base + (i * size_of::<TrbData>()) as u64
+    /// 各段的总线地址
+    pub fn segment_addrs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.segs.iter().map(|trbs| trbs.bus_addr())
     }
 
     pub fn current_trb_addr(&self) -> u64 {
-        self.trb_bus_addr(self.i)
+        self.segs[self.seg].bus_addr() + (self.i * TRB_SIZE) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::NonNull;
+
+    use super::*;
+
+    /// 主机上以虚拟地址作为总线地址
+    struct HostDma;
+
+    impl dma_api::Impl for HostDma {
+        fn map(addr: NonNull<u8>, _size: usize, _direction: Direction) -> u64 {
+            addr.as_ptr() as u64
+        }
+
+        fn unmap(_addr: NonNull<u8>, _size: usize) {}
+
+        fn flush(_addr: NonNull<u8>, _size: usize) {}
+
+        fn invalidate(_addr: NonNull<u8>, _size: usize) {}
+    }
+
+    dma_api::set_impl!(HostDma);
+
+    /// 测试中需要页大小等内核接口的模块共用
+    struct HostKernel;
+
+    impl crate::Kernel for HostKernel {
+        fn sleep<'a>(_duration: core::time::Duration) -> crate::LocalBoxFuture<'a, ()> {
+            alloc::boxed::Box::pin(async {})
+        }
+
+        fn page_size() -> usize {
+            0x1000
+        }
+    }
+
+    crate::set_impl!(HostKernel);
+
+    const TRB_TYPE_LINK: u32 = 6;
+    const TRB_TYPE_NOOP_COMMAND: u32 = 23;
+
+    fn ring(len: usize, max_segs: usize) -> Ring {
+        let mut ring = Ring::new_with_len(len, true, Direction::Bidirectional).unwrap();
+        ring.set_max_segments(max_segs);
+        ring
+    }
+
+    fn noop(ring: &mut Ring) -> u64 {
+        ring.enque_command(command::Allowed::Noop(command::Noop::new()))
+            .unwrap()
+    }
+
+    fn trb(ring: &Ring, seg: usize, i: usize) -> [u32; 4] {
+        ring.segs[seg].get(i).unwrap().0
+    }
+
+    fn trb_type(raw: [u32; 4]) -> u32 {
+        raw[3] >> 10 & 0x3F
+    }
+
+    fn cycle(raw: [u32; 4]) -> bool {
+        raw[3] & 1 != 0
+    }
+
+    #[test]
+    fn reserve_grows_up_to_max_segments() {
+        // 每段 8 个 TRB，最后一个为 Link，总有一个空位区分满与空
+        let mut ring = ring(8, 3);
+        assert_eq!(ring.free_trbs(), 6);
+        for _ in 0..6 {
+            noop(&mut ring);
+        }
+        assert_eq!(ring.free_trbs(), 0);
+
+        ring.reserve(1).unwrap();
+        assert_eq!(ring.segs.len(), 2);
+        assert_eq!(ring.free_trbs(), 7);
+
+        assert_eq!(ring.reserve(15), Err(USBError::RingFull));
+        assert_eq!(ring.segs.len(), 3);
+        assert_eq!(ring.free_trbs(), 14);
+    }
+
+    #[test]
+    fn reserve_without_growth() {
+        let mut ring = ring(8, 1);
+        assert_eq!(ring.reserve(7), Err(USBError::RingFull));
+        assert_eq!(ring.segs.len(), 1);
+        ring.reserve(6).unwrap();
+    }
+
+    #[test]
+    fn wrap_toggles_cycle() {
+        // 每段 3 个可用 TRB，最多 2 个在途
+        let mut ring = ring(4, 1);
+        let a0 = noop(&mut ring);
+        noop(&mut ring);
+        ring.set_dequeue(a0);
+        let a2 = noop(&mut ring);
+        assert!(cycle(trb(&ring, 0, 2)));
+
+        // 第一圈结束：Link 带 Toggle Cycle 且沿用旧的 cycle 位
+        let link = trb(&ring, 0, 3);
+        assert_eq!(trb_type(link), TRB_TYPE_LINK);
+        assert!(cycle(link));
+        assert_ne!(link[3] & 1 << 1, 0);
+        assert_eq!(link[0] as u64 | (link[1] as u64) << 32, ring.bus_addr());
+        assert!(!ring.cycle);
+        assert_eq!(ring.current_trb_addr(), ring.bus_addr());
+        assert_eq!(ring.free_trbs(), 0);
+
+        // 段内最后一个可用 TRB 完成后出队位置跳过 Link
+        ring.set_dequeue(a2);
+        assert_eq!(ring.deq, (0, 0));
+        assert_eq!(ring.free_trbs(), 2);
+        noop(&mut ring);
+        assert!(!cycle(trb(&ring, 0, 0)));
+    }
+
+    #[test]
+    fn set_dequeue_wraps_and_ignores_stale() {
+        let mut ring = ring(8, 2);
+        let addrs: Vec<_> = (0..6).map(|_| noop(&mut ring)).collect();
+        ring.set_dequeue(addrs[2]);
+        assert_eq!(ring.deq, (0, 3));
+        assert_eq!(ring.free_trbs(), 3);
+
+        // 已完成的地址不会让出队位置后退
+        ring.set_dequeue(addrs[1]);
+        assert_eq!(ring.deq, (0, 3));
+
+        noop(&mut ring);
+        let a = noop(&mut ring);
+        assert_eq!((ring.seg, ring.i), (0, 1));
+        ring.set_dequeue(a);
+        assert_eq!(ring.deq, (0, 1));
+        ring.set_dequeue(addrs[5]);
+        assert_eq!(ring.deq, (0, 1));
+        ring.set_dequeue(0);
+        assert_eq!(ring.deq, (0, 1));
+        assert_eq!(ring.free_trbs(), 6);
+
+        // 绕回后入队位置紧挨在出队位置之前，插入新段也腾不出空间
+        for _ in 0..6 {
+            noop(&mut ring);
+        }
+        assert_eq!((ring.seg, ring.i), (0, 0));
+        assert_eq!(ring.free_trbs(), 0);
+        assert_eq!(ring.reserve(1), Err(USBError::RingFull));
+        assert_eq!(ring.segs.len(), 1);
+    }
+
+    #[test]
+    fn grow_after_wrap_keeps_new_segment_invalid() {
+        let mut ring = ring(4, 2);
+        let a0 = noop(&mut ring);
+        noop(&mut ring);
+        ring.set_dequeue(a0);
+        let a2 = noop(&mut ring);
+        ring.set_dequeue(a2);
+        assert!(!ring.cycle);
+
+        ring.reserve(3).unwrap();
+        assert_eq!(ring.segs.len(), 2);
+        // 新段的 cycle 位与当前相反，控制器不会提前执行
+        assert!((0..4).all(|i| cycle(trb(&ring, 1, i))));
+        assert_eq!(ring.free_trbs(), 5);
+
+        for _ in 0..3 {
+            noop(&mut ring);
+        }
+        // 非最后一段的 Link 不翻转 cycle，指向新段
+        let link = trb(&ring, 0, 3);
+        assert_eq!(link[3] & 1 << 1, 0);
+        assert!(!cycle(link));
+        assert_eq!(
+            link[0] as u64 | (link[1] as u64) << 32,
+            ring.segs[1].bus_addr()
+        );
+        assert_eq!((ring.seg, ring.i), (1, 0));
+        assert!(!ring.cycle);
+
+        let addr = noop(&mut ring);
+        assert!(!cycle(trb(&ring, 1, 0)));
+        assert_eq!(ring.locate(addr), Some((1, 0)));
+    }
+
+    #[test]
+    fn grow_shifts_dequeue_after_insertion() {
+        let mut ring = ring(4, 3);
+        noop(&mut ring);
+        noop(&mut ring);
+        ring.reserve(1).unwrap();
+        assert_eq!(ring.segs.len(), 2);
+
+        let addrs: Vec<_> = (0..3).map(|_| noop(&mut ring)).collect();
+        assert_eq!(ring.locate(addrs[1]), Some((1, 0)));
+        ring.set_dequeue(addrs[1]);
+        assert_eq!(ring.deq, (1, 1));
+
+        noop(&mut ring);
+        let a = noop(&mut ring);
+        assert_eq!((ring.seg, ring.i), (0, 1));
+        assert!(!ring.cycle);
+
+        // 新段插在入队段之后，原来的第 1 段后移
+        let seg1 = ring.segs[1].bus_addr();
+        ring.reserve(4).unwrap();
+        assert_eq!(ring.segs.len(), 3);
+        assert_eq!(ring.deq, (2, 1));
+        assert_eq!(ring.segs[2].bus_addr(), seg1);
+
+        ring.set_dequeue(a);
+        assert_eq!(ring.deq, (0, 1));
+    }
+
+    #[test]
+    fn cancel_command_keeps_cycle() {
+        let mut ring = ring(4, 1);
+        noop(&mut ring);
+        let a1 = noop(&mut ring);
+        ring.set_dequeue(a1);
+        let a2 = ring
+            .enque_command(command::Allowed::EnableSlot(command::EnableSlot::new()))
+            .unwrap();
+        let a0 = ring
+            .enque_command(command::Allowed::EnableSlot(command::EnableSlot::new()))
+            .unwrap();
+
+        ring.cancel_command(a2);
+        let raw = trb(&ring, 0, 2);
+        assert_eq!(trb_type(raw), TRB_TYPE_NOOP_COMMAND);
+        assert!(cycle(raw));
+
+        ring.cancel_command(a0);
+        let raw = trb(&ring, 0, 0);
+        assert_eq!(trb_type(raw), TRB_TYPE_NOOP_COMMAND);
+        assert!(!cycle(raw));
+
+        // 不在环上的地址忽略
+        ring.cancel_command(0);
+    }
+
+    #[test]
+    fn cancel_one_of_two_tds() {
+        use super::super::completion::{Completions, RingKey};
+        use xhci::ring::trb::event::{Allowed, CompletionCode};
+
+        const TRB_TYPE_NOOP: u32 = 8;
+        const TRB_TYPE_TRANSFER_EVENT: u32 = 32;
+
+        let mut ring = ring(16, 1);
+        let key = RingKey::transfer(1, 2);
+        let completions = Completions::new();
+        completions.init(1);
+        completions.bind(key, &ring).unwrap();
+
+        let data = TrbBuffer::out(&[0xA5; 32], 64).unwrap();
+        let td1 = ring.enque_td(&data, false, false).unwrap();
+        let td2 = ring.enque_td(&data, false, false).unwrap();
+        let wait1 = completions.arm_td(key, &ring, &td1).unwrap();
+        let wait2 = completions.arm_td(key, &ring, &td2).unwrap();
+        let before: Vec<_> = td2
+            .iter()
+            .map(|&a| trb(&ring, 0, ring.locate(a).unwrap().1))
+            .collect();
+
+        assert!(completions.disarm(key, wait1).is_none());
+        let snapshot = ring.snapshot(&td1);
+        assert_eq!(snapshot.len(), td1.len());
+
+        // 位置已被改写时不做修改
+        let mut changed = snapshot.clone();
+        changed[0].1[2] ^= 1;
+        assert_eq!(ring.retire_td(&changed, td1[0]), None);
+        assert_eq!(trb(&ring, 0, 0), snapshot[0].1);
+
+        // 控制器停在 TD1 内：改为保留 cycle 的 No Op，跳到 TD2 开头
+        assert_eq!(ring.retire_td(&snapshot, td1[1]), Some((td2[0], true)));
+        for &(addr, raw) in &snapshot {
+            let now = trb(&ring, 0, ring.locate(addr).unwrap().1);
+            assert_eq!(trb_type(now), TRB_TYPE_NOOP);
+            assert_eq!(cycle(now), cycle(raw));
+            assert_eq!(now[3] & !(0x3F << 10 | 1), 0);
+        }
+        let after: Vec<_> = td2
+            .iter()
+            .map(|&a| trb(&ring, 0, ring.locate(a).unwrap().1))
+            .collect();
+        assert_eq!(after, before);
+
+        // TD1 迟到的事件被忽略，TD2 照常完成
+        let event = |addr: u64| {
+            Allowed::try_from([
+                addr as u32,
+                (addr >> 32) as u32,
+                (CompletionCode::Success as u32) << 24,
+                TRB_TYPE_TRANSFER_EVENT << 10 | 2 << 16 | 1 << 24 | 1,
+            ])
+            .unwrap()
+        };
+        completions.push(event(wait1));
+        assert!(completions.take(key, wait1).is_some_and(|r| r.is_err()));
+        completions.push(event(wait2));
+        let Some(Ok(Allowed::TransferEvent(ev))) = completions.take(key, wait2) else {
+            panic!("TD2 not completed");
+        };
+        assert_eq!(ev.trb_pointer(), wait2);
+        // TD2 出队后被退役的 TD1 一并回收
+        ring.set_dequeue(wait2);
+        assert_eq!(ring.free_trbs(), 14);
+    }
+
+    #[test]
+    fn idt_needs_max_packet_size_8() {
+        let data = [1, 2, 3];
+        assert!(TrbBuffer::out(&data, 8).unwrap().is_immediate());
+        assert!(TrbBuffer::out(&data, 64).unwrap().is_immediate());
+
+        // 最大包长小于 8 的端点（如低速中断端点）数据放在 DMA 缓冲区
+        let buf = TrbBuffer::out(&data, 4).unwrap();
+        assert!(matches!(buf, TrbBuffer::Dma(_)));
+        assert_eq!(buf.len(), 3);
+        assert_eq!(buf.chunks().len(), 1);
+
+        assert!(!TrbBuffer::out(&[0; 9], 64).unwrap().is_immediate());
+        assert!(!allow_idt(1, 0));
+        assert!(allow_idt(IDT_MAX, 8));
     }
 }
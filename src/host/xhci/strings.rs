@@ -0,0 +1,156 @@
+use core::sync::atomic::Ordering;
+
+use alloc::string::String;
+use log::debug;
+
+use super::Xhci;
+use crate::{
+    DeviceHandle, SetupPacket,
+    descriptor::{DeviceDescriptor, StringDescriptor, TYPE_STRING},
+    err::*,
+};
+
+/// 英语（美国）
+pub const LANG_EN_US: u16 = 0x0409;
+/// 字符串描述符的最大长度
+const STRING_MAX: usize = 255;
+
+/// 设备描述符引用的字符串，索引为 0 或读取失败时为`None`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DeviceStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl Xhci {
+    /// 设备支持多种语言时优先选用的 LANGID，对之后首次读取字符串的设备生效
+    pub fn set_string_language(&self, lang_id: u16) {
+        self.string_lang.store(lang_id, Ordering::Relaxed);
+    }
+
+    /// 读取`index`号字符串描述符并解码，结果按设备缓存
+    pub async fn string(&self, dev: DeviceHandle, index: u8) -> Result<String> {
+        if index == 0 {
+            return Err(USBError::NotSupported);
+        }
+        if let Some(s) = self.with_device(dev, |ctx| Ok(ctx.strings.get(&index).cloned()))? {
+            return Ok(s);
+        }
+
+        let lang = self.string_lang_id(dev).await?;
+        let mut buf = [0u8; STRING_MAX];
+        let n = self.read_string(dev, index, lang, &mut buf).await?;
+        let s = decode(&mut buf[..n])?;
+
+        self.with_device(dev, |ctx| {
+            ctx.strings.insert(index, s.clone());
+            Ok(())
+        })?;
+        Ok(s)
+    }
+
+    /// 读取设备描述符中的厂商、产品及序列号字符串
+    pub async fn device_strings(&self, dev: DeviceHandle) -> Result<DeviceStrings> {
+        let raw = self.device_descriptor(dev)?;
+        let desc = DeviceDescriptor::parse(&raw)?;
+
+        Ok(DeviceStrings {
+            manufacturer: self.optional_string(dev, desc.manufacturer_index()).await,
+            product: self.optional_string(dev, desc.product_index()).await,
+            serial: self.optional_string(dev, desc.serial_index()).await,
+        })
+    }
+
+    async fn optional_string(&self, dev: DeviceHandle, index: u8) -> Option<String> {
+        if index == 0 {
+            return None;
+        }
+        match self.string(dev, index).await {
+            Ok(s) => Some(s),
+            Err(e) => {
+                debug!("Slot {} string {} unavailable: {}", dev.slot_id(), index, e);
+                None
+            }
+        }
+    }
+
+    /// 读取 0 号字符串描述符协商语言：优先`set_string_language`设置的语言，
+    /// 否则取设备列出的第一个。不支持或返回空列表的设备按英语（美国）处理
+    async fn string_lang_id(&self, dev: DeviceHandle) -> Result<u16> {
+        if let Some(lang) = self.with_device(dev, |ctx| Ok(ctx.lang_id))? {
+            return Ok(lang);
+        }
+
+        let preferred = self.string_lang.load(Ordering::Relaxed);
+        let mut buf = [0u8; STRING_MAX];
+        let lang = match self.read_string(dev, 0, 0, &mut buf).await {
+            Ok(n) => match StringDescriptor::parse(clamp(&mut buf[..n])) {
+                Ok(desc) => desc
+                    .lang_ids()
+                    .find(|&id| id == preferred)
+                    .or(desc.lang_ids().next())
+                    .unwrap_or(LANG_EN_US),
+                Err(_) => LANG_EN_US,
+            },
+            Err(e @ (USBError::Disconnected | USBError::DeviceReset)) => return Err(e),
+            Err(e) => {
+                debug!("Slot {} LANGID read failed: {}", dev.slot_id(), e);
+                LANG_EN_US
+            }
+        };
+        debug!("Slot {} string language {:#06x}", dev.slot_id(), lang);
+
+        self.with_device(dev, |ctx| {
+            ctx.lang_id = Some(lang);
+            Ok(())
+        })?;
+        Ok(lang)
+    }
+
+    /// 先按最大长度读取；部分设备对此 STALL 或只返回一部分，
+    /// 此时先读 2 字节的头部得到 bLength，再按实际长度读取
+    async fn read_string(
+        &self,
+        dev: DeviceHandle,
+        index: u8,
+        lang: u16,
+        buf: &mut [u8; STRING_MAX],
+    ) -> Result<usize> {
+        let setup = SetupPacket::get_descriptor(TYPE_STRING, index, lang, STRING_MAX as u16);
+
+        match self.control_in(dev, setup, buf).await {
+            Ok(n) if n >= 2 && n >= buf[0] as usize => return Ok(n),
+            Ok(_) | Err(USBError::TransferEventError(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let n = self
+            .control_in(dev, setup.with_length(2), &mut buf[..2])
+            .await?;
+        let want = buf[0] as usize;
+        if n < 2 || want < 2 {
+            return Err(USBError::InvalidDescriptor);
+        }
+        self.control_in(dev, setup.with_length(want as u16), &mut buf[..want])
+            .await
+    }
+}
+
+/// 把 bLength 限制在实际收到的偶数字节内，容忍长度字段有误的设备
+fn clamp(raw: &mut [u8]) -> &[u8] {
+    let len = (raw.len().min(raw.first().copied().unwrap_or(0) as usize)) & !1;
+    if len >= 2 {
+        raw[0] = len as u8;
+    }
+    &raw[..len]
+}
+
+/// 解码 UTF-16LE，无效码元替换为 U+FFFD，去掉末尾的空字符与空白
+fn decode(raw: &mut [u8]) -> Result<String> {
+    let desc = StringDescriptor::parse(clamp(raw))?;
+    let s: String = desc.chars().collect();
+    Ok(String::from(s.trim_end_matches(|c: char| {
+        c == '\0' || c.is_whitespace()
+    })))
+}
@@ -0,0 +1,265 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use spin::Mutex;
+
+use crate::err::*;
+
+/// 异步互斥锁，持有期间可以 await；等待者按先后顺序唤醒
+pub struct AsyncLock {
+    locked: AtomicBool,
+    /// 每个等待中的`lock`调用占一项，以编号区分
+    waiters: Mutex<VecDeque<(u64, Waker)>>,
+    next_id: AtomicU64,
+}
+
+pub struct AsyncLockGuard<'a>(&'a AsyncLock);
+
+impl AsyncLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn try_lock(&self) -> Option<AsyncLockGuard<'_>> {
+        (!self.locked.swap(true, Ordering::Acquire)).then(|| AsyncLockGuard(self))
+    }
+
+    pub fn lock(&self) -> Lock<'_> {
+        Lock {
+            lock: self,
+            id: None,
+        }
+    }
+
+    fn wake_next(&self) {
+        if let Some((_, waker)) = self.waiters.lock().pop_front() {
+            waker.wake();
+        }
+    }
+}
+
+/// `AsyncLock::lock`返回的 future，被丢弃时撤销登记
+pub struct Lock<'a> {
+    lock: &'a AsyncLock,
+    /// 已登记的等待项编号
+    id: Option<u64>,
+}
+
+impl<'a> Future for Lock<'a> {
+    type Output = AsyncLockGuard<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let lock = self.lock;
+        if let Some(guard) = lock.try_lock() {
+            self.unregister();
+            return Poll::Ready(guard);
+        }
+
+        {
+            let mut waiters = lock.waiters.lock();
+            match self
+                .id
+                .and_then(|id| waiters.iter_mut().find(|(i, _)| *i == id))
+            {
+                Some((_, waker)) => waker.clone_from(cx.waker()),
+                None => {
+                    // 首次等待，或已被唤醒但未抢到锁：重新排到队尾
+                    let id = lock.next_id.fetch_add(1, Ordering::Relaxed);
+                    waiters.push_back((id, cx.waker().clone()));
+                    self.id = Some(id);
+                }
+            }
+        }
+
+        // 登记之后再试一次，避免错过登记前的释放
+        match lock.try_lock() {
+            Some(guard) => {
+                self.unregister();
+                Poll::Ready(guard)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Lock<'_> {
+    /// 移除自己的等待项，返回是否仍在队列中（即尚未被唤醒）
+    fn unregister(&mut self) -> bool {
+        let Some(id) = self.id.take() else {
+            return false;
+        };
+        let mut waiters = self.lock.waiters.lock();
+        match waiters.iter().position(|(i, _)| *i == id) {
+            Some(i) => {
+                waiters.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        // 已被唤醒却放弃等待时，把这次唤醒转交给下一个等待者
+        if self.id.is_some() && !self.unregister() && !self.lock.locked.load(Ordering::Acquire) {
+            self.lock.wake_next();
+        }
+    }
+}
+
+impl Drop for AsyncLockGuard<'_> {
+    fn drop(&mut self) {
+        self.0.locked.store(false, Ordering::Release);
+        self.0.wake_next();
+    }
+}
+
+/// 致命错误，中断中也可设置
+pub struct FatalError(AtomicU8);
+
+impl FatalError {
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(0))
+    }
+
+    /// 已有错误时保持不变，返回是否为首次设置
+    pub fn set(&self, err: &USBError) -> bool {
+        let code = match err {
+            USBError::HostSystemError => 1,
+            USBError::HostControllerError => 2,
+            USBError::ControllerNotResponding => 3,
+            other => unreachable!("{} is not fatal", other),
+        };
+        self.0
+            .compare_exchange(0, code, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    pub fn get(&self) -> Option<USBError> {
+        match self.0.load(Ordering::Acquire) {
+            0 => None,
+            1 => Some(USBError::HostSystemError),
+            2 => Some(USBError::HostControllerError),
+            _ => Some(USBError::ControllerNotResponding),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.0.store(0, Ordering::Release);
+    }
+}
+
+/// 根端口号（1..=255）的集合
+pub struct PortSet([AtomicU64; 4]);
+
+impl PortSet {
+    pub const fn new() -> Self {
+        Self([const { AtomicU64::new(0) }; 4])
+    }
+
+    pub fn insert(&self, port: u8) {
+        self.0[port as usize / 64].fetch_or(1 << (port % 64), Ordering::AcqRel);
+    }
+
+    pub fn contains(&self, port: u8) -> bool {
+        self.0[port as usize / 64].load(Ordering::Acquire) & (1 << (port % 64)) != 0
+    }
+
+    /// 取出并清空
+    pub fn take(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, word) in self.0.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::AcqRel);
+            while bits != 0 {
+                out.push((i * 64) as u8 + bits.trailing_zeros() as u8);
+                bits &= bits - 1;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, task::Wake};
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn waker() -> (Arc<Counter>, Waker) {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        (counter.clone(), Waker::from(counter))
+    }
+
+    fn poll<'a>(fut: &mut Lock<'a>, waker: &Waker) -> Poll<AsyncLockGuard<'a>> {
+        Pin::new(fut).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn repoll_keeps_single_entry() {
+        let lock = AsyncLock::new();
+        let _guard = lock.try_lock().unwrap();
+        let (_, w) = waker();
+        let mut fut = lock.lock();
+        for _ in 0..3 {
+            assert!(poll(&mut fut, &w).is_pending());
+        }
+        assert_eq!(lock.waiters.lock().len(), 1);
+        drop(fut);
+        assert!(lock.waiters.lock().is_empty());
+    }
+
+    #[test]
+    fn dropped_waiter_does_not_swallow_wakeup() {
+        let lock = AsyncLock::new();
+        let guard = lock.try_lock().unwrap();
+        let (_, w1) = waker();
+        let (c2, w2) = waker();
+        let mut first = lock.lock();
+        let mut second = lock.lock();
+        assert!(poll(&mut first, &w1).is_pending());
+        assert!(poll(&mut second, &w2).is_pending());
+
+        // 第一个等待者被唤醒后放弃，唤醒应转给第二个
+        drop(guard);
+        drop(first);
+        assert_eq!(c2.0.load(Ordering::SeqCst), 1);
+        assert!(poll(&mut second, &w2).is_ready());
+    }
+
+    #[test]
+    fn cancelled_waiter_is_removed() {
+        let lock = AsyncLock::new();
+        let guard = lock.try_lock().unwrap();
+        let (c1, w1) = waker();
+        let (c2, w2) = waker();
+        let mut first = lock.lock();
+        let mut second = lock.lock();
+        assert!(poll(&mut first, &w1).is_pending());
+        assert!(poll(&mut second, &w2).is_pending());
+
+        drop(first);
+        drop(guard);
+        assert_eq!(c1.0.load(Ordering::SeqCst), 0);
+        assert_eq!(c2.0.load(Ordering::SeqCst), 1);
+        assert!(poll(&mut second, &w2).is_ready());
+    }
+}
@@ -0,0 +1,371 @@
+use alloc::vec;
+use log::{debug, warn};
+use xhci::{
+    registers::doorbell,
+    ring::trb::{
+        command,
+        event::{Allowed, CompletionCode, TransferEvent},
+        transfer::{self, TransferType},
+    },
+};
+
+use super::{
+    Xhci,
+    buffer::{InBuf, OutBuf},
+    cancel::TdGuard,
+    completion::{Cancel, RingKey},
+    endpoint::{EndpointConfig, TransferKind},
+    enumerate::DeviceState,
+    ring::{Ring, TrbBuffer},
+};
+use crate::{DataDirection, DeviceHandle, SetupPacket, err::*};
+
+/// EP0 的 Device Context Index
+pub const DCI_EP0: u8 = 1;
+
+/// 数据阶段的长度即 wLength，缓冲区小于它或超出 wLength 的表示范围时返回`NotSupported`
+fn data_stage_len(setup: &SetupPacket, buf_len: usize) -> Result<usize> {
+    let len = setup.length as usize;
+    if buf_len < len || buf_len > u16::MAX as usize {
+        return Err(USBError::NotSupported);
+    }
+    Ok(len)
+}
+
+impl Xhci {
+    /// EP0 IN 控制传输，数据阶段长度为`setup.length`，返回实际收到的字节数
+    pub async fn control_in(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let len = data_stage_len(&setup, buf.len())?;
+        let mut data = TrbBuffer::input(len)?;
+        let n = self.control(dev, setup, true, &mut data).await?;
+        data.read(&mut buf[..n]);
+        Ok(n)
+    }
+
+    /// EP0 OUT 控制传输，发送`data`的前`setup.length`字节，长度为 0 时没有数据阶段
+    pub async fn control_out(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> Result<usize> {
+        let len = data_stage_len(&setup, data.len())?;
+        let mps = self.with_device(dev, |ctx| Ok(ctx.ep0_max_packet_size))?;
+        let mut data = TrbBuffer::out(&data[..len], mps)?;
+        self.control(dev, setup, false, &mut data).await
+    }
+
+    async fn control(
+        &self,
+        dev: DeviceHandle,
+        setup: SetupPacket,
+        dir_in: bool,
+        data: &mut TrbBuffer<'_>,
+    ) -> Result<usize> {
+        let len = data.len();
+
+        let mut setup_trb = transfer::SetupStage::new();
+        setup_trb
+            .set_request_type(
+                setup
+                    .request_type
+                    .with_direction(if dir_in {
+                        DataDirection::In
+                    } else {
+                        DataDirection::Out
+                    })
+                    .0,
+            )
+            .set_request(setup.request)
+            .set_value(setup.value)
+            .set_index(setup.index)
+            .set_length(setup.length)
+            .set_transfer_type(match (len, dir_in) {
+                (0, _) => TransferType::No,
+                (_, true) => TransferType::In,
+                (_, false) => TransferType::Out,
+            });
+
+        let key = RingKey::transfer(dev.slot_id(), DCI_EP0);
+        let (trbs, data_addr, status_addr) = self.with_ring(dev, DCI_EP0, |ring| {
+            ring.reserve(if data.is_empty() {
+                2
+            } else {
+                data.td_trbs() + 2
+            })?;
+            let setup_addr = ring.enque_transfer(transfer::Allowed::SetupStage(setup_trb))?;
+
+            let data_td = match &*data {
+                TrbBuffer::None => None,
+                data => Some(ring.enque_td(data, dir_in, true)?),
+            };
+
+            // 状态阶段与数据阶段方向相反，无数据阶段时为 IN
+            let mut status = transfer::StatusStage::new();
+            if data.is_empty() || !dir_in {
+                status.set_direction();
+            }
+            status.set_interrupt_on_completion();
+            let status_addr = ring.enque_transfer(transfer::Allowed::StatusStage(status))?;
+
+            // Setup 阶段出错时的事件投递到第一个等待的 TRB
+            let mut trbs = vec![setup_addr];
+            let data_addr = match data_td {
+                Some(data_td) => {
+                    trbs.extend(data_td);
+                    Some(self.completions.arm_td(key, ring, &trbs)?)
+                }
+                None => None,
+            };
+            let split = trbs.len();
+            trbs.push(status_addr);
+            self.completions.arm_td(key, ring, &trbs[split..])?;
+            self.kick(ring, dev.slot_id(), DCI_EP0);
+            Ok((trbs, data_addr, status_addr))
+        })?;
+
+        let mut guard = TdGuard {
+            xhci: self,
+            dev,
+            dci: DCI_EP0,
+            trbs,
+            waits: data_addr.into_iter().chain([status_addr]).collect(),
+            data,
+        };
+
+        let mut actual = 0;
+        if let Some(addr) = data_addr {
+            let ev = self.wait_transfer(dev, DCI_EP0, addr).await;
+            guard.waits.retain(|&a| a != addr);
+            match ev {
+                Ok(ev) => actual = td_actual_length(&ev, len),
+                Err(e) => {
+                    guard.done();
+                    return Err(e);
+                }
+            }
+        }
+        let res = self.wait_transfer(dev, DCI_EP0, status_addr).await;
+        guard.done();
+        res?;
+
+        if setup.is_set_configuration() {
+            let value = setup.value as u8;
+            self.with_device(dev, |ctx| {
+                ctx.configuration = (value != 0).then_some(value);
+                ctx.state = if value != 0 {
+                    DeviceState::Configured
+                } else {
+                    DeviceState::Addressed
+                };
+                Ok(())
+            })?;
+        }
+
+        Ok(actual)
+    }
+
+    /// 批量/中断 IN 传输，`endpoint`为 bEndpointAddress，返回实际收到的字节数。
+    /// 控制器直接写入`buf`，切片按页映射为分散/聚集 TRB 链，返回前解除映射
+    pub async fn transfer_in(
+        &self,
+        dev: DeviceHandle,
+        endpoint: u8,
+        buf: impl Into<InBuf<'_>>,
+    ) -> Result<usize> {
+        let dci = self.normal_endpoint(dev, endpoint | 0x80)?.dci();
+        let mut data = TrbBuffer::map_in(buf.into(), &self.dma)?;
+        let res = self.transfer_normal(dev, dci, true, &mut data).await;
+        data.complete(*res.as_ref().unwrap_or(&0));
+        res
+    }
+
+    /// 批量/中断 OUT 传输，控制器直接读取`data`；不超过 8 字节的切片直接放在 TRB 中
+    pub async fn transfer_out(
+        &self,
+        dev: DeviceHandle,
+        endpoint: u8,
+        data: impl Into<OutBuf<'_>>,
+    ) -> Result<usize> {
+        let ep = self.normal_endpoint(dev, endpoint & !0x80)?;
+        let mut data = TrbBuffer::map_out(data.into(), ep.max_packet_size & 0x7FF, &self.dma)?;
+        let dci = ep.dci();
+        self.transfer_normal(dev, dci, false, &mut data).await
+    }
+
+    fn normal_endpoint(&self, dev: DeviceHandle, address: u8) -> Result<EndpointConfig> {
+        let ep = self.with_device(dev, |ctx| {
+            ctx.endpoints
+                .values()
+                .find(|ep| ep.address == address)
+                .copied()
+                .ok_or(USBError::NotSupported)
+        })?;
+        match ep.kind {
+            TransferKind::Bulk | TransferKind::Interrupt => Ok(ep),
+            _ => Err(USBError::NotSupported),
+        }
+    }
+
+    async fn transfer_normal(
+        &self,
+        dev: DeviceHandle,
+        dci: u8,
+        dir_in: bool,
+        data: &mut TrbBuffer<'_>,
+    ) -> Result<usize> {
+        let len = data.len();
+        let key = RingKey::transfer(dev.slot_id(), dci);
+        let (trbs, addr) = self.with_ring(dev, dci, |ring| {
+            let td = ring.enque_td(data, dir_in, false)?;
+            let addr = self.completions.arm_td(key, ring, &td)?;
+            self.kick(ring, dev.slot_id(), dci);
+            Ok((td, addr))
+        })?;
+        let guard = TdGuard {
+            xhci: self,
+            dev,
+            dci,
+            trbs,
+            waits: vec![addr],
+            data,
+        };
+
+        let ev = self.wait_transfer(dev, dci, addr).await;
+        guard.done();
+        Ok(td_actual_length(&ev?, len))
+    }
+
+    /// 在不跨越 await 的短临界区内访问传输环，TD 入队与登记结果槽须在同一临界区内完成
+    pub(super) fn with_ring<R>(
+        &self,
+        dev: DeviceHandle,
+        dci: u8,
+        f: impl FnOnce(&mut Ring) -> Result<R>,
+    ) -> Result<R> {
+        self.with_device(dev, |ctx| {
+            f(ctx
+                .transfer_rings
+                .get_mut(&dci)
+                .ok_or(USBError::NotSupported)?)
+        })
+    }
+
+    /// TD 入队后启动端点，有 TD 正在取消时由取消流程结束后启动
+    fn kick(&self, ring: &Ring, slot_id: u8, dci: u8) {
+        if ring.stopping == 0 {
+            self.ring_doorbell(slot_id, dci);
+        }
+    }
+
+    pub(super) fn ring_doorbell(&self, slot_id: u8, target: u8) {
+        let mut db = doorbell::Register::default();
+        db.set_doorbell_target(target);
+        self.regs().doorbell.write_volatile_at(slot_id as _, db);
+    }
+
+    async fn wait_transfer(
+        &self,
+        dev: DeviceHandle,
+        dci: u8,
+        trb_addr: u64,
+    ) -> Result<TransferEvent> {
+        if let Some(err) = self.error.get() {
+            return Err(err);
+        }
+
+        let key = RingKey::transfer(dev.slot_id(), dci);
+        let res = self.wait_event(key, trb_addr, Some(dev.port_id())).await?;
+        let Allowed::TransferEvent(ev) = res else {
+            return Err(USBError::Unknown);
+        };
+        self.with_ring(dev, dci, |ring| {
+            ring.set_dequeue(trb_addr);
+            Ok(())
+        })?;
+
+        match ev.completion_code() {
+            Ok(CompletionCode::Success) | Ok(CompletionCode::ShortPacket) => Ok(ev),
+            Ok(code) => {
+                debug!(
+                    "Slot {} DCI {} transfer failed: {:?}",
+                    dev.slot_id(),
+                    dci,
+                    code
+                );
+                if let Err(e) = self.reset_endpoint(dev, dci).await {
+                    warn!("Slot {} DCI {} reset failed: {}", dev.slot_id(), dci, e);
+                }
+                Err(code.into())
+            }
+            Err(_) => Err(USBError::Unknown),
+        }
+    }
+
+    /// 端点因错误进入 Halted 后复位，并把 dequeue 指针移过出错的 TD；
+    /// 同一端点上其他任务排队中的 TD 一并丢弃，以`Stopped`结束
+    async fn reset_endpoint(&self, dev: DeviceHandle, dci: u8) -> Result {
+        let mut cmd = command::ResetEndpoint::new();
+        cmd.set_slot_id(dev.slot_id()).set_endpoint_id(dci);
+        self.post_cmd(command::Allowed::ResetEndpoint(cmd)).await?;
+        self.skip_pending_tds(dev, dci).await
+    }
+
+    /// 端点停止后把 dequeue 指针移到入队位置
+    async fn skip_pending_tds(&self, dev: DeviceHandle, dci: u8) -> Result {
+        let (deq, cycle) = self.with_ring(dev, dci, |ring| {
+            ring.discard();
+            self.completions
+                .fail_ring(RingKey::transfer(dev.slot_id(), dci), Cancel::Stopped);
+            Ok((ring.current_trb_addr(), ring.cycle))
+        })?;
+
+        let mut cmd = command::SetTrDequeuePointer::new();
+        cmd.set_slot_id(dev.slot_id())
+            .set_endpoint_id(dci)
+            .set_new_tr_dequeue_pointer(deq);
+        if cycle {
+            cmd.set_dequeue_cycle_state();
+        } else {
+            cmd.clear_dequeue_cycle_state();
+        }
+        self.post_cmd(command::Allowed::SetTrDequeuePointer(cmd))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// TD 实际传输的字节数：Event Data 事件报告的是累计长度（EDTLA），
+/// 普通事件报告的是剩余长度
+fn td_actual_length(ev: &TransferEvent, len: usize) -> usize {
+    let n = ev.trb_transfer_length() as usize;
+    if ev.event_data() {
+        n.min(len)
+    } else {
+        len.saturating_sub(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_stage_uses_wlength() {
+        let setup = SetupPacket::get_device_descriptor(8);
+        assert_eq!(data_stage_len(&setup, 18), Ok(8));
+        assert_eq!(data_stage_len(&setup, 8), Ok(8));
+        assert_eq!(data_stage_len(&setup, 2), Err(USBError::NotSupported));
+        assert_eq!(
+            data_stage_len(&setup, u16::MAX as usize + 1),
+            Err(USBError::NotSupported)
+        );
+        assert_eq!(data_stage_len(&SetupPacket::set_configuration(1), 0), Ok(0));
+    }
+}
@@ -3,17 +3,30 @@
 
 extern crate alloc;
 
-use core::time::Duration;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
+/// USB 描述符解析，各类型均直接引用原始字节，不做拷贝
+pub mod descriptor;
 pub mod err;
 mod host;
+/// 控制传输的 Setup 包及标准请求
+pub mod setup;
 
 pub use futures::future::LocalBoxFuture;
 pub use host::*;
+pub use setup::{DataDirection, Recipient, RequestKind, RequestType, SetupPacket};
 
 pub trait Kernel {
     fn sleep<'a>(duration: Duration) -> LocalBoxFuture<'a, ()>;
     fn page_size() -> usize;
+    /// 这段内存能否交给 dma_api 映射（如不在栈或 vmalloc 区），否则传输经驱动缓冲区中转
+    fn dma_capable(_addr: NonNull<u8>, _len: usize) -> bool {
+        true
+    }
 }
 
 pub(crate) async fn sleep(duration: Duration) {
@@ -36,6 +49,28 @@ pub(crate) fn page_size() -> usize {
     }
 }
 
+pub(crate) fn dma_capable(addr: NonNull<u8>, len: usize) -> bool {
+    unsafe {
+        unsafe extern "Rust" {
+            fn _usb_host_dma_capable(addr: NonNull<u8>, len: usize) -> bool;
+        }
+
+        _usb_host_dma_capable(addr, len)
+    }
+}
+
+static DMA_COHERENT: AtomicBool = AtomicBool::new(false);
+
+/// 平台 DMA 与 CPU 缓存一致时调用，之后控制器数据结构与传输缓冲区的读写
+/// 不再经过 dma_api 的 flush/invalidate
+pub fn set_dma_coherent(coherent: bool) {
+    DMA_COHERENT.store(coherent, Ordering::Relaxed);
+}
+
+pub(crate) fn dma_coherent() -> bool {
+    DMA_COHERENT.load(Ordering::Relaxed)
+}
+
 #[macro_export]
 macro_rules! set_impl {
     ($t: ty) => {
@@ -50,5 +85,10 @@ macro_rules! set_impl {
         unsafe fn _usb_host_page_size() -> usize {
             <$t as $crate::Kernel>::page_size()
         }
+
+        #[unsafe(no_mangle)]
+        unsafe fn _usb_host_dma_capable(addr: core::ptr::NonNull<u8>, len: usize) -> bool {
+            <$t as $crate::Kernel>::dma_capable(addr, len)
+        }
     };
 }
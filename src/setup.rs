@@ -0,0 +1,499 @@
+use crate::descriptor::TYPE_DEVICE;
+
+// 标准请求 bRequest
+pub const GET_STATUS: u8 = 0x00;
+pub const CLEAR_FEATURE: u8 = 0x01;
+pub const SET_FEATURE: u8 = 0x03;
+pub const SET_ADDRESS: u8 = 0x05;
+pub const GET_DESCRIPTOR: u8 = 0x06;
+pub const SET_DESCRIPTOR: u8 = 0x07;
+pub const GET_CONFIGURATION: u8 = 0x08;
+pub const SET_CONFIGURATION: u8 = 0x09;
+pub const GET_INTERFACE: u8 = 0x0A;
+pub const SET_INTERFACE: u8 = 0x0B;
+pub const SYNCH_FRAME: u8 = 0x0C;
+pub const SET_SEL: u8 = 0x30;
+pub const SET_ISOCH_DELAY: u8 = 0x31;
+
+// 特性选择子
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+/// 接收者为接口，SuperSpeed 设备使用
+pub const FEATURE_FUNCTION_SUSPEND: u16 = 0;
+pub const FEATURE_DEVICE_REMOTE_WAKEUP: u16 = 1;
+pub const FEATURE_TEST_MODE: u16 = 2;
+pub const FEATURE_U1_ENABLE: u16 = 48;
+pub const FEATURE_U2_ENABLE: u16 = 49;
+pub const FEATURE_LTM_ENABLE: u16 = 50;
+
+/// 数据阶段方向，对应 bmRequestType bit 7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDirection {
+    Out,
+    In,
+}
+
+/// bmRequestType bit 5..=6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Standard,
+    Class,
+    Vendor,
+    Reserved,
+}
+
+/// bmRequestType bit 0..=4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+    /// 保留值，原样保留
+    Reserved(u8),
+}
+
+/// bmRequestType
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestType(pub u8);
+
+impl RequestType {
+    pub const fn new(direction: DataDirection, kind: RequestKind, recipient: Recipient) -> Self {
+        let dir = match direction {
+            DataDirection::Out => 0,
+            DataDirection::In => 0x80,
+        };
+        let kind = match kind {
+            RequestKind::Standard => 0,
+            RequestKind::Class => 1,
+            RequestKind::Vendor => 2,
+            RequestKind::Reserved => 3,
+        };
+        let recipient = match recipient {
+            Recipient::Device => 0,
+            Recipient::Interface => 1,
+            Recipient::Endpoint => 2,
+            Recipient::Other => 3,
+            Recipient::Reserved(r) => r & 0x1F,
+        };
+        Self(dir | kind << 5 | recipient)
+    }
+
+    pub fn direction(&self) -> DataDirection {
+        if self.0 & 0x80 != 0 {
+            DataDirection::In
+        } else {
+            DataDirection::Out
+        }
+    }
+
+    pub fn kind(&self) -> RequestKind {
+        match self.0 >> 5 & 0x3 {
+            0 => RequestKind::Standard,
+            1 => RequestKind::Class,
+            2 => RequestKind::Vendor,
+            _ => RequestKind::Reserved,
+        }
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        match self.0 & 0x1F {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            3 => Recipient::Other,
+            r => Recipient::Reserved(r),
+        }
+    }
+
+    pub fn with_direction(self, direction: DataDirection) -> Self {
+        match direction {
+            DataDirection::Out => Self(self.0 & !0x80),
+            DataDirection::In => Self(self.0 | 0x80),
+        }
+    }
+}
+
+/// 控制传输的 Setup 包。经`control_in`/`control_out`发送时方向位以所调用的方法为准；
+/// `length`即数据阶段的长度，缓冲区不能比它短
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: RequestType,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub const fn new(
+        request_type: RequestType,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Self {
+        Self {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    const fn standard(
+        direction: DataDirection,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Self {
+        Self::new(
+            RequestType::new(direction, RequestKind::Standard, recipient),
+            request,
+            value,
+            index,
+            length,
+        )
+    }
+
+    /// 类请求，如 HID 的 GET_REPORT、大容量存储的 Bulk-Only Reset
+    pub const fn class(
+        direction: DataDirection,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Self {
+        Self::new(
+            RequestType::new(direction, RequestKind::Class, recipient),
+            request,
+            value,
+            index,
+            length,
+        )
+    }
+
+    pub const fn vendor(
+        direction: DataDirection,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Self {
+        Self::new(
+            RequestType::new(direction, RequestKind::Vendor, recipient),
+            request,
+            value,
+            index,
+            length,
+        )
+    }
+
+    /// 字符串描述符的`lang_id`为 LANGID，其余描述符为 0
+    pub const fn get_descriptor(ty: u8, index: u8, lang_id: u16, length: u16) -> Self {
+        Self::standard(
+            DataDirection::In,
+            Recipient::Device,
+            GET_DESCRIPTOR,
+            (ty as u16) << 8 | index as u16,
+            lang_id,
+            length,
+        )
+    }
+
+    /// 设备描述符
+    pub const fn get_device_descriptor(length: u16) -> Self {
+        Self::get_descriptor(TYPE_DEVICE, 0, 0, length)
+    }
+
+    pub const fn set_descriptor(ty: u8, index: u8, lang_id: u16, length: u16) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            Recipient::Device,
+            SET_DESCRIPTOR,
+            (ty as u16) << 8 | index as u16,
+            lang_id,
+            length,
+        )
+    }
+
+    pub const fn get_configuration() -> Self {
+        Self::standard(
+            DataDirection::In,
+            Recipient::Device,
+            GET_CONFIGURATION,
+            0,
+            0,
+            1,
+        )
+    }
+
+    /// `value`为 0 时回到 Address 状态
+    pub const fn set_configuration(value: u8) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            Recipient::Device,
+            SET_CONFIGURATION,
+            value as u16,
+            0,
+            0,
+        )
+    }
+
+    pub const fn get_interface(interface: u8) -> Self {
+        Self::standard(
+            DataDirection::In,
+            Recipient::Interface,
+            GET_INTERFACE,
+            0,
+            interface as u16,
+            1,
+        )
+    }
+
+    pub const fn set_interface(interface: u8, alt_setting: u8) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            Recipient::Interface,
+            SET_INTERFACE,
+            alt_setting as u16,
+            interface as u16,
+            0,
+        )
+    }
+
+    /// `index`为接口号或端点地址，接收者为设备时为 0
+    pub const fn get_status(recipient: Recipient, index: u16) -> Self {
+        Self::standard(DataDirection::In, recipient, GET_STATUS, 0, index, 2)
+    }
+
+    pub const fn clear_feature(recipient: Recipient, feature: u16, index: u16) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            recipient,
+            CLEAR_FEATURE,
+            feature,
+            index,
+            0,
+        )
+    }
+
+    pub const fn set_feature(recipient: Recipient, feature: u16, index: u16) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            recipient,
+            SET_FEATURE,
+            feature,
+            index,
+            0,
+        )
+    }
+
+    /// 清除端点的 Halt 状态
+    pub const fn clear_halt(endpoint: u8) -> Self {
+        Self::clear_feature(Recipient::Endpoint, FEATURE_ENDPOINT_HALT, endpoint as u16)
+    }
+
+    /// xHCI 由 Address Device 命令代为发送，仅用于其他场合
+    pub const fn set_address(address: u8) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            Recipient::Device,
+            SET_ADDRESS,
+            address as u16,
+            0,
+            0,
+        )
+    }
+
+    pub const fn synch_frame(endpoint: u8) -> Self {
+        Self::standard(
+            DataDirection::In,
+            Recipient::Endpoint,
+            SYNCH_FRAME,
+            0,
+            endpoint as u16,
+            2,
+        )
+    }
+
+    /// 数据阶段为 6 字节的 U1SEL/U1PEL/U2SEL/U2PEL
+    pub const fn set_sel() -> Self {
+        Self::standard(DataDirection::Out, Recipient::Device, SET_SEL, 0, 0, 6)
+    }
+
+    /// `delay_ns`为主机到设备的等时延迟（ns）
+    pub const fn set_isoch_delay(delay_ns: u16) -> Self {
+        Self::standard(
+            DataDirection::Out,
+            Recipient::Device,
+            SET_ISOCH_DELAY,
+            delay_ns,
+            0,
+            0,
+        )
+    }
+
+    pub fn with_recipient(mut self, recipient: Recipient) -> Self {
+        let ty = self.request_type;
+        self.request_type = RequestType::new(ty.direction(), ty.kind(), recipient);
+        self
+    }
+
+    pub fn with_length(mut self, length: u16) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// 总线上传输的 8 字节格式
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let [v0, v1] = self.value.to_le_bytes();
+        let [i0, i1] = self.index.to_le_bytes();
+        let [l0, l1] = self.length.to_le_bytes();
+        [self.request_type.0, self.request, v0, v1, i0, i1, l0, l1]
+    }
+
+    pub fn from_bytes(raw: [u8; 8]) -> Self {
+        Self {
+            request_type: RequestType(raw[0]),
+            request: raw[1],
+            value: u16::from_le_bytes([raw[2], raw[3]]),
+            index: u16::from_le_bytes([raw[4], raw[5]]),
+            length: u16::from_le_bytes([raw[6], raw[7]]),
+        }
+    }
+
+    /// 是否为标准 SET_CONFIGURATION 请求
+    pub fn is_set_configuration(&self) -> bool {
+        self.request_type.kind() == RequestKind::Standard
+            && self.request_type.recipient() == Recipient::Device
+            && self.request == SET_CONFIGURATION
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::{TYPE_CONFIGURATION, TYPE_STRING};
+
+    #[test]
+    fn standard_requests_on_the_wire() {
+        assert_eq!(
+            SetupPacket::get_device_descriptor(18).to_bytes(),
+            [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::get_descriptor(TYPE_CONFIGURATION, 1, 0, 0x1FF).to_bytes(),
+            [0x80, 0x06, 0x01, 0x02, 0x00, 0x00, 0xFF, 0x01]
+        );
+        assert_eq!(
+            SetupPacket::get_descriptor(TYPE_STRING, 2, 0x0409, 255).to_bytes(),
+            [0x80, 0x06, 0x02, 0x03, 0x09, 0x04, 0xFF, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::set_configuration(1).to_bytes(),
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::set_interface(2, 1).to_bytes(),
+            [0x01, 0x0B, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::get_interface(2).to_bytes(),
+            [0x81, 0x0A, 0x00, 0x00, 0x02, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::clear_halt(0x81).to_bytes(),
+            [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::get_status(Recipient::Device, 0).to_bytes(),
+            [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::set_feature(Recipient::Device, FEATURE_DEVICE_REMOTE_WAKEUP, 0).to_bytes(),
+            [0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            SetupPacket::synch_frame(0x83).to_bytes(),
+            [0x82, 0x0C, 0x00, 0x00, 0x83, 0x00, 0x02, 0x00]
+        );
+        assert_eq!(SetupPacket::set_sel().length, 6);
+        assert_eq!(SetupPacket::set_isoch_delay(40).value, 40);
+    }
+
+    #[test]
+    fn class_and_vendor_requests() {
+        // 大容量存储 Bulk-Only Mass Storage Reset
+        let reset = SetupPacket::class(DataDirection::Out, Recipient::Interface, 0xFF, 0, 0, 0);
+        assert_eq!(reset.to_bytes(), [0x21, 0xFF, 0, 0, 0, 0, 0, 0]);
+
+        let vendor = SetupPacket::vendor(DataDirection::In, Recipient::Device, 0x01, 0, 0, 4);
+        assert_eq!(vendor.request_type.0, 0xC0);
+        assert_eq!(vendor.request_type.kind(), RequestKind::Vendor);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let raw = [0xA1, 0x01, 0x00, 0x01, 0x02, 0x00, 0x40, 0x00];
+        let setup = SetupPacket::from_bytes(raw);
+        assert_eq!(setup.request_type.direction(), DataDirection::In);
+        assert_eq!(setup.request_type.kind(), RequestKind::Class);
+        assert_eq!(setup.request_type.recipient(), Recipient::Interface);
+        assert_eq!(setup.value, 0x0100);
+        assert_eq!(setup.index, 2);
+        assert_eq!(setup.length, 0x40);
+        assert_eq!(setup.to_bytes(), raw);
+    }
+
+    #[test]
+    fn request_type_fields() {
+        let ty = RequestType::new(DataDirection::In, RequestKind::Vendor, Recipient::Other);
+        assert_eq!(ty.0, 0xC3);
+        assert_eq!(ty.with_direction(DataDirection::Out).0, 0x43);
+        assert_eq!(ty.with_direction(DataDirection::In), ty);
+
+        // 保留的接收者原样保留，超出 5 位的部分截断
+        let ty = RequestType::new(
+            DataDirection::Out,
+            RequestKind::Standard,
+            Recipient::Reserved(0x3F),
+        );
+        assert_eq!(ty.0, 0x1F);
+        assert_eq!(ty.recipient(), Recipient::Reserved(0x1F));
+        assert_eq!(RequestType(0x60).kind(), RequestKind::Reserved);
+    }
+
+    #[test]
+    fn builders_keep_other_fields() {
+        let setup =
+            SetupPacket::get_status(Recipient::Device, 0).with_recipient(Recipient::Endpoint);
+        assert_eq!(setup.request_type.0, 0x82);
+        assert_eq!(setup.request, GET_STATUS);
+        assert_eq!(setup.length, 2);
+
+        let setup = SetupPacket::get_descriptor(TYPE_STRING, 0, 0, 255).with_length(2);
+        assert_eq!(setup.to_bytes(), [0x80, 0x06, 0x00, 0x03, 0, 0, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn set_configuration_detection() {
+        assert!(SetupPacket::set_configuration(0).is_set_configuration());
+        assert!(!SetupPacket::get_configuration().is_set_configuration());
+        let class = SetupPacket::class(
+            DataDirection::Out,
+            Recipient::Device,
+            SET_CONFIGURATION,
+            1,
+            0,
+            0,
+        );
+        assert!(!class.is_set_configuration());
+        let iface = SetupPacket::set_configuration(1).with_recipient(Recipient::Interface);
+        assert!(!iface.is_set_configuration());
+    }
+}
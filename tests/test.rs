@@ -18,16 +18,12 @@ use bare_test::{
     platform::fdt::GetPciIrqConfig,
     println,
 };
-use core::{cell::UnsafeCell, time::Duration};
+use core::time::Duration;
 use futures::FutureExt;
 use log::*;
 use pcie::*;
 use usb_host::*;
 
-struct Host(UnsafeCell<USBHost<Xhci>>);
-unsafe impl Send for Host {}
-unsafe impl Sync for Host {}
-
 #[bare_test::tests]
 mod tests {
     use core::hint::spin_loop;
@@ -48,17 +44,7 @@ mod tests {
         let info = get_usb_host();
         let host = info.usb;
 
-        let host = Arc::new(Host(UnsafeCell::new(host)));
-        bare_test::time::after(Duration::from_secs(5), {
-            let host = host.clone();
-            move || {
-                debug!("test timer");
-
-                unsafe {
-                    (&mut *host.0.get()).handle_irq();
-                }
-            }
-        });
+        let host = Arc::new(host);
 
         if let Some(irq) = &info.irq {
             for one in &irq.cfgs {
@@ -69,9 +55,7 @@ mod tests {
                 .register_builder({
                     let host = host.clone();
                     move |irq| {
-                        unsafe {
-                            (&mut *host.0.get()).handle_irq();
-                        }
+                        host.handle_irq();
                         IrqHandleResult::Handled
                     }
                 })
@@ -80,8 +64,6 @@ mod tests {
         }
 
         spin_on::spin_on(async move {
-            let host = unsafe { &mut *host.0.get() };
-
             host.init().await.unwrap();
 
             debug!("usb cmd test");
@@ -89,6 +71,33 @@ mod tests {
             host.test_cmd().await.unwrap();
 
             debug!("usb cmd ok");
+
+            let devices = host.probe().await.unwrap();
+            info!("devices: {:?}", devices);
+            assert!(!devices.is_empty());
+
+            let mut desc = [0u8; 18];
+            let setup = SetupPacket::get_device_descriptor(desc.len() as u16);
+            let n = host.control_in(devices[0], setup, &mut desc).await.unwrap();
+            info!("device descriptor: {:x?}", &desc[..n]);
+            assert_eq!(n, desc.len());
+            let desc = descriptor::DeviceDescriptor::parse(&desc).unwrap();
+            info!(
+                "vendor {:04x} product {:04x}",
+                desc.vendor_id(),
+                desc.product_id()
+            );
+            assert_eq!(host.device_descriptor(devices[0]).unwrap(), desc.bytes());
+            let state = host.device_state(devices[0]).unwrap();
+            info!("state: {:?}", state);
+            if let Some(value) = host.active_configuration(devices[0]).unwrap() {
+                assert_eq!(state, DeviceState::Configured);
+                info!("configuration {}", value);
+            }
+            let strings = host.device_strings(devices[0]).await.unwrap();
+            info!("strings: {:?}", strings);
+
+            host.shutdown(false).await.unwrap();
         });
     }
 }
@@ -220,7 +229,7 @@ fn get_usb_host() -> XhciInfo {
                 println!("irq: {irq:?}");
 
                 return XhciInfo {
-                    usb: USBHost::new(addr),
+                    usb: USBHost::new(addr, EventMode::Polling),
                     irq,
                 };
             }
@@ -241,7 +250,7 @@ fn get_usb_host() -> XhciInfo {
             let irq = node.irq_info();
 
             return XhciInfo {
-                usb: USBHost::new(addr),
+                usb: USBHost::new(addr, EventMode::Polling),
                 irq,
             };
         }